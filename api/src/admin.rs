@@ -0,0 +1,774 @@
+use crate::{
+    db::{self, Db},
+    errors::ChatError,
+    sync::Token,
+    validation,
+};
+use aws_sdk_dynamodb::{
+    client::fluent_builders::{Query, Scan},
+    model::{AttributeValue, Delete, DeleteRequest, TransactWriteItem, Update, WriteRequest},
+    types::SdkError,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::Duration,
+};
+
+// Operations for running the chat service, as opposed to using it. These are
+// what `chatctl` does; the API doesn't serve them.
+//
+// Most of them scan whole tables, so they're slow and expensive on a big
+// deployment, and none of them are atomic. The API can keep serving while
+// they run. Changes to the list of active rooms are retried if the API
+// changes it meanwhile, so neither loses the other's, but a room deleted
+// during `rebuild_active_rooms`, for example, can be put back in the list,
+// which `check` reports.
+
+type Item = HashMap<String, AttributeValue>;
+
+/// `BatchWriteItem` takes at most this many requests.
+const BATCH_SIZE: usize = 25;
+
+#[derive(Serialize)]
+pub struct UserSummary {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct RoomSummary {
+    pub id: String,
+    pub name: String,
+    /// Whether the room is in the list of active rooms, which is what
+    /// `GET /rooms` shows.
+    pub active: bool,
+}
+
+/// Something that's wrong with the data, found by `check`.
+#[derive(Serialize)]
+pub struct Problem {
+    /// What kind of problem it is, like `dangling_active_room`.
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+/// Which messages `purge` deletes. Messages have to match every filter that
+/// is set.
+#[derive(Default)]
+pub struct Purge {
+    pub room_id: Option<String>,
+    pub user_id: Option<String>,
+    /// Sort key of the first message to delete, see `message_sort_key`.
+    pub since: Option<String>,
+    /// Sort key just past the last message to delete.
+    pub until: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Purged {
+    pub messages: usize,
+    pub rooms: Vec<String>,
+}
+
+#[derive(Default, Serialize)]
+pub struct Seeded {
+    pub users: usize,
+    pub rooms: usize,
+    pub messages: usize,
+}
+
+/// The sort key a message posted at `time` would have, for `Purge`. `time`
+/// is an RFC 3339 date time, or a date, which means the start of that day in
+/// UTC.
+pub fn message_sort_key(time: &str) -> Option<String> {
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return Some(format!("message.{}", date.format("%Y-%m-%d")));
+    }
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|date_time| format!("message.{}", date_time.with_timezone(&Utc).to_rfc3339()))
+}
+
+async fn scan_all(table: &str, request: Scan) -> Result<Vec<Item>, ChatError> {
+    let mut items = Vec::new();
+    let mut start = None;
+    loop {
+        let output = db::call(
+            "Scan",
+            table,
+            request.clone().set_exclusive_start_key(start).send(),
+        )
+        .await?;
+        items.extend(output.items.unwrap_or_default());
+        start = output.last_evaluated_key;
+        if start.is_none() {
+            return Ok(items);
+        }
+    }
+}
+
+async fn query_all(table: &str, request: Query) -> Result<Vec<Item>, ChatError> {
+    let mut items = Vec::new();
+    let mut start = None;
+    loop {
+        let output = db::call(
+            "Query",
+            table,
+            request.clone().set_exclusive_start_key(start).send(),
+        )
+        .await?;
+        items.extend(output.items.unwrap_or_default());
+        start = output.last_evaluated_key;
+        if start.is_none() {
+            return Ok(items);
+        }
+    }
+}
+
+/// Deletes items by key, `BATCH_SIZE` at a time. DynamoDB can hand back part
+/// of a batch when it's busy, which is retried after a moment.
+async fn delete_all(dynamodb: &Db, table: &str, keys: Vec<Item>) -> Result<(), ChatError> {
+    for chunk in keys.chunks(BATCH_SIZE) {
+        let mut requests: Vec<WriteRequest> = chunk
+            .iter()
+            .map(|key| {
+                WriteRequest::builder()
+                    .delete_request(DeleteRequest::builder().set_key(Some(key.clone())).build())
+                    .build()
+            })
+            .collect();
+        let mut backoff = Duration::from_millis(50);
+        while !requests.is_empty() {
+            let output = db::call(
+                "BatchWriteItem",
+                table,
+                dynamodb
+                    .client
+                    .batch_write_item()
+                    .request_items(table, requests)
+                    .send(),
+            )
+            .await?;
+            requests = output
+                .unprocessed_items
+                .and_then(|mut unprocessed| unprocessed.remove(table))
+                .unwrap_or_default();
+            if !requests.is_empty() {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(5));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn string(item: &Item, key: &str) -> String {
+    match item.get(key) {
+        Some(AttributeValue::S(value)) | Some(AttributeValue::N(value)) => value.clone(),
+        _ => String::new(),
+    }
+}
+
+fn message_key(item: &Item) -> Item {
+    item.iter()
+        .filter(|(key, _)| *key == "room_id" || *key == "sort")
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+// Users
+
+pub async fn users(dynamodb: &Db) -> Result<Vec<UserSummary>, ChatError> {
+    let table = &dynamodb.tables.users;
+    let mut users: Vec<_> = scan_all(
+        table,
+        dynamodb
+            .client
+            .scan()
+            .table_name(table)
+            .projection_expression("user_id,#n")
+            .expression_attribute_names("#n", "name"),
+    )
+    .await?
+    .iter()
+    .map(|user| UserSummary {
+        id: string(user, "user_id"),
+        name: string(user, "name"),
+    })
+    .collect();
+    users.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(users)
+}
+
+/// Renames a user, unless somebody else has the name (or one that looks like
+/// it). Messages they already sent keep the name they were sent with.
+pub async fn rename_user(dynamodb: &Db, user_id: &str, name: &str) -> Result<(), ChatError> {
+    let name = validation::name("name", name)?;
+    let name_key = validation::name_key(&name);
+    let user = user_item(dynamodb, user_id)
+        .await?
+        .ok_or_else(|| ChatError::new(None, "User does not exist".into()))?;
+    let old_key = user.get("name_key").and_then(|key| key.as_s().ok());
+
+    // The condition makes the rename fail if they were renamed or deleted
+    // since they were read, so the name given up is the one they had.
+    let users = &dynamodb.tables.users;
+    let update = Update::builder()
+        .table_name(users)
+        .key("user_id", AttributeValue::N(user_id.to_owned()))
+        .update_expression("SET #n = :n, name_key = :k")
+        .expression_attribute_names("#n", "name")
+        .expression_attribute_values(":n", AttributeValue::S(name.clone()))
+        .expression_attribute_values(":k", AttributeValue::S(name_key.clone()));
+    let update = match old_key {
+        Some(old_key) => update
+            .condition_expression("name_key = :old")
+            .expression_attribute_values(":old", AttributeValue::S(old_key.clone())),
+        None => update
+            .condition_expression("attribute_exists(user_id) AND attribute_not_exists(name_key)"),
+    };
+    let mut request = dynamodb
+        .client
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().update(update.build()).build())
+        .transact_items(db::claim_name(dynamodb, &name_key, user_id, &name));
+    if let Some(old_key) = old_key {
+        if *old_key != name_key && owns_name(dynamodb, user_id, old_key).await? {
+            request = request.transact_items(db::release_name(dynamodb, old_key, user_id));
+        }
+    }
+
+    match db::call("TransactWriteItems", users, request.send()).await {
+        Ok(_) => Ok(()),
+        Err(error) if db::condition_failed(&error, 1) => {
+            Err(ChatError::new(None, "Name already registered".into()))
+        }
+        Err(error) if db::condition_failed(&error, 0) || db::condition_failed(&error, 2) => {
+            Err(changed_meanwhile())
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Deletes a user, and gives up their name. Their messages stay, see `purge`
+/// for those.
+pub async fn delete_user(dynamodb: &Db, user_id: &str) -> Result<(), ChatError> {
+    let user = user_item(dynamodb, user_id)
+        .await?
+        .ok_or_else(|| ChatError::new(None, "User does not exist".into()))?;
+    let name_key = user.get("name_key").and_then(|key| key.as_s().ok());
+
+    let users = &dynamodb.tables.users;
+    let delete = Delete::builder()
+        .table_name(users)
+        .key("user_id", AttributeValue::N(user_id.to_owned()));
+    let delete = match name_key {
+        Some(name_key) => delete
+            .condition_expression("name_key = :k")
+            .expression_attribute_values(":k", AttributeValue::S(name_key.clone())),
+        None => delete
+            .condition_expression("attribute_exists(user_id) AND attribute_not_exists(name_key)"),
+    };
+    let mut request = dynamodb
+        .client
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().delete(delete.build()).build());
+    if let Some(name_key) = name_key {
+        if owns_name(dynamodb, user_id, name_key).await? {
+            request = request.transact_items(db::release_name(dynamodb, name_key, user_id));
+        }
+    }
+
+    match db::call("TransactWriteItems", users, request.send()).await {
+        Ok(_) => Ok(()),
+        Err(error) if db::condition_failed(&error, 0) || db::condition_failed(&error, 1) => {
+            Err(changed_meanwhile())
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// A user, read consistently, since it's about to be changed.
+async fn user_item(dynamodb: &Db, user_id: &str) -> Result<Option<Item>, ChatError> {
+    let output = db::call(
+        "GetItem",
+        &dynamodb.tables.users,
+        dynamodb
+            .client
+            .get_item()
+            .table_name(&dynamodb.tables.users)
+            .key("user_id", AttributeValue::N(user_id.to_owned()))
+            .consistent_read(true)
+            .send(),
+    )
+    .await?;
+    Ok(output.item)
+}
+
+/// Whether a user has their `name_key` in the user names table. Users who
+/// signed up twice under look-alike names before names were claimed there
+/// only have one claim between them.
+async fn owns_name(dynamodb: &Db, user_id: &str, name_key: &str) -> Result<bool, ChatError> {
+    Ok(db::get_name_owner(dynamodb, name_key)
+        .await?
+        .is_some_and(|(owner, _)| owner == user_id))
+}
+
+fn changed_meanwhile() -> ChatError {
+    ChatError::new(
+        None,
+        "The user was changed or deleted in the meantime, try again".into(),
+    )
+}
+
+// Rooms
+
+/// Every room item, whether or not it's in the list of active rooms.
+async fn room_items(dynamodb: &Db) -> Result<Vec<Item>, ChatError> {
+    let table = &dynamodb.tables.messages;
+    scan_all(
+        table,
+        dynamodb
+            .client
+            .scan()
+            .table_name(table)
+            .filter_expression("sort = :room")
+            .projection_expression("room_id,#n,changed_at")
+            .expression_attribute_names("#n", "name")
+            .expression_attribute_values(":room", AttributeValue::S("room".into())),
+    )
+    .await
+}
+
+/// Every room, the active ones first, most recently used first, like
+/// `GET /rooms`.
+pub async fn rooms(dynamodb: &Db) -> Result<Vec<RoomSummary>, ChatError> {
+    let active = db::get_active_room_ids(dynamodb).await?;
+    let mut rooms: Vec<_> = room_items(dynamodb)
+        .await?
+        .iter()
+        .map(|room| {
+            let id = string(room, "room_id");
+            RoomSummary {
+                active: active.contains(&id),
+                name: string(room, "name"),
+                id,
+            }
+        })
+        .collect();
+    let position = |room: &RoomSummary| active.iter().rev().position(|id| *id == room.id);
+    rooms.sort_by(|a, b| match (position(a), position(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => a.name.cmp(&b.name),
+    });
+    Ok(rooms)
+}
+
+pub async fn rename_room(dynamodb: &Db, room_id: &str, name: &str) -> Result<(), ChatError> {
+    let name = validation::name("name", name)?;
+    let result = db::call(
+        "UpdateItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .update_item()
+            .table_name(&dynamodb.tables.messages)
+            .key("room_id", AttributeValue::N(room_id.to_owned()))
+            .key("sort", AttributeValue::S("room".into()))
+            .update_expression("SET #n = :n, changed_at = :c")
+            .condition_expression("attribute_exists(room_id)")
+            .expression_attribute_names("#n", "name")
+            .expression_attribute_values(":n", AttributeValue::S(name))
+            .expression_attribute_values(":c", AttributeValue::N(Token::now().to_string()))
+            .send(),
+    )
+    .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(SdkError::ServiceError { err, .. }) if err.is_conditional_check_failed_exception() => {
+            Err(ChatError::new(None, "Room does not exist".into()))
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Deletes a room along with its messages and flood control records, and
+/// takes it off the list of active rooms. Returns how many items went.
+pub async fn delete_room(dynamodb: &Db, room_id: &str) -> Result<usize, ChatError> {
+    // The list of active rooms lives under room 1.
+    if room_id == "1" {
+        return Err(ChatError::new(None, "Room does not exist".into()));
+    }
+    let table = &dynamodb.tables.messages;
+    let keys: Vec<_> = query_all(
+        table,
+        dynamodb
+            .client
+            .query()
+            .table_name(table)
+            .key_condition_expression("room_id = :r")
+            .expression_attribute_values(":r", AttributeValue::N(room_id.to_owned()))
+            .projection_expression("room_id,sort"),
+    )
+    .await?;
+    if keys.is_empty() {
+        return Err(ChatError::new(None, "Room does not exist".into()));
+    }
+
+    db::update_active_room_ids(dynamodb, |mut active| {
+        active.retain(|id| id != room_id);
+        active
+    })
+    .await?;
+    let deleted = keys.len();
+    delete_all(dynamodb, table, keys).await?;
+    Ok(deleted)
+}
+
+/// Rewrites the list of active rooms from the rooms that exist, ordered by
+/// when they last changed. Rooms that haven't changed since `changed_at` was
+/// added go by their latest message. Returns the room IDs, most recently used
+/// first.
+pub async fn rebuild_active_rooms(dynamodb: &Db) -> Result<Vec<String>, ChatError> {
+    let before: HashSet<_> = db::get_active_room_ids(dynamodb)
+        .await?
+        .into_iter()
+        .collect();
+    let mut rooms = Vec::new();
+    for room in room_items(dynamodb).await? {
+        let id = string(&room, "room_id");
+        let changed_at = match db::room_changed_at(&room)? {
+            Some(changed_at) => Some(changed_at),
+            None => db::get_messages(dynamodb, &id, 1)
+                .await?
+                .first()
+                .and_then(|message| Token::from_sort_key(&string(message, "sort"))),
+        };
+        rooms.push((changed_at, id));
+    }
+    rooms.sort();
+
+    let rebuilt: Vec<_> = rooms.into_iter().map(|(_, id)| id).collect();
+    // Rooms created while the rooms were being read are kept, as the most
+    // recently used.
+    let room_ids = db::update_active_room_ids(dynamodb, |active| {
+        let mut room_ids = rebuilt.clone();
+        room_ids.extend(
+            active
+                .into_iter()
+                .filter(|id| !before.contains(id) && !rebuilt.contains(id)),
+        );
+        room_ids
+    })
+    .await?;
+    Ok(room_ids.into_iter().rev().collect())
+}
+
+// Messages
+
+/// Deletes the messages that match `purge`, unless it's a `dry_run`. Rooms
+/// that lose messages get their version bumped, so that clients polling them
+/// reload.
+pub async fn purge(dynamodb: &Db, purge: &Purge, dry_run: bool) -> Result<Purged, ChatError> {
+    let table = &dynamodb.tables.messages;
+    let mut filters = Vec::new();
+    let mut values = HashMap::new();
+    if let Some(user_id) = &purge.user_id {
+        filters.push("sender_id = :u");
+        values.insert(":u".to_owned(), AttributeValue::N(user_id.clone()));
+    }
+
+    let messages = match &purge.room_id {
+        // One room's messages are a range of its sort keys.
+        Some(room_id) => {
+            values.insert(":r".to_owned(), AttributeValue::N(room_id.clone()));
+            values.insert(
+                ":s".to_owned(),
+                AttributeValue::S(purge.since.clone().unwrap_or_else(|| "message.".into())),
+            );
+            values.insert(
+                ":e".to_owned(),
+                AttributeValue::S(purge.until.clone().unwrap_or_else(|| "message/".into())),
+            );
+            query_all(
+                table,
+                dynamodb
+                    .client
+                    .query()
+                    .table_name(table)
+                    .key_condition_expression("room_id = :r AND sort BETWEEN :s AND :e")
+                    .set_filter_expression((!filters.is_empty()).then(|| filters.join(" AND ")))
+                    .set_expression_attribute_values(Some(values))
+                    .projection_expression("room_id,sort"),
+            )
+            .await?
+        }
+        None => {
+            filters.push("begins_with(sort, :m)");
+            values.insert(":m".to_owned(), AttributeValue::S("message.".into()));
+            if let Some(since) = &purge.since {
+                filters.push("sort >= :s");
+                values.insert(":s".to_owned(), AttributeValue::S(since.clone()));
+            }
+            if let Some(until) = &purge.until {
+                filters.push("sort < :e");
+                values.insert(":e".to_owned(), AttributeValue::S(until.clone()));
+            }
+            scan_all(
+                table,
+                dynamodb
+                    .client
+                    .scan()
+                    .table_name(table)
+                    .filter_expression(filters.join(" AND "))
+                    .set_expression_attribute_values(Some(values))
+                    .projection_expression("room_id,sort"),
+            )
+            .await?
+        }
+    };
+    // `BETWEEN` takes both ends, `until` is just past the end.
+    let messages: Vec<_> = messages
+        .into_iter()
+        .filter(|message| {
+            purge
+                .until
+                .as_ref()
+                .is_none_or(|until| string(message, "sort") < *until)
+        })
+        .collect();
+
+    let rooms: BTreeSet<_> = messages
+        .iter()
+        .map(|message| string(message, "room_id"))
+        .collect();
+    let purged = Purged {
+        messages: messages.len(),
+        rooms: rooms.into_iter().collect(),
+    };
+    if dry_run {
+        return Ok(purged);
+    }
+
+    delete_all(dynamodb, table, messages.iter().map(message_key).collect()).await?;
+    for room_id in &purged.rooms {
+        // The room itself may be gone.
+        if let Err(error) = db::rewrite_room_history(dynamodb, room_id).await {
+            tracing::debug!(room_id, error = ?error.debug, "Could not bump room version");
+        }
+    }
+    Ok(purged)
+}
+
+// Checks
+
+/// Looks for data the API doesn't expect:
+///
+/// - `dangling_active_room`: the list of active rooms has a room that
+///   doesn't exist.
+/// - `duplicate_active_room`: the list has a room more than once.
+/// - `inactive_room`: a room isn't on the list, so `GET /rooms` doesn't show
+///   it.
+/// - `orphaned_message`: a message's `sender_id` isn't a user.
+/// - `message_without_room`: a message is in a room that doesn't exist.
+///
+/// `rebuild_active_rooms` fixes the first three.
+pub async fn check(dynamodb: &Db) -> Result<Vec<Problem>, ChatError> {
+    let mut problems = Vec::new();
+    let rooms: HashSet<_> = room_items(dynamodb)
+        .await?
+        .iter()
+        .map(|room| string(room, "room_id"))
+        .collect();
+
+    let active = db::get_active_room_ids(dynamodb).await?;
+    let mut seen = HashSet::new();
+    for room_id in &active {
+        if !seen.insert(room_id) {
+            problems.push(Problem {
+                kind: "duplicate_active_room",
+                detail: format!("Room {} is in the list of active rooms twice", room_id),
+            });
+        } else if !rooms.contains(room_id) {
+            problems.push(Problem {
+                kind: "dangling_active_room",
+                detail: format!(
+                    "Room {} is in the list of active rooms but doesn't exist",
+                    room_id
+                ),
+            });
+        }
+    }
+    let mut inactive: Vec<_> = rooms.iter().filter(|id| !seen.contains(id)).collect();
+    inactive.sort();
+    for room_id in inactive {
+        problems.push(Problem {
+            kind: "inactive_room",
+            detail: format!("Room {} isn't in the list of active rooms", room_id),
+        });
+    }
+
+    let users: HashSet<_> = users(dynamodb)
+        .await?
+        .into_iter()
+        .map(|user| user.id)
+        .collect();
+    let table = &dynamodb.tables.messages;
+    let messages = scan_all(
+        table,
+        dynamodb
+            .client
+            .scan()
+            .table_name(table)
+            .filter_expression("begins_with(sort, :m)")
+            .expression_attribute_values(":m", AttributeValue::S("message.".into()))
+            .projection_expression("room_id,sort,sender_id"),
+    )
+    .await?;
+    for message in messages {
+        let room_id = string(&message, "room_id");
+        let sort = string(&message, "sort");
+        if !rooms.contains(&room_id) {
+            problems.push(Problem {
+                kind: "message_without_room",
+                detail: format!(
+                    "Message {} is in room {}, which doesn't exist",
+                    sort, room_id
+                ),
+            });
+        }
+        let sender_id = string(&message, "sender_id");
+        if !users.contains(&sender_id) {
+            problems.push(Problem {
+                kind: "orphaned_message",
+                detail: format!(
+                    "Message {} in room {} was sent by user {}, who doesn't exist",
+                    sort, room_id, sender_id
+                ),
+            });
+        }
+    }
+    Ok(problems)
+}
+
+// Fixtures
+
+/// Sample users, rooms and messages for trying the app out. Users and rooms
+/// that already exist by name are left alone, and messages are only posted in
+/// rooms that were just created, so seeding twice doesn't double up.
+pub async fn seed(dynamodb: &Db) -> Result<Seeded, ChatError> {
+    let mut seeded = Seeded::default();
+    let mut user_ids = HashMap::new();
+    for name in ["Ryan", "Peter"] {
+        let (user_id, _, created) =
+            db::find_or_create_user(dynamodb, name, &validation::name_key(name)).await?;
+        if created {
+            seeded.users += 1;
+        }
+        user_ids.insert(name, user_id);
+    }
+
+    let rooms: [(&str, &[(&str, &str)]); 2] = [
+        (
+            "Bird Watching",
+            &[("Ryan", "Hello"), ("Peter", "Saw a heron this morning")],
+        ),
+        ("Fan club", &[("Peter", "He-haw"), ("Ryan", "Hello")]),
+    ];
+    for (name, messages) in rooms {
+        if room_by_name(dynamodb, name).await?.is_some() {
+            continue;
+        }
+        let room_id = crate::uuid();
+        db::create_room(dynamodb, &room_id, name).await?;
+        db::bump_room(dynamodb, &room_id).await?;
+        seeded.rooms += 1;
+        for (sender, message) in messages.iter() {
+            db::post_message(
+                dynamodb,
+                &room_id,
+                message,
+                &user_ids[sender],
+                sender,
+                &Utc::now().to_rfc3339(),
+            )
+            .await?;
+            db::bump_room_version(dynamodb, &room_id).await?;
+            seeded.messages += 1;
+        }
+    }
+    Ok(seeded)
+}
+
+/// The ID of a room with exactly this name, if there is one.
+pub async fn room_by_name(dynamodb: &Db, name: &str) -> Result<Option<String>, ChatError> {
+    let output = db::call(
+        "Query",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .query()
+            .table_name(&dynamodb.tables.messages)
+            .index_name("name-index")
+            .key_condition_expression("#n = :n")
+            .expression_attribute_names("#n", "name")
+            .expression_attribute_values(":n", AttributeValue::S(name.to_owned()))
+            .send(),
+    )
+    .await?;
+    Ok(output
+        .items
+        .unwrap_or_default()
+        .first()
+        .map(|room| string(room, "room_id")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn purge_bounds_are_dates_or_rfc_3339() {
+        assert_eq!(
+            message_sort_key("2025-10-18").as_deref(),
+            Some("message.2025-10-18")
+        );
+        // Times are moved to UTC, like message dates.
+        assert_eq!(
+            message_sort_key("2025-10-18T14:30:00+02:00").as_deref(),
+            Some("message.2025-10-18T12:30:00+00:00")
+        );
+        assert_eq!(
+            message_sort_key("2025-10-18T12:30:00.5Z").as_deref(),
+            Some("message.2025-10-18T12:30:00.500+00:00")
+        );
+        for invalid in [
+            "",
+            "yesterday",
+            "2025-13-01",
+            "2025-10-18 12:30",
+            "18/10/2025",
+        ] {
+            assert_eq!(message_sort_key(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn dates_are_the_start_of_the_day() {
+        let day = message_sort_key("2025-10-18").unwrap();
+        let message = |date_time: &str| format!("message.{}", date_time);
+
+        assert!(message("2025-10-17T23:59:59.999999999+00:00") < day);
+        assert!(message("2025-10-18T00:00:00+00:00") > day);
+        assert!(message("2025-10-18T23:59:59+00:00") < message_sort_key("2025-10-19").unwrap());
+        // Both bounds sort among the messages of the day they're on.
+        let noon = message_sort_key("2025-10-18T12:00:00Z").unwrap();
+        assert!(message("2025-10-18T11:59:59.999+00:00") < noon);
+        assert_eq!(message("2025-10-18T12:00:00+00:00"), noon);
+        assert!(message("2025-10-18T12:00:00.001+00:00") > noon);
+    }
+}
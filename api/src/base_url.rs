@@ -0,0 +1,505 @@
+use crate::{config::Config, errors::ChatError, versioning::RequestedVersion};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequest, RequestParts},
+};
+use http::{uri::Authority, HeaderMap, Request};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+/// The URL clients used to reach this API, e.g. `https://chat.example.com`,
+/// with no trailing slash. Every URI in a response is built on top of it. If
+/// the request came in under a version prefix, like `/v2`, the prefix is part
+/// of the base URL, so that links keep the client on the version it chose.
+///
+/// When the request came through one of `Config::trusted_proxies`, this is
+/// worked out from the `Forwarded` header, or from `X-Forwarded-Proto` and
+/// `X-Forwarded-Host` if there's no `Forwarded` header. Otherwise, or if the
+/// proxy didn't say, it's `Config::public_base_url`. Headers from anybody else
+/// are ignored, since anybody can send them.
+///
+/// ```ignore
+/// async fn handler(BaseUrl(base_url): BaseUrl) -> String {
+///     format!("{}/rooms", base_url)
+/// }
+/// ```
+pub struct BaseUrl(pub String);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for BaseUrl {
+    type Rejection = ChatError;
+
+    async fn from_request(request: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let extensions = request.extensions().ok_or_else(|| {
+            ChatError::new(
+                Some("Extensions taken by another extractor".into()),
+                "Internal server error".into(),
+            )
+        })?;
+        let config = extensions.get::<Arc<Config>>().ok_or_else(|| {
+            ChatError::new(
+                Some("Config extension is missing".into()),
+                "Internal server error".into(),
+            )
+        })?;
+        let peer = extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+
+        let forwarded = match (peer, request.headers()) {
+            (Some(peer), Some(headers)) if is_trusted(&config.trusted_proxies, peer) => {
+                forwarded(&config.trusted_proxies, headers).or_else(|| x_forwarded(headers))
+            }
+            _ => None,
+        };
+
+        let prefix = extensions
+            .get::<RequestedVersion>()
+            .map_or("", |requested| requested.prefix);
+
+        Ok(BaseUrl(match forwarded {
+            Some((proto, host)) => format!("{}://{}{}", proto, host, prefix),
+            None => format!("{}{}", config.public_base_url, prefix),
+        }))
+    }
+}
+
+/// An IP address or a CIDR block, like `10.0.0.1` or `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix_length: u8,
+}
+
+impl TryFrom<String> for TrustedProxy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || {
+            format!(
+                "{:?} is not an IP address like \"10.0.0.1\" or \"10.0.0.0/8\"",
+                value
+            )
+        };
+        let (network, prefix_length) = match value.split_once('/') {
+            Some((network, prefix_length)) => (
+                network.trim().parse::<IpAddr>().map_err(|_| invalid())?,
+                Some(prefix_length.trim().parse::<u8>().map_err(|_| invalid())?),
+            ),
+            None => (value.trim().parse::<IpAddr>().map_err(|_| invalid())?, None),
+        };
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_length = prefix_length.unwrap_or(max);
+        if prefix_length > max {
+            return Err(invalid());
+        }
+        Ok(Self {
+            network,
+            prefix_length,
+        })
+    }
+}
+
+impl From<TrustedProxy> for String {
+    fn from(proxy: TrustedProxy) -> Self {
+        format!("{}/{}", proxy.network, proxy.prefix_length)
+    }
+}
+
+impl TrustedProxy {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(ip) => ip
+                .to_ipv4_mapped()
+                .map(IpAddr::V4)
+                .unwrap_or(IpAddr::V6(ip)),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_trusted(trusted_proxies: &[TrustedProxy], ip: IpAddr) -> bool {
+    trusted_proxies.iter().any(|proxy| proxy.contains(ip))
+}
+
+/// The IP address of the client that sent a request, for keying per-client
+/// state like rate limits. `None` if the server wasn't started with
+/// `ConnectInfo`.
+///
+/// Like `BaseUrl`, this only believes `Forwarded` and `X-Forwarded-For` when
+/// the request came through one of `Config::trusted_proxies`. Otherwise it's
+/// the peer address, which behind a proxy would be the proxy's.
+pub fn client_ip<B>(request: &Request<B>) -> Option<IpAddr> {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip())?;
+    let trusted_proxies = match request.extensions().get::<Arc<Config>>() {
+        Some(config) if is_trusted(&config.trusted_proxies, peer) => &config.trusted_proxies,
+        _ => return Some(peer),
+    };
+
+    // Each proxy appends who it got the request from, so walking the hops
+    // back from the end, the first one that isn't a trusted proxy is the
+    // client. If they all are, the client is the first hop.
+    let hops = forwarded_for(request.headers());
+    let client = hops
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(trusted_proxies, **ip))
+        .or_else(|| hops.first())
+        .copied();
+    Some(client.unwrap_or(peer))
+}
+
+/// The `for` of each element of `Forwarded`, or if there's no `Forwarded`
+/// header, the addresses in `X-Forwarded-For`. Hops that aren't IP addresses,
+/// like `unknown` or obfuscated identifiers, are skipped.
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    let values = |name: &str| -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|value| value.trim().to_owned())
+            .collect()
+    };
+    let forwarded = values("forwarded");
+    if forwarded.is_empty() {
+        return values("x-forwarded-for")
+            .iter()
+            .filter_map(|node| node_ip(node))
+            .collect();
+    }
+    forwarded
+        .iter()
+        .filter_map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| node_ip(node.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+/// Reads the proto and host from the RFC 7239 `Forwarded` header.
+///
+/// Each proxy adds an element to the end of the header, saying who it got the
+/// request from (`for`) and how (`proto` and `host`). Walking it from the end,
+/// for as long as the requests came from other trusted proxies, finds the
+/// element written by the proxy that the client actually connected to.
+fn forwarded(trusted_proxies: &[TrustedProxy], headers: &HeaderMap) -> Option<(String, String)> {
+    let elements: Vec<Vec<(String, String)>> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| {
+                    (
+                        key.trim().to_ascii_lowercase(),
+                        value.trim().trim_matches('"').to_owned(),
+                    )
+                })
+                .collect()
+        })
+        .collect();
+
+    let get = |element: &Vec<(String, String)>, key: &str| {
+        element
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+    };
+
+    let element = elements
+        .iter()
+        .rev()
+        .find(|element| {
+            !get(element, "for")
+                .and_then(|node| node_ip(&node))
+                .is_some_and(|ip| is_trusted(trusted_proxies, ip))
+        })
+        .or_else(|| elements.first())?;
+
+    valid(get(element, "proto"), get(element, "host"))
+}
+
+/// Reads the proto and host from `X-Forwarded-Proto` and `X-Forwarded-Host`.
+/// When a proxy adds to these instead of replacing them, the last value is
+/// the one our proxy added.
+fn x_forwarded(headers: &HeaderMap) -> Option<(String, String)> {
+    let last = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .map(|value| value.trim().to_owned())
+    };
+    valid(last("x-forwarded-proto"), last("x-forwarded-host"))
+}
+
+/// Checks that a forwarded proto and host make a sane base URL. A missing
+/// proto is taken to be `http`, but without a host there's nothing to go on.
+fn valid(proto: Option<String>, host: Option<String>) -> Option<(String, String)> {
+    let proto = proto.unwrap_or_else(|| "http".into()).to_ascii_lowercase();
+    let host = host?.parse::<Authority>().ok()?;
+    if (proto == "http" || proto == "https") && !host.as_str().contains('@') {
+        Some((proto, host.as_str().to_ascii_lowercase()))
+    } else {
+        None
+    }
+}
+
+/// The IP address in a `Forwarded` node, like `192.0.2.43`,
+/// `192.0.2.43:47011` or `[2001:db8:cafe::17]:4711`.
+fn node_ip(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|address| address.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(proxy: &str) -> TrustedProxy {
+        TrustedProxy::try_from(proxy.to_owned()).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    /// A request from `peer`, to a server that trusts `10.0.0.0/8`.
+    fn request(peer: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let config = Config {
+            public_base_url: "http://localhost:5000".into(),
+            trusted_proxies: vec![proxy("10.0.0.0/8")],
+            ..Config::default()
+        };
+        let mut request = Request::builder().uri("/rooms");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let mut request = request.body(()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::new(ip(peer), 4711)));
+        request.extensions_mut().insert(Arc::new(config));
+        request
+    }
+
+    async fn base_url(peer: &str, headers: &[(&str, &str)]) -> String {
+        let mut request = RequestParts::new(request(peer, headers));
+        BaseUrl::from_request(&mut request).await.ok().unwrap().0
+    }
+
+    #[test]
+    fn proxies_are_addresses_or_blocks() {
+        assert!(proxy("10.0.0.1").contains(ip("10.0.0.1")));
+        assert!(!proxy("10.0.0.1").contains(ip("10.0.0.2")));
+        assert!(proxy("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!proxy("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(proxy("172.16.0.0/12").contains(ip("172.31.255.255")));
+        assert!(!proxy("172.16.0.0/12").contains(ip("172.32.0.0")));
+        assert!(proxy("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(proxy("2001:db8::/32").contains(ip("2001:db8:cafe::17")));
+        assert!(!proxy("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(!proxy("::/0").contains(ip("10.0.0.1")));
+
+        // A dual stack server sees IPv4 peers as IPv4-mapped IPv6 addresses.
+        assert!(proxy("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert!(!proxy("10.0.0.0/8").contains(ip("::ffff:11.0.0.1")));
+
+        for invalid in [
+            "",
+            "nope",
+            "10.0.0.0/33",
+            "::1/129",
+            "10.0.0.0/x",
+            "10.0.0/8",
+        ] {
+            assert!(
+                TrustedProxy::try_from(invalid.to_owned()).is_err(),
+                "{}",
+                invalid
+            );
+        }
+        assert_eq!(String::from(proxy(" 10.0.0.1 ")), "10.0.0.1/32");
+    }
+
+    #[test]
+    fn headers_are_only_believed_from_trusted_proxies() {
+        let headers = [("x-forwarded-for", "198.51.100.7")];
+        assert_eq!(
+            client_ip(&request("203.0.113.9", &headers)),
+            Some(ip("203.0.113.9"))
+        );
+        assert_eq!(
+            client_ip(&request("10.0.0.1", &headers)),
+            Some(ip("198.51.100.7"))
+        );
+        assert_eq!(
+            client_ip(&request("::ffff:10.0.0.1", &headers)),
+            Some(ip("198.51.100.7"))
+        );
+        assert_eq!(client_ip(&request("10.0.0.1", &[])), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn the_client_is_the_last_hop_that_isnt_a_proxy() {
+        // The client made up the first hop, and our proxies added the rest.
+        let forwarded = [(
+            "forwarded",
+            "for=192.0.2.1, for=198.51.100.7;proto=https, for=10.0.0.5",
+        )];
+        assert_eq!(
+            client_ip(&request("10.0.0.1", &forwarded)),
+            Some(ip("198.51.100.7"))
+        );
+
+        let x_forwarded_for = [("x-forwarded-for", "192.0.2.1, 198.51.100.7, 10.0.0.5")];
+        assert_eq!(
+            client_ip(&request("10.0.0.1", &x_forwarded_for)),
+            Some(ip("198.51.100.7"))
+        );
+
+        // Forwarded wins over X-Forwarded-For.
+        let both = [
+            ("forwarded", "for=198.51.100.7"),
+            ("x-forwarded-for", "192.0.2.1"),
+        ];
+        assert_eq!(
+            client_ip(&request("10.0.0.1", &both)),
+            Some(ip("198.51.100.7"))
+        );
+
+        // Only proxies all the way, so the first hop is all there is to go on.
+        let proxies = [("forwarded", "for=10.0.0.7, for=10.0.0.5")];
+        assert_eq!(
+            client_ip(&request("10.0.0.1", &proxies)),
+            Some(ip("10.0.0.7"))
+        );
+    }
+
+    #[test]
+    fn forwarded_nodes_can_be_quoted_with_ports_or_ipv6() {
+        for (node, client) in [
+            ("192.0.2.43", "192.0.2.43"),
+            ("\"192.0.2.43:47011\"", "192.0.2.43"),
+            ("\"[2001:db8:cafe::17]\"", "2001:db8:cafe::17"),
+            ("\"[2001:db8:cafe::17]:4711\"", "2001:db8:cafe::17"),
+        ] {
+            let element = format!("For={};proto=https", node);
+            let headers = [("forwarded", element.as_str())];
+            assert_eq!(
+                client_ip(&request("10.0.0.1", &headers)),
+                Some(ip(client)),
+                "{}",
+                node
+            );
+        }
+
+        // Hops that aren't addresses are skipped.
+        let hidden = [("forwarded", "for=198.51.100.7, for=unknown, for=_hidden")];
+        assert_eq!(
+            client_ip(&request("10.0.0.1", &hidden)),
+            Some(ip("198.51.100.7"))
+        );
+    }
+
+    #[tokio::test]
+    async fn the_base_url_comes_from_the_proxy_the_client_connected_to() {
+        let forwarded = [(
+            "forwarded",
+            "for=198.51.100.7;proto=https;host=chat.example.com",
+        )];
+        assert_eq!(
+            base_url("10.0.0.1", &forwarded).await,
+            "https://chat.example.com"
+        );
+        assert_eq!(
+            base_url("203.0.113.9", &forwarded).await,
+            "http://localhost:5000"
+        );
+
+        // The client's own element is ignored, the proxy's is used.
+        let spoofed = [(
+            "forwarded",
+            "for=10.0.0.9;proto=http;host=evil.example, \
+             for=198.51.100.7;proto=https;host=chat.example.com",
+        )];
+        assert_eq!(
+            base_url("10.0.0.1", &spoofed).await,
+            "https://chat.example.com"
+        );
+
+        let x_forwarded = [
+            ("x-forwarded-proto", "http, https"),
+            ("x-forwarded-host", "evil.example, Chat.Example.com:8443"),
+        ];
+        assert_eq!(
+            base_url("10.0.0.1", &x_forwarded).await,
+            "https://chat.example.com:8443"
+        );
+    }
+
+    #[tokio::test]
+    async fn bad_forwarded_hosts_and_protos_are_ignored() {
+        for element in [
+            "proto=javascript;host=chat.example.com",
+            "proto=https;host=user@evil.example",
+            "proto=https;host=\"bad host\"",
+            "proto=https;host=chat.example.com/path",
+            "proto=https",
+        ] {
+            let headers = [("forwarded", element)];
+            assert_eq!(
+                base_url("10.0.0.1", &headers).await,
+                "http://localhost:5000",
+                "{}",
+                element
+            );
+        }
+        assert_eq!(
+            valid(None, Some("chat.example.com".into())),
+            Some(("http".into(), "chat.example.com".into()))
+        );
+        assert_eq!(
+            valid(Some("HTTPS".into()), Some("[2001:db8::1]:8443".into())),
+            Some(("https".into(), "[2001:db8::1]:8443".into()))
+        );
+    }
+}
@@ -0,0 +1,549 @@
+use api::{
+    admin,
+    config::{Args, Config},
+    db::Db,
+    errors::ChatError,
+    export::{Export, Format},
+    import::{self, Source},
+    migrations, schema,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tracing_subscriber::EnvFilter;
+
+const USAGE: &str = "\
+Usage: chatctl [--config <path>] [--json] <command>
+
+Commands:
+  create-tables                   Create the tables and run migrations, like the API does on start
+  seed                            Add sample users, rooms and messages
+  users list
+  users rename <user_id> <name>
+  users delete <user_id>          Their messages stay, see purge
+  rooms list
+  rooms rename <room_id> <name>
+  rooms delete <room_id>          Along with its messages
+  purge [--room <room_id>] [--user <user_id>] [--since <time>] [--until <time>] [--dry-run]
+                                  Delete messages matching every filter given. Times are
+                                  RFC 3339 or YYYY-MM-DD (UTC); --until is exclusive
+  rebuild-active-rooms            Rewrite the room list from the rooms that exist
+  check                           Look for dangling or orphaned data, exits 1 if there is any
+  export [--format <format>] [--dir <dir>] (--all | <room_id>...)
+                                  Write each room's history to <dir>/room-<room_id>.<format>.
+                                  Formats are json (the default), ndjson, csv, html and txt
+  import (slack | discord) <file> Import history from a Slack export zip or a channel exported
+                                  as JSON with DiscordChatExporter. Running it again only
+                                  writes what's missing
+
+The config is read like the API's: --config or CONFIG_FILE, then environment
+variables on top.";
+
+/// Admin tool for the chat service. It talks to the same DynamoDB tables as
+/// the API, with the same config, see `admin` for what each command does.
+#[tokio::main]
+async fn main() {
+    let mut config_file = std::env::var_os("CONFIG_FILE").map(PathBuf::from);
+    let mut as_json = false;
+    let mut words = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => match args.next() {
+                Some(path) => config_file = Some(path.into()),
+                None => usage("--config needs a path"),
+            },
+            "--json" => as_json = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => words.push(arg),
+        }
+    }
+
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let command = Command::parse(&words).unwrap_or_else(|error| usage(&error));
+
+    let config = match Config::load(&Args {
+        config_file,
+        print_config: false,
+        print_openapi: false,
+    }) {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("Invalid configuration:");
+            for error in errors {
+                eprintln!("  - {}", error);
+            }
+            std::process::exit(1);
+        }
+    };
+
+    // Logs go to stderr, so that they don't get mixed up with the output.
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+        )
+        .init();
+
+    let dynamodb = Db {
+        client: api::dynamodb_client(&config.dynamodb).await,
+        tables: config.tables.prefixed(),
+    };
+    let output = Output { as_json };
+    if let Err(error) = run(command, &config, &dynamodb, &output).await {
+        eprintln!("{}", error.display);
+        for field in &error.fields {
+            eprintln!("  - {}: {}", field.field, field.message);
+        }
+        if let Some(debug) = &error.debug {
+            eprintln!("{}", debug);
+        }
+        std::process::exit(1);
+    }
+}
+
+/// What to do, read from the arguments before anything else, so that a
+/// mistake in them is reported without touching DynamoDB.
+enum Command {
+    CreateTables,
+    Seed,
+    Users,
+    RenameUser(String, String),
+    DeleteUser(String),
+    Rooms,
+    RenameRoom(String, String),
+    DeleteRoom(String),
+    Purge(admin::Purge, bool),
+    RebuildActiveRooms,
+    Check,
+    /// `None` for every room.
+    Export(Option<Vec<String>>, Format, PathBuf),
+    Import(Source, PathBuf),
+}
+
+impl Command {
+    fn parse(words: &[&str]) -> Result<Command, String> {
+        let owned = |word: &str| word.to_string();
+        Ok(match words {
+            ["create-tables"] => Command::CreateTables,
+            ["seed"] => Command::Seed,
+            ["users", "list"] => Command::Users,
+            ["users", "rename", user_id, name] => Command::RenameUser(owned(user_id), owned(name)),
+            ["users", "delete", user_id] => Command::DeleteUser(owned(user_id)),
+            ["rooms", "list"] => Command::Rooms,
+            ["rooms", "rename", room_id, name] => Command::RenameRoom(owned(room_id), owned(name)),
+            ["rooms", "delete", room_id] => Command::DeleteRoom(owned(room_id)),
+            ["purge", flags @ ..] => {
+                let (purge, dry_run) = purge_flags(flags)?;
+                Command::Purge(purge, dry_run)
+            }
+            ["rebuild-active-rooms"] => Command::RebuildActiveRooms,
+            ["check"] => Command::Check,
+            ["export", flags @ ..] => export_flags(flags)?,
+            ["import", source, file] => match Source::parse("source", source) {
+                Ok(source) => Command::Import(source, file.into()),
+                Err(error) => return Err(format!("{} {}", error.field, error.message)),
+            },
+            [] => return Err("No command given".into()),
+            _ => return Err(format!("Unknown command {:?}", words.join(" "))),
+        })
+    }
+}
+
+async fn run(
+    command: Command,
+    config: &Config,
+    dynamodb: &Db,
+    output: &Output,
+) -> Result<(), ChatError> {
+    match command {
+        Command::CreateTables => {
+            let tables = schema::tables(config);
+            if let Err(problems) = schema::apply(dynamodb, &tables, &config.schema).await {
+                return Err(ChatError::new(
+                    Some(problems.join("\n")),
+                    "The DynamoDB tables don't match what the API needs".into(),
+                ));
+            }
+            migrations::run(dynamodb).await?;
+            let names: Vec<_> = tables.into_iter().map(|table| table.name).collect();
+            output.print(&json!({ "tables": names }), || {
+                println!("Tables are ready: {}", names.join(", "))
+            });
+        }
+        Command::Seed => {
+            let seeded = admin::seed(dynamodb).await?;
+            output.print(&seeded, || {
+                println!(
+                    "Added {} users, {} rooms and {} messages",
+                    seeded.users, seeded.rooms, seeded.messages
+                )
+            });
+        }
+
+        Command::Users => {
+            let users = admin::users(dynamodb).await?;
+            output.print(&users, || {
+                for user in &users {
+                    println!("{:<40} {}", user.id, user.name);
+                }
+            });
+        }
+        Command::RenameUser(user_id, name) => {
+            admin::rename_user(dynamodb, &user_id, &name).await?;
+            output.print(&json!({ "id": user_id, "name": name }), || {
+                println!("Renamed user {} to {}", user_id, name)
+            });
+        }
+        Command::DeleteUser(user_id) => {
+            admin::delete_user(dynamodb, &user_id).await?;
+            output.print(&json!({ "deleted": user_id }), || {
+                println!("Deleted user {}", user_id)
+            });
+        }
+
+        Command::Rooms => {
+            let rooms = admin::rooms(dynamodb).await?;
+            output.print(&rooms, || {
+                for room in &rooms {
+                    let active = if room.active { "" } else { " (not listed)" };
+                    println!("{:<40} {}{}", room.id, room.name, active);
+                }
+            });
+        }
+        Command::RenameRoom(room_id, name) => {
+            admin::rename_room(dynamodb, &room_id, &name).await?;
+            output.print(&json!({ "id": room_id, "name": name }), || {
+                println!("Renamed room {} to {}", room_id, name)
+            });
+        }
+        Command::DeleteRoom(room_id) => {
+            let items = admin::delete_room(dynamodb, &room_id).await?;
+            output.print(&json!({ "deleted": room_id, "items": items }), || {
+                println!("Deleted room {} ({} items)", room_id, items)
+            });
+        }
+
+        Command::Purge(purge, dry_run) => {
+            let purged = admin::purge(dynamodb, &purge, dry_run).await?;
+            output.print(&purged, || {
+                let verb = if dry_run { "Would delete" } else { "Deleted" };
+                println!(
+                    "{} {} messages in {} rooms",
+                    verb,
+                    purged.messages,
+                    purged.rooms.len()
+                );
+            });
+        }
+        Command::RebuildActiveRooms => {
+            let room_ids = admin::rebuild_active_rooms(dynamodb).await?;
+            output.print(&json!({ "active_rooms": room_ids }), || {
+                println!("Listed {} rooms, most recently used first:", room_ids.len());
+                for room_id in &room_ids {
+                    println!("  {}", room_id);
+                }
+            });
+        }
+        Command::Check => {
+            let problems = admin::check(dynamodb).await?;
+            output.print(&problems, || {
+                for problem in &problems {
+                    println!("{}: {}", problem.kind, problem.detail);
+                }
+                if problems.is_empty() {
+                    println!("Everything checks out");
+                }
+            });
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::Export(room_ids, format, dir) => {
+            let room_ids = match room_ids {
+                Some(room_ids) => room_ids,
+                None => admin::rooms(dynamodb)
+                    .await?
+                    .into_iter()
+                    .map(|room| room.id)
+                    .collect(),
+            };
+            let mut files = Vec::new();
+            for room_id in room_ids {
+                let path = dir.join(format!("room-{}.{}", room_id, format.extension()));
+                let name = export(config, dynamodb, &room_id, format, &path).await?;
+                if !output.as_json {
+                    println!("Exported {} to {}", name, path.display());
+                }
+                files.push(json!({ "room_id": room_id, "name": name, "path": path }));
+            }
+            output.print(&files, || {});
+        }
+        Command::Import(source, file) => {
+            let export = tokio::fs::read(&file).await.map_err(|error| {
+                ChatError::new(
+                    Some(format!("{:?}", error)),
+                    format!("Could not read {}: {}", file.display(), error),
+                )
+            })?;
+            let channels = import::read(source, &export)?;
+            let report = import::import(dynamodb, channels, |channel, report| {
+                eprintln!(
+                    "#{}: {} messages imported so far",
+                    channel, report.messages_imported
+                )
+            })
+            .await?;
+            output.print(&report, || {
+                println!("Rooms created: {}", report.rooms_created);
+                println!("Users created: {}", report.users_created);
+                println!("Messages imported: {}", report.messages_imported);
+                println!(
+                    "Messages already imported: {}",
+                    report.messages_already_imported
+                );
+                println!("Messages rejected: {}", report.messages_rejected);
+                for problem in &report.problems {
+                    println!("  - {}", problem);
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reads the filters for `purge`. At least one is needed, so that a typo
+/// can't delete every message.
+fn purge_flags(flags: &[&str]) -> Result<(admin::Purge, bool), String> {
+    let mut purge = admin::Purge::default();
+    let mut dry_run = false;
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let mut value = || match flags.next() {
+            Some(value) => Ok(value.to_string()),
+            None => Err(format!("{} needs a value", flag)),
+        };
+        let time = |value: String| match admin::message_sort_key(&value) {
+            Some(sort_key) => Ok(sort_key),
+            None => Err(format!(
+                "{} {:?} is not an RFC 3339 time or a YYYY-MM-DD date",
+                flag, value
+            )),
+        };
+        match *flag {
+            "--room" => purge.room_id = Some(value()?),
+            "--user" => purge.user_id = Some(value()?),
+            "--since" => purge.since = Some(time(value()?)?),
+            "--until" => purge.until = Some(time(value()?)?),
+            "--dry-run" => dry_run = true,
+            _ => return Err(format!("Unknown purge option {:?}", flag)),
+        }
+    }
+    if purge.room_id.is_none()
+        && purge.user_id.is_none()
+        && purge.since.is_none()
+        && purge.until.is_none()
+    {
+        return Err("purge needs at least one of --room, --user, --since or --until".into());
+    }
+    Ok((purge, dry_run))
+}
+
+/// Reads the options for `export`.
+fn export_flags(flags: &[&str]) -> Result<Command, String> {
+    let mut room_ids = Vec::new();
+    let mut all = false;
+    let mut format = Format::Json;
+    let mut dir = PathBuf::from(".");
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let mut value = || match flags.next() {
+            Some(value) => Ok(*value),
+            None => Err(format!("{} needs a value", flag)),
+        };
+        match *flag {
+            "--all" => all = true,
+            "--format" => {
+                format = Format::parse("--format", value()?)
+                    .map_err(|error| format!("{} {}", error.field, error.message))?
+            }
+            "--dir" => dir = value()?.into(),
+            _ if flag.starts_with("--") => return Err(format!("Unknown export option {:?}", flag)),
+            room_id => room_ids.push(room_id.to_owned()),
+        }
+    }
+    match (all, room_ids.is_empty()) {
+        (true, true) => Ok(Command::Export(None, format, dir)),
+        (false, false) => Ok(Command::Export(Some(room_ids), format, dir)),
+        _ => Err("export needs either --all or room IDs".into()),
+    }
+}
+
+/// Writes one room's export to `path`, by way of a `.part` file that is
+/// only renamed into place once the export is complete. Returns the room's
+/// name.
+async fn export(
+    config: &Config,
+    dynamodb: &Db,
+    room_id: &str,
+    format: Format,
+    path: &Path,
+) -> Result<String, ChatError> {
+    let file_error = |error: std::io::Error| {
+        ChatError::new(
+            Some(format!("{:?}", error)),
+            format!("Could not write {}: {}", path.display(), error),
+        )
+    };
+    let mut export = Export::open(dynamodb.clone(), room_id, &config.public_base_url, format)
+        .await
+        .map_err(|error| {
+            ChatError::new(error.debug, format!("Room {}: {}", room_id, error.display))
+        })?;
+    let part = path.with_extension(format!("{}.part", format.extension()));
+    let mut file =
+        tokio::io::BufWriter::new(tokio::fs::File::create(&part).await.map_err(file_error)?);
+    while let Some(chunk) = export.next().await? {
+        file.write_all(chunk.as_bytes()).await.map_err(file_error)?;
+    }
+    file.flush().await.map_err(file_error)?;
+    tokio::fs::rename(&part, path).await.map_err(file_error)?;
+    Ok(export.room_name().to_owned())
+}
+
+/// Prints results either as JSON, for scripts, or as text, for people.
+struct Output {
+    as_json: bool,
+}
+
+impl Output {
+    fn print<T: Serialize>(&self, value: &T, text: impl FnOnce()) {
+        match self.as_json {
+            true => println!("{}", serde_json::to_string_pretty(value).unwrap()),
+            false => text(),
+        }
+    }
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("{}\n\n{}", error, USAGE);
+    std::process::exit(2);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn purge_reads_its_filters() {
+        let (filters, dry_run) = purge_flags(&[
+            "--room",
+            "7",
+            "--user",
+            "9",
+            "--since",
+            "2025-10-01",
+            "--until",
+            "2025-10-18T12:00:00+02:00",
+            "--dry-run",
+        ])
+        .unwrap();
+        assert_eq!(filters.room_id.as_deref(), Some("7"));
+        assert_eq!(filters.user_id.as_deref(), Some("9"));
+        assert_eq!(filters.since.as_deref(), Some("message.2025-10-01"));
+        assert_eq!(
+            filters.until.as_deref(),
+            Some("message.2025-10-18T10:00:00+00:00")
+        );
+        assert!(dry_run);
+
+        let (filters, dry_run) = purge_flags(&["--until", "2025-10-18"]).unwrap();
+        assert!(filters.room_id.is_none() && filters.since.is_none());
+        assert!(!dry_run);
+    }
+
+    #[test]
+    fn purge_until_a_date_leaves_that_day_alone() {
+        let (filters, _) =
+            purge_flags(&["--since", "2025-10-17", "--until", "2025-10-18"]).unwrap();
+        let (since, until) = (filters.since.unwrap(), filters.until.unwrap());
+        let purged = |sort: &str| since.as_str() <= sort && sort < until.as_str();
+
+        assert!(!purged("message.2025-10-16T23:59:59.999+00:00"));
+        assert!(purged("message.2025-10-17T00:00:00+00:00"));
+        assert!(purged("message.2025-10-17T23:59:59.999999999+00:00"));
+        assert!(!purged("message.2025-10-18T00:00:00+00:00"));
+        assert!(!purged("message.2025-10-18T09:30:00+00:00"));
+    }
+
+    #[test]
+    fn purge_needs_a_filter() {
+        let error = "purge needs at least one of --room, --user, --since or --until";
+        assert_eq!(purge_flags(&[]).err().unwrap(), error);
+        assert_eq!(purge_flags(&["--dry-run"]).err().unwrap(), error);
+
+        assert_eq!(
+            purge_flags(&["--room"]).err().unwrap(),
+            "--room needs a value"
+        );
+        assert_eq!(
+            purge_flags(&["--since", "last week"]).err().unwrap(),
+            "--since \"last week\" is not an RFC 3339 time or a YYYY-MM-DD date"
+        );
+        assert_eq!(
+            purge_flags(&["--room", "7", "--everything"]).err().unwrap(),
+            "Unknown purge option \"--everything\""
+        );
+    }
+
+    #[test]
+    fn export_takes_all_rooms_or_some() {
+        assert!(matches!(
+            export_flags(&["--all"]),
+            Ok(Command::Export(None, Format::Json, dir)) if dir == Path::new(".")
+        ));
+        assert!(matches!(
+            export_flags(&["--format", "csv", "3", "--dir", "out", "5"]),
+            Ok(Command::Export(Some(room_ids), Format::Csv, dir))
+                if room_ids == ["3", "5"] && dir == Path::new("out")
+        ));
+
+        let error = "export needs either --all or room IDs";
+        assert_eq!(export_flags(&["--all", "3"]).err().unwrap(), error);
+        assert_eq!(export_flags(&["3", "--all"]).err().unwrap(), error);
+        assert_eq!(export_flags(&["--format", "csv"]).err().unwrap(), error);
+        assert_eq!(
+            export_flags(&["--all", "--format", "pdf"]).err().unwrap(),
+            "--format \"pdf\" isn't one of json, ndjson, csv, html or txt"
+        );
+        assert_eq!(
+            export_flags(&["--all", "--dir"]).err().unwrap(),
+            "--dir needs a value"
+        );
+    }
+
+    #[test]
+    fn unknown_commands_are_errors() {
+        assert!(matches!(Command::parse(&["check"]), Ok(Command::Check)));
+        assert!(matches!(
+            Command::parse(&["purge", "--room", "7"]),
+            Ok(Command::Purge(
+                admin::Purge {
+                    room_id: Some(_),
+                    ..
+                },
+                false
+            ))
+        ));
+        assert_eq!(Command::parse(&[]).err().unwrap(), "No command given");
+        assert_eq!(
+            Command::parse(&["rooms", "remove", "7"]).err().unwrap(),
+            "Unknown command \"rooms remove 7\""
+        );
+        assert!(Command::parse(&["purge"]).is_err());
+    }
+}
@@ -0,0 +1,750 @@
+use crate::{
+    base_url::TrustedProxy,
+    cors::CorsConfig,
+    db::Tables,
+    idempotency::IdempotencyConfig,
+    limits::Limits,
+    rate_limit::RateLimitConfig,
+    schema::SchemaConfig,
+    telemetry::{LogConfig, OtelConfig},
+    versioning::{self, Deprecation},
+};
+use http::Uri;
+use serde::{Deserialize, Serialize, Serializer};
+use std::{collections::BTreeMap, fmt, net::SocketAddr, path::PathBuf};
+
+/// Everything that can be configured about the API, in one place.
+///
+/// Configuration is read from a TOML file (see `config.example.toml`) and then
+/// environment variables are applied on top of it, so that a container can be
+/// configured without a file at all. Every setting has a default that works
+/// with `docker compose up`.
+///
+/// The file is the one passed with `--config <path>`, or in the `CONFIG_FILE`
+/// environment variable. If neither is set, `config.toml` in the working
+/// directory is used if it exists.
+///
+/// The config is validated once on startup, and the server refuses to start
+/// with a list of everything that is wrong rather than failing on the first
+/// request that happens to need a broken setting.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address and port to listen on. Env: `LISTEN_ADDRESS`, or just `PORT`.
+    pub listen_address: SocketAddr,
+    /// Where clients reach this API, used to build the URIs in responses.
+    /// Env: `PUBLIC_BASE_URL`, or `HOSTNAME` (which gets `http://` added).
+    pub public_base_url: String,
+    /// Proxies whose `Forwarded` and `X-Forwarded-*` headers are believed,
+    /// see `BaseUrl` and `base_url::client_ip`. Env: `TRUSTED_PROXIES`, comma
+    /// separated.
+    pub trusted_proxies: Vec<TrustedProxy>,
+    /// Serve Swagger UI at `/docs`. Env: `SWAGGER_UI`.
+    pub swagger_ui: bool,
+    /// Bearer token for the admin endpoints, which are off without one.
+    /// Env: `ADMIN_TOKEN`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<Secret>,
+    /// Env: `ACCESS_CONTROL_ALLOW_ORIGIN` (comma separated) and
+    /// `CORS_ALLOW_CREDENTIALS`.
+    pub cors: CorsConfig,
+    pub tables: Tables,
+    /// Env: `BILLING_MODE`, `READ_CAPACITY_UNITS`, `WRITE_CAPACITY_UNITS`,
+    /// `POINT_IN_TIME_RECOVERY` and `TABLE_TTL`.
+    pub schema: SchemaConfig,
+    pub dynamodb: DynamoDbConfig,
+    pub limits: Limits,
+    pub rate_limits: RateLimitConfig,
+    /// Env: `IDEMPOTENCY_TTL_SECONDS`.
+    pub idempotency: IdempotencyConfig,
+    /// Env: `LOG_FORMAT` and `RUST_LOG`.
+    pub log: LogConfig,
+    /// Env: `OTEL_EXPORTER_OTLP_ENDPOINT`, `OTEL_SERVICE_NAME` and
+    /// `OTEL_TRACES_SAMPLER_ARG`.
+    pub otel: OtelConfig,
+    /// Route trees that are on their way out, see `versioning::Deprecation`.
+    pub deprecations: BTreeMap<String, Deprecation>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_address: SocketAddr::from(([0, 0, 0, 0], 80)),
+            public_base_url: "http://api".into(),
+            trusted_proxies: Vec::new(),
+            cors: CorsConfig::default(),
+            swagger_ui: false,
+            admin_token: None,
+            tables: Tables::default(),
+            schema: SchemaConfig::default(),
+            dynamodb: DynamoDbConfig::default(),
+            limits: Limits::default(),
+            rate_limits: RateLimitConfig::default(),
+            idempotency: IdempotencyConfig::default(),
+            log: LogConfig::default(),
+            otel: OtelConfig::default(),
+            deprecations: BTreeMap::new(),
+        }
+    }
+}
+
+/// How to reach DynamoDB.
+///
+/// Leave `hostname` unset to use the real DynamoDB in `region`. Set it to
+/// talk to DynamoDB local instead. Credentials are normally picked up by the
+/// AWS SDK from the environment, but can be set here for DynamoDB local,
+/// which doesn't check them (the SDK just insists on having some).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DynamoDbConfig {
+    /// Env: `AWS_REGION`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// Env: `DB_HOSTNAME`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Env: `DB_PORT`.
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_key_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_access_key: Option<Secret>,
+}
+
+impl Default for DynamoDbConfig {
+    fn default() -> Self {
+        Self {
+            region: None,
+            hostname: None,
+            port: 8000,
+            access_key_id: None,
+            secret_access_key: None,
+        }
+    }
+}
+
+/// A setting that must never end up in a log or a terminal. Printing one
+/// (with `{:?}` or `--print-config`) shows `<redacted>` instead.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+/// What the server was asked to do on the command line.
+pub struct Args {
+    pub config_file: Option<PathBuf>,
+    pub print_config: bool,
+    pub print_openapi: bool,
+}
+
+impl Args {
+    pub fn parse() -> Result<Self, String> {
+        let mut args = std::env::args().skip(1);
+        let mut parsed = Self {
+            config_file: std::env::var_os("CONFIG_FILE").map(PathBuf::from),
+            print_config: false,
+            print_openapi: false,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    let path = args.next().ok_or("--config needs a path")?;
+                    parsed.config_file = Some(path.into());
+                }
+                "--print-config" => parsed.print_config = true,
+                "--print-openapi" => parsed.print_openapi = true,
+                _ => return Err(format!("Unknown argument {:?}", arg)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl Config {
+    /// Loads, overrides and validates the config. On failure, returns every
+    /// problem that was found, each one naming the setting it's about.
+    pub fn load(args: &Args) -> Result<Self, Vec<String>> {
+        let mut config = match &args.config_file {
+            Some(path) => Self::from_file(path)?,
+            None if std::path::Path::new("config.toml").exists() => {
+                Self::from_file(&PathBuf::from("config.toml"))?
+            }
+            None => Self::default(),
+        };
+
+        let mut errors = config.apply_env(|name| std::env::var(name).ok());
+        errors.extend(config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self, Vec<String>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| vec![format!("{}: {}", path.display(), error)])?;
+        toml::from_str(&contents).map_err(|error| vec![format!("{}: {}", path.display(), error)])
+    }
+
+    /// The config as TOML, with secrets redacted.
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap()
+    }
+
+    /// Overrides settings with the environment variables that `var` looks
+    /// up, see `Config`.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        env(
+            &var,
+            "LISTEN_ADDRESS",
+            &mut self.listen_address,
+            &mut errors,
+        );
+        let mut port = self.listen_address.port();
+        env(&var, "PORT", &mut port, &mut errors);
+        self.listen_address.set_port(port);
+
+        if let Some(hostname) = var("HOSTNAME") {
+            self.public_base_url = format!("http://{}", hostname);
+        }
+        env(
+            &var,
+            "PUBLIC_BASE_URL",
+            &mut self.public_base_url,
+            &mut errors,
+        );
+        if let Some(proxies) = var("TRUSTED_PROXIES") {
+            self.trusted_proxies.clear();
+            for proxy in proxies.split(',').filter(|proxy| !proxy.trim().is_empty()) {
+                match proxy.to_owned().try_into() {
+                    Ok(proxy) => self.trusted_proxies.push(proxy),
+                    Err(error) => errors.push(format!("TRUSTED_PROXIES: {}", error)),
+                }
+            }
+        }
+
+        if let Some(origins) = var("ACCESS_CONTROL_ALLOW_ORIGIN") {
+            self.cors.allowed_origins = origins
+                .split(',')
+                .map(|origin| origin.trim().to_owned())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+        env(
+            &var,
+            "CORS_ALLOW_CREDENTIALS",
+            &mut self.cors.allow_credentials,
+            &mut errors,
+        );
+
+        env(&var, "SWAGGER_UI", &mut self.swagger_ui, &mut errors);
+        if let Some(token) = var("ADMIN_TOKEN") {
+            self.admin_token = Some(Secret(token)).filter(|token| !token.0.is_empty());
+        }
+
+        env(&var, "TABLE_PREFIX", &mut self.tables.prefix, &mut errors);
+        env(
+            &var,
+            "MESSAGES_TABLE",
+            &mut self.tables.messages,
+            &mut errors,
+        );
+        env(&var, "USERS_TABLE", &mut self.tables.users, &mut errors);
+        env(
+            &var,
+            "USER_NAMES_TABLE",
+            &mut self.tables.user_names,
+            &mut errors,
+        );
+        env(
+            &var,
+            "RATE_LIMITS_TABLE",
+            &mut self.tables.rate_limits,
+            &mut errors,
+        );
+        env(
+            &var,
+            "IDEMPOTENCY_KEYS_TABLE",
+            &mut self.tables.idempotency_keys,
+            &mut errors,
+        );
+        env(
+            &var,
+            "MIGRATIONS_TABLE",
+            &mut self.tables.migrations,
+            &mut errors,
+        );
+
+        match var("BILLING_MODE").as_deref() {
+            Some("provisioned") => {
+                self.schema.billing_mode = crate::schema::BillingMode::Provisioned
+            }
+            Some("pay_per_request") => {
+                self.schema.billing_mode = crate::schema::BillingMode::PayPerRequest
+            }
+            Some(other) => errors.push(format!(
+                "BILLING_MODE: {:?} is not \"provisioned\" or \"pay_per_request\"",
+                other
+            )),
+            None => {}
+        }
+        env(
+            &var,
+            "READ_CAPACITY_UNITS",
+            &mut self.schema.read_capacity_units,
+            &mut errors,
+        );
+        env(
+            &var,
+            "WRITE_CAPACITY_UNITS",
+            &mut self.schema.write_capacity_units,
+            &mut errors,
+        );
+        if var("POINT_IN_TIME_RECOVERY").is_some() {
+            let mut enabled = false;
+            env(&var, "POINT_IN_TIME_RECOVERY", &mut enabled, &mut errors);
+            self.schema.point_in_time_recovery = Some(enabled);
+        }
+        env(&var, "TABLE_TTL", &mut self.schema.ttl, &mut errors);
+
+        if let Some(region) = var("AWS_REGION") {
+            self.dynamodb.region = Some(region);
+        }
+        if let Some(hostname) = var("DB_HOSTNAME") {
+            self.dynamodb.hostname = Some(hostname);
+        }
+        env(&var, "DB_PORT", &mut self.dynamodb.port, &mut errors);
+
+        let limits = &mut self.limits;
+        env(
+            &var,
+            "MAX_BODY_BYTES",
+            &mut limits.max_body_bytes,
+            &mut errors,
+        );
+        env(
+            &var,
+            "MAX_IMPORT_BYTES",
+            &mut limits.max_import_bytes,
+            &mut errors,
+        );
+        env_seconds(
+            &var,
+            "REQUEST_TIMEOUT_SECONDS",
+            &mut limits.request_timeout,
+            &mut errors,
+        );
+        env_seconds(
+            &var,
+            "IMPORT_TIMEOUT_SECONDS",
+            &mut limits.import_timeout,
+            &mut errors,
+        );
+        env_seconds(
+            &var,
+            "HEADER_READ_TIMEOUT_SECONDS",
+            &mut limits.header_read_timeout,
+            &mut errors,
+        );
+        env(
+            &var,
+            "MAX_CONCURRENT_REQUESTS",
+            &mut limits.max_concurrent_requests,
+            &mut errors,
+        );
+        env(
+            &var,
+            "MAX_CONCURRENT_WRITES",
+            &mut limits.max_concurrent_writes,
+            &mut errors,
+        );
+        env_seconds(
+            &var,
+            "DRAIN_TIMEOUT_SECONDS",
+            &mut limits.drain_timeout,
+            &mut errors,
+        );
+
+        let rate_limits = &mut self.rate_limits;
+        match var("RATE_LIMIT_BACKEND").as_deref() {
+            Some("memory") => rate_limits.backend = crate::rate_limit::BackendKind::Memory,
+            Some("dynamodb") => rate_limits.backend = crate::rate_limit::BackendKind::DynamoDb,
+            Some(other) => errors.push(format!(
+                "RATE_LIMIT_BACKEND: {:?} is not \"memory\" or \"dynamodb\"",
+                other
+            )),
+            None => {}
+        }
+        for (name, limit) in [
+            ("RATE_LIMIT_AUTH", &mut rate_limits.auth),
+            ("RATE_LIMIT_WRITES", &mut rate_limits.writes),
+            ("RATE_LIMIT_READS", &mut rate_limits.reads),
+        ] {
+            if let Some(value) = var(name) {
+                match value.try_into() {
+                    Ok(value) => *limit = value,
+                    Err(error) => errors.push(format!("{}: {}", name, error)),
+                }
+            }
+        }
+
+        env_seconds(
+            &var,
+            "IDEMPOTENCY_TTL_SECONDS",
+            &mut self.idempotency.ttl,
+            &mut errors,
+        );
+
+        match var("LOG_FORMAT").as_deref() {
+            Some("json") => self.log.format = crate::telemetry::LogFormat::Json,
+            Some("text") => self.log.format = crate::telemetry::LogFormat::Text,
+            Some(other) => errors.push(format!(
+                "LOG_FORMAT: {:?} is not \"json\" or \"text\"",
+                other
+            )),
+            None => {}
+        }
+        if let Some(filter) = var("RUST_LOG") {
+            self.log.filter = filter;
+        }
+
+        if let Some(endpoint) = var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.otel.endpoint = Some(endpoint).filter(|endpoint| !endpoint.is_empty());
+        }
+        env(
+            &var,
+            "OTEL_SERVICE_NAME",
+            &mut self.otel.service_name,
+            &mut errors,
+        );
+        env(
+            &var,
+            "OTEL_TRACES_SAMPLER_ARG",
+            &mut self.otel.sample_ratio,
+            &mut errors,
+        );
+
+        errors
+    }
+
+    fn validate(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        self.public_base_url = self.public_base_url.trim_end_matches('/').to_owned();
+        match self.public_base_url.parse::<Uri>() {
+            Ok(uri)
+                if matches!(uri.scheme_str(), Some("http") | Some("https"))
+                    && uri.authority().is_some()
+                    && uri.path() == "/" => {}
+            _ => errors.push(format!(
+                "public_base_url: {:?} should look like \"https://chat.example.com\"",
+                self.public_base_url
+            )),
+        }
+
+        errors.extend(self.cors.validate());
+        errors.extend(versioning::validate(&self.deprecations));
+        errors.extend(self.log.validate());
+        errors.extend(self.otel.validate());
+        errors.extend(self.schema.validate());
+
+        // Checked with the prefix in front, since that's the name DynamoDB
+        // gets.
+        let tables = self.tables.prefixed();
+        for (setting, name) in [
+            ("tables.messages", &tables.messages),
+            ("tables.users", &tables.users),
+            ("tables.user_names", &tables.user_names),
+            ("tables.rate_limits", &tables.rate_limits),
+            ("tables.idempotency_keys", &tables.idempotency_keys),
+            ("tables.migrations", &tables.migrations),
+        ] {
+            let valid = (3..=255).contains(&name.len())
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+            if !valid {
+                errors.push(format!(
+                    "{}: {:?} is not a valid DynamoDB table name (3-255 of a-z, A-Z, 0-9, _, - and .)",
+                    setting, name
+                ));
+            }
+        }
+
+        // Anybody who can guess the token can write any history they like.
+        if let Some(token) = &self.admin_token {
+            if token.expose().len() < 16 {
+                errors.push("admin_token: should be at least 16 characters".into());
+            }
+        }
+
+        if self.dynamodb.port == 0 {
+            errors.push("dynamodb.port: can't be 0".into());
+        }
+        if self.dynamodb.access_key_id.is_some() != self.dynamodb.secret_access_key.is_some() {
+            errors.push(
+                "dynamodb: access_key_id and secret_access_key have to be set together".into(),
+            );
+        }
+
+        let limits = &self.limits;
+        for (setting, value) in [
+            ("limits.max_body_bytes", limits.max_body_bytes as u64),
+            ("limits.max_import_bytes", limits.max_import_bytes as u64),
+            (
+                "limits.request_timeout_seconds",
+                limits.request_timeout.as_secs(),
+            ),
+            (
+                "limits.import_timeout_seconds",
+                limits.import_timeout.as_secs(),
+            ),
+            (
+                "limits.header_read_timeout_seconds",
+                limits.header_read_timeout.as_secs(),
+            ),
+            (
+                "limits.max_concurrent_requests",
+                limits.max_concurrent_requests as u64,
+            ),
+            (
+                "limits.max_concurrent_writes",
+                limits.max_concurrent_writes as u64,
+            ),
+            ("idempotency.ttl_seconds", self.idempotency.ttl.as_secs()),
+        ] {
+            if value == 0 {
+                errors.push(format!("{}: can't be 0", setting));
+            }
+        }
+
+        errors
+    }
+}
+
+/// Overrides a setting with an environment variable, if it is set.
+fn env<T: std::str::FromStr>(
+    var: impl Fn(&str) -> Option<String>,
+    name: &str,
+    setting: &mut T,
+    errors: &mut Vec<String>,
+) {
+    if let Some(value) = var(name) {
+        match value.parse() {
+            Ok(value) => *setting = value,
+            Err(_) => errors.push(format!("{}: {:?} is not a valid value", name, value)),
+        }
+    }
+}
+
+fn env_seconds(
+    var: impl Fn(&str) -> Option<String>,
+    name: &str,
+    setting: &mut std::time::Duration,
+    errors: &mut Vec<String>,
+) {
+    let mut seconds = setting.as_secs();
+    env(var, name, &mut seconds, errors);
+    *setting = std::time::Duration::from_secs(seconds);
+}
+
+/// (De)serializes a `Duration` as a whole number of seconds, for settings
+/// named `..._seconds`.
+pub mod seconds {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(value.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_secs(u64::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Applies `vars` as the environment, and then validates.
+    fn load(toml: &str, vars: &[(&str, &str)]) -> Result<Config, Vec<String>> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        let mut config: Config = toml::from_str(toml).map_err(|error| vec![error.to_string()])?;
+        let mut errors = config.apply_env(|name| vars.get(name).map(|value| value.to_string()));
+        errors.extend(config.validate());
+        match errors.is_empty() {
+            true => Ok(config),
+            false => Err(errors),
+        }
+    }
+
+    #[test]
+    fn the_example_is_the_defaults() {
+        let example = load(include_str!("../config.example.toml"), &[]).unwrap();
+        assert_eq!(example.to_toml(), Config::default().to_toml());
+    }
+
+    #[test]
+    fn settings_left_out_of_the_file_are_defaults() {
+        let config = load("swagger_ui = true\n[limits]\nmax_body_bytes = 1024\n", &[]).unwrap();
+        assert!(config.swagger_ui);
+        assert_eq!(config.limits.max_body_bytes, 1024);
+        assert_eq!(config.listen_address, Config::default().listen_address);
+        assert_eq!(
+            config.limits.request_timeout,
+            Limits::default().request_timeout
+        );
+        assert_eq!(config.tables.messages, "messages");
+
+        let unknown = load("swager_ui = true\n", &[]).err().unwrap();
+        assert!(
+            unknown[0].contains("unknown field `swager_ui`"),
+            "{:?}",
+            unknown
+        );
+    }
+
+    #[test]
+    fn the_environment_overrides_the_file() {
+        let file =
+            "listen_address = \"127.0.0.1:8080\"\npublic_base_url = \"https://file.example.com\"\n\
+                    [rate_limits]\nwrites = \"5/60\"\n";
+        let config = load(
+            file,
+            &[
+                ("LISTEN_ADDRESS", "10.0.0.1:9000"),
+                ("PUBLIC_BASE_URL", "https://chat.example.com/"),
+                ("RATE_LIMIT_WRITES", "100/10"),
+                ("REQUEST_TIMEOUT_SECONDS", "30"),
+                ("BILLING_MODE", "pay_per_request"),
+                ("POINT_IN_TIME_RECOVERY", "true"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.listen_address, "10.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.public_base_url, "https://chat.example.com");
+        assert_eq!(String::from(config.rate_limits.writes), "100/10");
+        assert_eq!(
+            config.limits.request_timeout,
+            std::time::Duration::from_secs(30)
+        );
+        assert_eq!(
+            config.schema.billing_mode,
+            crate::schema::BillingMode::PayPerRequest
+        );
+        assert_eq!(config.schema.point_in_time_recovery, Some(true));
+
+        // Whatever isn't in the environment is left as the file has it.
+        let config = load(file, &[]).unwrap();
+        assert_eq!(config.listen_address, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(String::from(config.rate_limits.writes), "5/60");
+        assert_eq!(config.schema.point_in_time_recovery, None);
+    }
+
+    #[test]
+    fn port_only_changes_the_port() {
+        let config = load("listen_address = \"127.0.0.1:8080\"\n", &[("PORT", "5050")]).unwrap();
+        assert_eq!(config.listen_address, "127.0.0.1:5050".parse().unwrap());
+
+        let config = load(
+            "",
+            &[("LISTEN_ADDRESS", "127.0.0.1:8080"), ("PORT", "5050")],
+        )
+        .unwrap();
+        assert_eq!(config.listen_address, "127.0.0.1:5050".parse().unwrap());
+    }
+
+    #[test]
+    fn every_invalid_setting_is_reported() {
+        let errors = load(
+            "",
+            &[
+                ("LISTEN_ADDRESS", "localhost"),
+                ("PORT", "http"),
+                ("RATE_LIMIT_WRITES", "0/60"),
+                ("RATE_LIMIT_READS", "lots"),
+                ("RATE_LIMIT_BACKEND", "redis"),
+                ("MAX_BODY_BYTES", "0"),
+            ],
+        )
+        .err()
+        .unwrap();
+        let settings: Vec<_> = errors
+            .iter()
+            .map(|error| error.split(':').next().unwrap())
+            .collect();
+        assert_eq!(
+            settings,
+            [
+                "LISTEN_ADDRESS",
+                "PORT",
+                "RATE_LIMIT_BACKEND",
+                "RATE_LIMIT_WRITES",
+                "RATE_LIMIT_READS",
+                "limits.max_body_bytes",
+            ],
+            "{:?}",
+            errors
+        );
+    }
+
+    #[test]
+    fn admin_tokens_have_to_be_long() {
+        let errors = load("", &[("ADMIN_TOKEN", "hunter2")]).err().unwrap();
+        assert_eq!(errors, ["admin_token: should be at least 16 characters"]);
+
+        let config = load("", &[("ADMIN_TOKEN", "a-long-enough-admin-token")]).unwrap();
+        assert_eq!(
+            config.admin_token.unwrap().expose(),
+            "a-long-enough-admin-token"
+        );
+        // Set but empty turns the admin endpoints off.
+        let config = load(
+            "admin_token = \"a-long-enough-admin-token\"\n",
+            &[("ADMIN_TOKEN", "")],
+        )
+        .unwrap();
+        assert!(config.admin_token.is_none());
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let config = load(
+            "admin_token = \"a-long-enough-admin-token\"\n\
+             [dynamodb]\naccess_key_id = \"local\"\nsecret_access_key = \"a-secret-key\"\n",
+            &[],
+        )
+        .unwrap();
+        for printed in [format!("{:?}", config), config.to_toml()] {
+            assert!(
+                !printed.contains("a-long-enough-admin-token"),
+                "{}",
+                printed
+            );
+            assert!(!printed.contains("a-secret-key"), "{}", printed);
+            assert!(printed.contains("<redacted>"), "{}", printed);
+        }
+    }
+}
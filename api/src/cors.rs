@@ -0,0 +1,269 @@
+use http::{
+    header::{HeaderName, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+    HeaderValue, Method, Uri,
+};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{CorsLayer, Origin};
+
+/// Which browser origins can call the API.
+///
+/// - `allowed_origins`: origins like `https://chat.example.com`, or patterns
+///   like `https://*.preview.example.com` that match any subdomain (at any
+///   depth, but not the bare domain). Scheme and port have to match exactly.
+/// - `allow_credentials`: lets browsers send cookies and `Authorization`
+///   headers along with cross-origin requests. Off by default.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allow_credentials: bool,
+}
+
+/// An entry of `CorsConfig::allowed_origins`.
+#[derive(Clone, Debug)]
+enum AllowedOrigin {
+    Exact(String),
+    /// `https://*.example.com:8443` is stored as `https://` and
+    /// `.example.com:8443`.
+    Subdomain {
+        scheme: String,
+        suffix: String,
+    },
+}
+
+impl AllowedOrigin {
+    fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!(
+                "{:?} should look like \"https://chat.example.com\" or \"https://*.example.com\"",
+                value
+            )
+        };
+        let value = value.to_ascii_lowercase();
+
+        if let Some((scheme, rest)) = value.split_once("://*.") {
+            let scheme = format!("{}://", scheme);
+            let example = format!("{}x.{}", scheme, rest);
+            if !is_origin(&example) {
+                return Err(invalid());
+            }
+            return Ok(AllowedOrigin::Subdomain {
+                scheme,
+                suffix: format!(".{}", rest),
+            });
+        }
+
+        if is_origin(&value) {
+            Ok(AllowedOrigin::Exact(value))
+        } else {
+            Err(invalid())
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            AllowedOrigin::Subdomain { scheme, suffix } => {
+                let origin = origin.to_ascii_lowercase();
+                let subdomain = origin
+                    .strip_prefix(scheme.as_str())
+                    .and_then(|host| host.strip_suffix(suffix.as_str()));
+                // Only letters, digits, '-' and '.' are allowed in front of the
+                // suffix, so that nothing like `https://evil.com?.example.com`
+                // or `https://x@example.com` sneaks through.
+                subdomain.is_some_and(|subdomain| {
+                    !subdomain.is_empty()
+                        && !subdomain.starts_with('.')
+                        && !subdomain.ends_with('.')
+                        && subdomain
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+                })
+            }
+        }
+    }
+}
+
+/// Whether `value` is a bare origin, a scheme and an authority with nothing
+/// after it.
+fn is_origin(value: &str) -> bool {
+    HeaderValue::from_str(value).is_ok()
+        && !value.ends_with('/')
+        && value.parse::<Uri>().is_ok_and(|uri| {
+            uri.scheme().is_some()
+                && uri
+                    .authority()
+                    .is_some_and(|authority| !authority.as_str().contains('@'))
+                && uri.path() == "/"
+        })
+}
+
+impl CorsConfig {
+    /// Returns a message for each allowed origin that can't be used.
+    pub fn validate(&self) -> Vec<String> {
+        self.allowed_origins
+            .iter()
+            .enumerate()
+            .filter_map(|(i, origin)| {
+                AllowedOrigin::parse(origin)
+                    .err()
+                    .map(|error| format!("cors.allowed_origins[{}]: {}", i, error))
+            })
+            .collect()
+    }
+
+    /// Builds the CORS layer. The config has to have been validated.
+    ///
+    /// The request's origin is echoed back when it's allowed, rather than
+    /// sending `*`, which browsers refuse to combine with credentials.
+    pub fn layer(&self) -> CorsLayer {
+        let allowed: Vec<AllowedOrigin> = self
+            .allowed_origins
+            .iter()
+            .filter_map(|origin| AllowedOrigin::parse(origin).ok())
+            .collect();
+
+        CorsLayer::new()
+            .allow_methods(vec![
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
+            .allow_headers(vec![
+                AUTHORIZATION,
+                CONTENT_TYPE,
+                IF_NONE_MATCH,
+                HeaderName::from_static(crate::idempotency::IDEMPOTENCY_KEY),
+            ])
+            .expose_headers(vec![
+                ETAG,
+                HeaderName::from_static("idempotency-replayed"),
+                HeaderName::from_static("x-request-id"),
+            ])
+            .allow_credentials(self.allow_credentials)
+            .allow_origin(Origin::predicate(move |origin, _| {
+                origin
+                    .to_str()
+                    .is_ok_and(|origin| allowed.iter().any(|allowed| allowed.matches(origin)))
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+        },
+        HeaderMap, Request, Response,
+    };
+    use hyper::Body;
+    use std::convert::Infallible;
+    use tower::{ServiceBuilder, ServiceExt};
+
+    fn config(allowed_origins: &[&str], allow_credentials: bool) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: allowed_origins
+                .iter()
+                .map(|&origin| origin.into())
+                .collect(),
+            allow_credentials,
+        }
+    }
+
+    /// The headers of the response to a preflight request from `origin`.
+    async fn preflight(config: &CorsConfig, origin: &str, method: Method) -> HeaderMap {
+        let service = ServiceBuilder::new()
+            .layer(config.layer())
+            .service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::empty())) });
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/rooms")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method.as_str())
+            .body(Body::empty())
+            .unwrap();
+        service.oneshot(request).await.unwrap().headers().clone()
+    }
+
+    async fn allows(config: &CorsConfig, origin: &str) -> bool {
+        let headers = preflight(config, origin, Method::GET).await;
+        match headers.get(ACCESS_CONTROL_ALLOW_ORIGIN) {
+            Some(allowed) => {
+                assert_eq!(allowed, origin);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn exact_origins() {
+        let config = config(&["https://chat.example.com"], false);
+        assert!(allows(&config, "https://chat.example.com").await);
+        assert!(allows(&config, "https://Chat.Example.com").await);
+        assert!(!allows(&config, "http://chat.example.com").await);
+        assert!(!allows(&config, "https://chat.example.com:8443").await);
+        assert!(!allows(&config, "https://evil.chat.example.com").await);
+        assert!(!allows(&config, "https://example.com").await);
+    }
+
+    #[tokio::test]
+    async fn wildcard_subdomain_origins() {
+        let config = config(&["https://*.preview.example.com"], false);
+        assert!(allows(&config, "https://pr-1.preview.example.com").await);
+        assert!(allows(&config, "https://a.b.preview.example.com").await);
+        assert!(!allows(&config, "https://preview.example.com").await);
+        assert!(!allows(&config, "http://pr-1.preview.example.com").await);
+        assert!(!allows(&config, "https://pr-1.preview.example.com.evil.com").await);
+        assert!(!allows(&config, "https://evil.com?.preview.example.com").await);
+        assert!(!allows(&config, "https://x@y.preview.example.com").await);
+        assert!(!allows(&config, "https://.preview.example.com").await);
+    }
+
+    #[tokio::test]
+    async fn credentials_only_when_allowed() {
+        let origin = "https://chat.example.com";
+        let with = preflight(&config(&[origin], true), origin, Method::GET).await;
+        assert_eq!(with[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        let without = preflight(&config(&[origin], false), origin, Method::GET).await;
+        assert!(without.get(ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+    }
+
+    #[tokio::test]
+    async fn every_method_the_api_uses() {
+        let origin = "https://chat.example.com";
+        for method in [Method::PATCH, Method::DELETE] {
+            let headers = preflight(&config(&[origin], false), origin, method.clone()).await;
+            let allowed = headers[ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap();
+            assert!(
+                allowed
+                    .split(',')
+                    .any(|allowed| allowed.trim() == method.as_str()),
+                "{} isn't in {:?}",
+                method,
+                allowed
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_origins() {
+        let config = config(
+            &[
+                "https://chat.example.com",
+                "chat.example.com",
+                "https://chat.example.com/",
+                "https://*.example.com/rooms",
+            ],
+            false,
+        );
+        let errors = config.validate();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].starts_with("cors.allowed_origins[1]:"));
+    }
+}
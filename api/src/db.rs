@@ -1,68 +1,377 @@
-use crate::errors::ChatError;
+use crate::{
+    errors::{retry_after_seconds, ChatError},
+    metrics,
+    sync::Token,
+};
 use aws_sdk_dynamodb::{
-    error::PutItemError,
-    model::{AttributeValue, KeysAndAttributes},
+    error::{PutItemError, TransactWriteItemsError, TransactWriteItemsErrorKind},
+    model::{AttributeValue, Delete, KeysAndAttributes, Put, ReturnValue, TransactWriteItem},
     output::PutItemOutput,
     types::SdkError,
 };
-use std::collections::HashMap;
+use aws_smithy_types::retry::ProvideErrorKind;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    time::{Duration, Instant},
+};
+use tracing::Instrument;
+
+/// The DynamoDB client, along with the names of the tables it should use.
+///
+/// One of these is created on startup and handed to the handlers that need it
+/// as an `Extension`, so every handler's data can still be traced back to
+/// `main`.
+#[derive(Clone)]
+pub struct Db {
+    pub client: aws_sdk_dynamodb::Client,
+    pub tables: Tables,
+}
+
+/// Table names. These can be changed in the config so that more than one
+/// environment can share an AWS account, either by putting the same `prefix`
+/// in front of each (like `staging-`), or by naming each table outright.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tables {
+    /// Put in front of every name below. Env: `TABLE_PREFIX`.
+    pub prefix: String,
+    pub messages: String,
+    pub users: String,
+    /// Claims each `name_key` for one user, see `create_user`.
+    pub user_names: String,
+    pub rate_limits: String,
+    pub idempotency_keys: String,
+    pub migrations: String,
+}
+
+impl Default for Tables {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            messages: "messages".into(),
+            users: "users".into(),
+            user_names: "user_names".into(),
+            rate_limits: "rate_limits".into(),
+            idempotency_keys: "idempotency_keys".into(),
+            migrations: "migrations".into(),
+        }
+    }
+}
+
+impl Tables {
+    /// The names as they are in DynamoDB, with the prefix in front.
+    pub fn prefixed(&self) -> Self {
+        let prefixed = |name: &str| format!("{}{}", self.prefix, name);
+        Self {
+            prefix: String::new(),
+            messages: prefixed(&self.messages),
+            users: prefixed(&self.users),
+            user_names: prefixed(&self.user_names),
+            rate_limits: prefixed(&self.rate_limits),
+            idempotency_keys: prefixed(&self.idempotency_keys),
+            migrations: prefixed(&self.migrations),
+        }
+    }
+}
+
+/// Makes a DynamoDB call, like `GetItem`, in a span of its own, and logs and
+/// records in the metrics how long it took. Every call to DynamoDB goes
+/// through here.
+///
+/// ```ignore
+/// let output = db::call(
+///     "GetItem",
+///     &dynamodb.tables.users,
+///     dynamodb.client.get_item().table_name(&dynamodb.tables.users).send(),
+/// )
+/// .await?;
+/// ```
+pub async fn call<T, E: ProvideErrorKind + Debug>(
+    operation: &'static str,
+    table: &str,
+    request: impl Future<Output = Result<T, SdkError<E>>>,
+) -> Result<T, SdkError<E>> {
+    let span = tracing::info_span!("dynamodb", otel.kind = "client", operation, table);
+    let start = Instant::now();
+    let result = request.instrument(span.clone()).await;
+    let latency = start.elapsed();
+    let code = result.as_ref().err().map(|error| match error {
+        SdkError::ServiceError { err, .. } => err.code().unwrap_or("Unknown"),
+        SdkError::ConstructionFailure(_) => "ConstructionFailure",
+        SdkError::TimeoutError(_) => "TimeoutError",
+        SdkError::DispatchFailure(_) => "DispatchFailure",
+        SdkError::ResponseError { .. } => "ResponseError",
+    });
+    metrics::dynamodb_call(operation, table, latency, code);
+    let latency_ms = latency.as_secs_f64() * 1000.0;
+    span.in_scope(|| match &result {
+        Ok(_) => tracing::debug!(latency_ms, "DynamoDB call"),
+        // Conditional check failures are how flood control and idempotency
+        // keys work, so failed calls aren't necessarily a problem. The ones
+        // that are end up as a `ChatError`, which is logged anyway.
+        Err(error) => tracing::debug!(latency_ms, ?error, "DynamoDB call failed"),
+    });
+    result
+}
 
 // Messages
 
 pub async fn get_message_by_id(
-    dynamodb: &aws_sdk_dynamodb::Client,
+    dynamodb: &Db,
     room_id: &str,
     message_id: &str,
 ) -> Result<HashMap<String, AttributeValue>, ChatError> {
-    let output = dynamodb
-        .get_item()
-        .table_name("messages")
-        .key("room_id", AttributeValue::N(room_id.to_owned()))
-        .key("sort", AttributeValue::S(format!("message.{}", message_id)))
-        .projection_expression("room_id,sort,sender_id,sender_name,message")
-        .send()
-        .await?;
+    let output = call(
+        "GetItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .get_item()
+            .table_name(&dynamodb.tables.messages)
+            .key("room_id", AttributeValue::N(room_id.to_owned()))
+            .key("sort", AttributeValue::S(format!("message.{}", message_id)))
+            .projection_expression("room_id,sort,sender_id,sender_name,message")
+            .send(),
+    )
+    .await?;
 
-    Ok(output.item.ok_or_else(query_error)?)
+    output.item.ok_or_else(query_error)
 }
 
+/// The latest `limit` messages in a room, newest first. Read consistently,
+/// like `get_room_version`, so that a page tagged with a version has every
+/// message up to that version on it.
 pub async fn get_messages(
-    dynamodb: &aws_sdk_dynamodb::Client,
+    dynamodb: &Db,
     room_id: &str,
     limit: u8,
 ) -> Result<Vec<HashMap<String, AttributeValue>>, ChatError> {
-    let output = dynamodb
-        .query()
-        .table_name("messages")
-        .key_condition_expression("room_id = :r AND begins_with(sort, :m)")
-        .expression_attribute_values(":r", AttributeValue::N(room_id.to_owned()))
-        .expression_attribute_values(":m", AttributeValue::S("message.".into()))
-        .scan_index_forward(false)
-        .limit(limit.into())
-        .send()
-        .await?;
+    let output = call(
+        "Query",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .query()
+            .table_name(&dynamodb.tables.messages)
+            .key_condition_expression("room_id = :r AND begins_with(sort, :m)")
+            .expression_attribute_values(":r", AttributeValue::N(room_id.to_owned()))
+            .expression_attribute_values(":m", AttributeValue::S("message.".into()))
+            .scan_index_forward(false)
+            .limit(limit.into())
+            .consistent_read(true)
+            .send(),
+    )
+    .await?;
 
-    Ok(output.items.ok_or_else(query_error)?)
+    output.items.ok_or_else(query_error)
+}
+
+/// The first `limit` messages in a room posted after `since`, oldest first.
+/// Read consistently, since a message a stale read leaves out would be behind
+/// the next sync token, and never synced.
+pub async fn get_messages_since(
+    dynamodb: &Db,
+    room_id: &str,
+    since: Token,
+    limit: usize,
+) -> Result<Vec<HashMap<String, AttributeValue>>, ChatError> {
+    let output = call(
+        "Query",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .query()
+            .table_name(&dynamodb.tables.messages)
+            .key_condition_expression("room_id = :r AND sort BETWEEN :s AND :m")
+            .expression_attribute_values(":r", AttributeValue::N(room_id.to_owned()))
+            .expression_attribute_values(":s", AttributeValue::S(since.sort_key()))
+            // Just past every `message.` key, since "/" comes after "."
+            .expression_attribute_values(":m", AttributeValue::S("message/".into()))
+            .limit(limit as i32)
+            .consistent_read(true)
+            .send(),
+    )
+    .await?;
+
+    output.items.ok_or_else(query_error)
+}
+
+/// One page of a room's messages, oldest first, starting after `start`. Also
+/// returns where the next page starts, or `None` if this was the last one.
+pub async fn get_messages_page(
+    dynamodb: &Db,
+    room_id: &str,
+    start: Option<HashMap<String, AttributeValue>>,
+    limit: i32,
+) -> Result<
+    (
+        Vec<HashMap<String, AttributeValue>>,
+        Option<HashMap<String, AttributeValue>>,
+    ),
+    ChatError,
+> {
+    let output = call(
+        "Query",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .query()
+            .table_name(&dynamodb.tables.messages)
+            .key_condition_expression("room_id = :r AND begins_with(sort, :m)")
+            .expression_attribute_values(":r", AttributeValue::N(room_id.to_owned()))
+            .expression_attribute_values(":m", AttributeValue::S("message.".into()))
+            .projection_expression("room_id,sort,sender_id,sender_name,message")
+            .set_exclusive_start_key(start)
+            .limit(limit)
+            .send(),
+    )
+    .await?;
+
+    Ok((
+        output.items.ok_or_else(query_error)?,
+        output.last_evaluated_key,
+    ))
 }
 
 pub async fn post_message(
-    dynamodb: &aws_sdk_dynamodb::Client,
+    dynamodb: &Db,
     room_id: &str,
     message: &str,
     sender_id: &str,
     sender_name: &str,
     date_time: &str,
 ) -> Result<PutItemOutput, ChatError> {
-    Ok(dynamodb
-        .put_item()
-        .table_name("messages")
-        .item("room_id", AttributeValue::N(room_id.to_owned()))
-        .item("sort", AttributeValue::S(format!("message.{}", date_time)))
-        .item("sender_id", AttributeValue::N(sender_id.to_owned()))
-        .item("sender_name", AttributeValue::S(sender_name.to_owned()))
-        .item("message", AttributeValue::S(message.to_owned()))
-        .send()
-        .await?)
+    Ok(call(
+        "PutItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .put_item()
+            .table_name(&dynamodb.tables.messages)
+            .item("room_id", AttributeValue::N(room_id.to_owned()))
+            .item("sort", AttributeValue::S(format!("message.{}", date_time)))
+            .item("sender_id", AttributeValue::N(sender_id.to_owned()))
+            .item("sender_name", AttributeValue::S(sender_name.to_owned()))
+            .item("message", AttributeValue::S(message.to_owned()))
+            .send(),
+    )
+    .await?)
+}
+
+/// Like `post_message`, but only if there's no message at `date_time` in the
+/// room yet. Returns whether the message was written.
+pub async fn post_message_if_new(
+    dynamodb: &Db,
+    room_id: &str,
+    message: &str,
+    sender_id: &str,
+    sender_name: &str,
+    date_time: &str,
+) -> Result<bool, ChatError> {
+    let result = call(
+        "PutItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .put_item()
+            .table_name(&dynamodb.tables.messages)
+            .item("room_id", AttributeValue::N(room_id.to_owned()))
+            .item("sort", AttributeValue::S(format!("message.{}", date_time)))
+            .item("sender_id", AttributeValue::N(sender_id.to_owned()))
+            .item("sender_name", AttributeValue::S(sender_name.to_owned()))
+            .item("message", AttributeValue::S(message.to_owned()))
+            .condition_expression("attribute_not_exists(sort)")
+            .send(),
+    )
+    .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(SdkError::ServiceError { err, .. }) if err.is_conditional_check_failed_exception() => {
+            Ok(false)
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Reads a room's message version, which goes up by one every time a message
+/// is posted in it. It's cheap to read, so the latest messages can be checked
+/// for changes without querying them. Rooms that have never had a message
+/// posted since the counter was added are at version 0.
+///
+/// The read is consistent. An eventually consistent one could return the
+/// version from before the last message, and a client polling with
+/// `If-None-Match` would be told nothing changed when something did.
+pub async fn get_room_version(dynamodb: &Db, room_id: &str) -> Result<u64, ChatError> {
+    let output = call(
+        "GetItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .get_item()
+            .table_name(&dynamodb.tables.messages)
+            .key("room_id", AttributeValue::N(room_id.to_owned()))
+            .key("sort", AttributeValue::S("room".into()))
+            .projection_expression("version")
+            .consistent_read(true)
+            .send(),
+    )
+    .await?;
+
+    match output.item.as_ref().and_then(|item| item.get("version")) {
+        None => Ok(0),
+        Some(version) => Ok(version.as_n()?.parse()?),
+    }
+}
+
+/// Bumps a room's message version, and marks the room as changed for
+/// `GET /sync`. Has to be called after the message is written, so that nobody
+/// sees the new version without the new message.
+pub async fn bump_room_version(dynamodb: &Db, room_id: &str) -> Result<(), ChatError> {
+    call(
+        "UpdateItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .update_item()
+            .table_name(&dynamodb.tables.messages)
+            .key("room_id", AttributeValue::N(room_id.to_owned()))
+            .key("sort", AttributeValue::S("room".into()))
+            .update_expression("ADD version :one SET changed_at = :c")
+            .condition_expression("attribute_exists(room_id)")
+            .expression_attribute_values(":one", AttributeValue::N("1".into()))
+            .expression_attribute_values(":c", AttributeValue::N(Token::now().to_string()))
+            .send(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Bumps a room's message version like `bump_room_version`, for messages
+/// that were written or deleted before the newest ones, which `GET /sync`
+/// can't list as changes. Clients that synced before then are told to load
+/// the room again.
+pub async fn rewrite_room_history(dynamodb: &Db, room_id: &str) -> Result<(), ChatError> {
+    call(
+        "UpdateItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .update_item()
+            .table_name(&dynamodb.tables.messages)
+            .key("room_id", AttributeValue::N(room_id.to_owned()))
+            .key("sort", AttributeValue::S("room".into()))
+            .update_expression("ADD version :one SET changed_at = :c, history_changed_at = :c")
+            .condition_expression("attribute_exists(room_id)")
+            .expression_attribute_values(":one", AttributeValue::N("1".into()))
+            .expression_attribute_values(":c", AttributeValue::N(Token::now().to_string()))
+            .send(),
+    )
+    .await?;
+    Ok(())
 }
 
 // Rooms
@@ -71,7 +380,7 @@ pub async fn post_message(
 /// middle of the CSV string and any preceding comma, and then appends it to
 /// the end of a new allocated string.
 /// For example:
-/// ```
+/// ```ignore
 /// assert_eq!("a", bump_csv("a", "a"));
 /// assert_eq!("a", bump_csv("", "a"))
 /// assert_eq!("b,c,d,a", bump_csv("a,b,c,d", "a"));
@@ -103,47 +412,381 @@ fn bump_csv(csv_string: &str, input: &str) -> String {
     }
 }
 
-pub async fn bump_room(
-    dynamodb: &aws_sdk_dynamodb::Client,
-    room_id: &str,
-) -> Result<PutItemOutput, ChatError> {
-    let room_ids_csv = get_active_rooms_scalar(dynamodb).await?;
-    let room_ids_csv = bump_csv(&room_ids_csv, room_id);
+/// How many times to try changing the list of active rooms, when something
+/// else keeps changing it first, before giving up.
+const ACTIVE_ROOMS_ATTEMPTS: usize = 10;
 
-    Ok(dynamodb
-        .put_item()
-        .table_name("messages")
-        .item("room_id", AttributeValue::N("1".into()))
-        .item("sort", AttributeValue::S("active_rooms".into()))
-        .item("room_ids", AttributeValue::S(room_ids_csv))
-        .send()
-        .await?)
+pub async fn bump_room(dynamodb: &Db, room_id: &str) -> Result<(), ChatError> {
+    for attempt in 0..ACTIVE_ROOMS_ATTEMPTS {
+        let previous = get_active_rooms_scalar(dynamodb).await?;
+        let room_ids_csv = bump_csv(&previous, room_id);
+        if room_ids_csv == previous
+            || replace_active_rooms(dynamodb, &previous, room_ids_csv).await?
+        {
+            return Ok(());
+        }
+        active_rooms_backoff(attempt).await;
+    }
+    Err(active_rooms_busy())
 }
 
 pub async fn create_room(
-    dynamodb: &aws_sdk_dynamodb::Client,
+    dynamodb: &Db,
     room_id: &str,
     room_name: &str,
 ) -> Result<PutItemOutput, SdkError<PutItemError>> {
-    Ok(dynamodb
-        .put_item()
-        .table_name("messages")
-        .item("room_id", AttributeValue::N(room_id.to_owned()))
-        .item("sort", AttributeValue::S("room".into()))
-        .item("name", AttributeValue::S(room_name.to_owned()))
-        .send()
-        .await?)
-}
-
-async fn get_active_rooms_scalar(dynamodb: &aws_sdk_dynamodb::Client) -> Result<String, ChatError> {
-    let output = dynamodb
-        .get_item()
-        .table_name("messages")
-        .key("room_id", AttributeValue::N("1".into()))
-        .key("sort", AttributeValue::S("active_rooms".into()))
-        .projection_expression("room_ids")
-        .send()
-        .await?;
+    call(
+        "PutItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .put_item()
+            .table_name(&dynamodb.tables.messages)
+            .item("room_id", AttributeValue::N(room_id.to_owned()))
+            .item("sort", AttributeValue::S("room".into()))
+            .item("name", AttributeValue::S(room_name.to_owned()))
+            .item("changed_at", AttributeValue::N(Token::now().to_string()))
+            .send(),
+    )
+    .await
+}
+
+pub async fn get_room(
+    dynamodb: &Db,
+    room_id: &str,
+) -> Result<HashMap<String, AttributeValue>, ChatError> {
+    let output = call(
+        "GetItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .get_item()
+            .table_name(&dynamodb.tables.messages)
+            .key("room_id", AttributeValue::N(room_id.to_owned()))
+            .key("sort", AttributeValue::S("room".into()))
+            .projection_expression("room_id,#n,slow_mode_seconds,duplicate_window_seconds")
+            .expression_attribute_names("#n", "name")
+            .send(),
+    )
+    .await?;
+
+    output
+        .item
+        .ok_or_else(|| ChatError::new(None, "Room does not exist".into()))
+}
+
+/// Flood control settings for a room. Both are in seconds, and 0 means off.
+///
+/// - `slow_mode_seconds`: the minimum time between two messages from the same
+///   user.
+/// - `duplicate_window_seconds`: how long a user has to wait before they can
+///   send the exact same message again.
+pub struct RoomSettings {
+    pub slow_mode_seconds: u32,
+    pub duplicate_window_seconds: u32,
+}
+
+/// Reads the flood control settings off of a room item. Rooms created before
+/// these settings existed don't have them, so they are treated as off.
+pub fn room_settings(room: &HashMap<String, AttributeValue>) -> Result<RoomSettings, ChatError> {
+    let seconds = |key: &str| -> Result<u32, ChatError> {
+        match room.get(key) {
+            None => Ok(0),
+            Some(value) => Ok(value.as_n()?.parse()?),
+        }
+    };
+    Ok(RoomSettings {
+        slow_mode_seconds: seconds("slow_mode_seconds")?,
+        duplicate_window_seconds: seconds("duplicate_window_seconds")?,
+    })
+}
+
+/// When a room was created, had its settings changed, or last had a message
+/// posted in it. Rooms that haven't changed since this was added don't have
+/// one.
+pub fn room_changed_at(room: &HashMap<String, AttributeValue>) -> Result<Option<Token>, ChatError> {
+    match room.get("changed_at") {
+        None => Ok(None),
+        Some(value) => Ok(Token::parse(value.as_n()?)),
+    }
+}
+
+/// When messages were last imported into or purged from a room, if ever.
+pub fn room_history_changed_at(
+    room: &HashMap<String, AttributeValue>,
+) -> Result<Option<Token>, ChatError> {
+    match room.get("history_changed_at") {
+        None => Ok(None),
+        Some(value) => Ok(Token::parse(value.as_n()?)),
+    }
+}
+
+/// Updates a room's flood control settings. Settings that are `None` are left
+/// alone. Returns the updated room item.
+pub async fn update_room_settings(
+    dynamodb: &Db,
+    room_id: &str,
+    slow_mode_seconds: Option<u32>,
+    duplicate_window_seconds: Option<u32>,
+) -> Result<HashMap<String, AttributeValue>, ChatError> {
+    let mut sets = Vec::new();
+    let mut request = dynamodb
+        .client
+        .update_item()
+        .table_name(&dynamodb.tables.messages)
+        .key("room_id", AttributeValue::N(room_id.to_owned()))
+        .key("sort", AttributeValue::S("room".into()))
+        .condition_expression("attribute_exists(room_id)")
+        .return_values(ReturnValue::AllNew);
+    if let Some(seconds) = slow_mode_seconds {
+        sets.push("slow_mode_seconds = :s");
+        request = request.expression_attribute_values(":s", AttributeValue::N(seconds.to_string()));
+    }
+    if let Some(seconds) = duplicate_window_seconds {
+        sets.push("duplicate_window_seconds = :d");
+        request = request.expression_attribute_values(":d", AttributeValue::N(seconds.to_string()));
+    }
+    if sets.is_empty() {
+        return get_room(dynamodb, room_id).await;
+    }
+    sets.push("changed_at = :c");
+    request =
+        request.expression_attribute_values(":c", AttributeValue::N(Token::now().to_string()));
+
+    let output = call(
+        "UpdateItem",
+        &dynamodb.tables.messages,
+        request
+            .update_expression(format!("SET {}", sets.join(", ")))
+            .send(),
+    )
+    .await
+    .map_err(|error| match &error {
+        SdkError::ServiceError { err, .. } if err.is_conditional_check_failed_exception() => {
+            ChatError::new(None, "Room does not exist".into())
+        }
+        _ => error.into(),
+    })?;
+
+    output.attributes.ok_or_else(query_error)
+}
+
+/// A post recorded by `record_post`, with what it replaced, so that it can be
+/// taken back with `unrecord_post` if the message can't be posted after all.
+pub struct RecordedPost {
+    posted_at: i64,
+    previous: Option<HashMap<String, AttributeValue>>,
+}
+
+/// Enforces a room's flood control settings for a user that is about to post
+/// `message`, and records the post so that the next one can be checked.
+///
+/// Every user has a `last_post.USER_ID` item in each room they post in. The
+/// check and the write are one conditional `PutItem`, so two messages sent at
+/// the same time can't both sneak through. When the condition fails, we read
+/// the item back to tell the user how long they have to wait.
+pub async fn record_post(
+    dynamodb: &Db,
+    room_id: &str,
+    sender_id: &str,
+    message: &str,
+    settings: &RoomSettings,
+) -> Result<RecordedPost, ChatError> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let slow_cutoff = now - i64::from(settings.slow_mode_seconds) * 1000;
+    let duplicate_cutoff = now - i64::from(settings.duplicate_window_seconds) * 1000;
+
+    let result = call(
+        "PutItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .put_item()
+            .table_name(&dynamodb.tables.messages)
+            .item("room_id", AttributeValue::N(room_id.to_owned()))
+            .item(
+                "sort",
+                AttributeValue::S(format!("last_post.{}", sender_id)),
+            )
+            .item("posted_at", AttributeValue::N(now.to_string()))
+            .item("message", AttributeValue::S(message.to_owned()))
+            .condition_expression(
+                "attribute_not_exists(room_id) OR \
+             (posted_at <= :s AND (message <> :m OR posted_at <= :d))",
+            )
+            .expression_attribute_values(":s", AttributeValue::N(slow_cutoff.to_string()))
+            .expression_attribute_values(":d", AttributeValue::N(duplicate_cutoff.to_string()))
+            .expression_attribute_values(":m", AttributeValue::S(message.to_owned()))
+            .return_values(ReturnValue::AllOld)
+            .send(),
+    )
+    .await;
+
+    match result {
+        Ok(output) => Ok(RecordedPost {
+            posted_at: now,
+            previous: output.attributes,
+        }),
+        Err(SdkError::ServiceError { err, .. }) if err.is_conditional_check_failed_exception() => {
+            Err(flood_error(dynamodb, room_id, sender_id, message, settings, now).await?)
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Takes back a post recorded by `record_post`, so that a message that
+/// couldn't be posted doesn't count against slow mode. Only if nothing was
+/// recorded since, which would mean another post went through.
+pub async fn unrecord_post(
+    dynamodb: &Db,
+    room_id: &str,
+    sender_id: &str,
+    post: RecordedPost,
+) -> Result<(), ChatError> {
+    let posted_at = AttributeValue::N(post.posted_at.to_string());
+    match post.previous {
+        Some(previous) => {
+            let result = call(
+                "PutItem",
+                &dynamodb.tables.messages,
+                dynamodb
+                    .client
+                    .put_item()
+                    .table_name(&dynamodb.tables.messages)
+                    .set_item(Some(previous))
+                    .condition_expression("posted_at = :p")
+                    .expression_attribute_values(":p", posted_at)
+                    .send(),
+            )
+            .await;
+            match result {
+                Ok(_) => Ok(()),
+                Err(SdkError::ServiceError { err, .. })
+                    if err.is_conditional_check_failed_exception() =>
+                {
+                    Ok(())
+                }
+                Err(error) => Err(error.into()),
+            }
+        }
+        None => {
+            let result = call(
+                "DeleteItem",
+                &dynamodb.tables.messages,
+                dynamodb
+                    .client
+                    .delete_item()
+                    .table_name(&dynamodb.tables.messages)
+                    .key("room_id", AttributeValue::N(room_id.to_owned()))
+                    .key(
+                        "sort",
+                        AttributeValue::S(format!("last_post.{}", sender_id)),
+                    )
+                    .condition_expression("posted_at = :p")
+                    .expression_attribute_values(":p", posted_at)
+                    .send(),
+            )
+            .await;
+            match result {
+                Ok(_) => Ok(()),
+                Err(SdkError::ServiceError { err, .. })
+                    if err.is_conditional_check_failed_exception() =>
+                {
+                    Ok(())
+                }
+                Err(error) => Err(error.into()),
+            }
+        }
+    }
+}
+
+/// Works out which flood control rule a post broke, and for how long.
+async fn flood_error(
+    dynamodb: &Db,
+    room_id: &str,
+    sender_id: &str,
+    message: &str,
+    settings: &RoomSettings,
+    now: i64,
+) -> Result<ChatError, ChatError> {
+    let output = call(
+        "GetItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .get_item()
+            .table_name(&dynamodb.tables.messages)
+            .key("room_id", AttributeValue::N(room_id.to_owned()))
+            .key(
+                "sort",
+                AttributeValue::S(format!("last_post.{}", sender_id)),
+            )
+            .projection_expression("posted_at,message")
+            .send(),
+    )
+    .await?;
+    let last_post = output.item.ok_or_else(query_error)?;
+    let posted_at: i64 = last_post
+        .get("posted_at")
+        .ok_or_else(query_error)?
+        .as_n()?
+        .parse()?;
+    let is_duplicate = last_post
+        .get("message")
+        .and_then(|last| last.as_s().ok())
+        .is_some_and(|last| last == message);
+    Ok(broken_rule(settings, posted_at, is_duplicate, now))
+}
+
+/// Which rule a post at `now` breaks, given when the user last posted and
+/// whether it's the same message, and how long until it doesn't. Times are
+/// in milliseconds.
+fn broken_rule(settings: &RoomSettings, posted_at: i64, is_duplicate: bool, now: i64) -> ChatError {
+    let wait = |seconds: u32| {
+        let until = posted_at + i64::from(seconds) * 1000;
+        Duration::from_millis((until - now).max(0) as u64)
+    };
+    let slow_wait = wait(settings.slow_mode_seconds);
+    let duplicate_wait = if is_duplicate {
+        wait(settings.duplicate_window_seconds)
+    } else {
+        Duration::ZERO
+    };
+
+    if duplicate_wait > slow_wait {
+        ChatError::too_many_requests(
+            format!(
+                "You already sent that message, you can send it again in {} seconds",
+                retry_after_seconds(duplicate_wait)
+            ),
+            duplicate_wait,
+        )
+    } else {
+        ChatError::too_many_requests(
+            format!(
+                "Slow mode is on, you can post again in {} seconds",
+                retry_after_seconds(slow_wait)
+            ),
+            slow_wait,
+        )
+    }
+}
+
+async fn get_active_rooms_scalar(dynamodb: &Db) -> Result<String, ChatError> {
+    let output = call(
+        "GetItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .get_item()
+            .table_name(&dynamodb.tables.messages)
+            .key("room_id", AttributeValue::N("1".into()))
+            .key("sort", AttributeValue::S("active_rooms".into()))
+            .projection_expression("room_ids")
+            .consistent_read(true)
+            .send(),
+    )
+    .await?;
 
     match output.item {
         None => Ok(String::new()),
@@ -151,8 +794,94 @@ async fn get_active_rooms_scalar(dynamodb: &aws_sdk_dynamodb::Client) -> Result<
     }
 }
 
+/// The IDs in the list of active rooms, least recently used first.
+pub async fn get_active_room_ids(dynamodb: &Db) -> Result<Vec<String>, ChatError> {
+    Ok(split_room_ids(&get_active_rooms_scalar(dynamodb).await?))
+}
+
+fn split_room_ids(room_ids_csv: &str) -> Vec<String> {
+    room_ids_csv
+        .split(',')
+        .filter(|room_id| !room_id.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Changes the list of active rooms, least recently used first, with
+/// `change`. If something else changes the list between reading and writing
+/// it, like a message being sent, it's read and changed again, so neither
+/// change is lost. Returns the list as written.
+pub async fn update_active_room_ids(
+    dynamodb: &Db,
+    mut change: impl FnMut(Vec<String>) -> Vec<String>,
+) -> Result<Vec<String>, ChatError> {
+    for attempt in 0..ACTIVE_ROOMS_ATTEMPTS {
+        let previous = get_active_rooms_scalar(dynamodb).await?;
+        let room_ids = change(split_room_ids(&previous));
+        let room_ids_csv = room_ids.join(",");
+        if room_ids_csv == previous
+            || replace_active_rooms(dynamodb, &previous, room_ids_csv).await?
+        {
+            return Ok(room_ids);
+        }
+        active_rooms_backoff(attempt).await;
+    }
+    Err(active_rooms_busy())
+}
+
+/// Writes the list of active rooms if it's still `previous`, as read by
+/// `get_active_rooms_scalar`. Returns whether it was.
+async fn replace_active_rooms(
+    dynamodb: &Db,
+    previous: &str,
+    room_ids_csv: String,
+) -> Result<bool, ChatError> {
+    // A list that has never been written reads as empty too.
+    let condition = if previous.is_empty() {
+        "attribute_not_exists(room_ids) OR room_ids = :previous"
+    } else {
+        "room_ids = :previous"
+    };
+    let result = call(
+        "PutItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .put_item()
+            .table_name(&dynamodb.tables.messages)
+            .item("room_id", AttributeValue::N("1".into()))
+            .item("sort", AttributeValue::S("active_rooms".into()))
+            .item("room_ids", AttributeValue::S(room_ids_csv))
+            .condition_expression(condition)
+            .expression_attribute_values(":previous", AttributeValue::S(previous.to_owned()))
+            .send(),
+    )
+    .await;
+    match result {
+        Ok(_) => Ok(true),
+        Err(SdkError::ServiceError { err, .. }) if err.is_conditional_check_failed_exception() => {
+            Ok(false)
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Waits a random while, longer after each attempt, so that writers that
+/// keep changing the list of active rooms at once spread out.
+async fn active_rooms_backoff(attempt: usize) {
+    let most = 10 << attempt.min(6);
+    tokio::time::sleep(Duration::from_millis(fastrand::u64(0..=most))).await;
+}
+
+fn active_rooms_busy() -> ChatError {
+    ChatError::new(
+        None,
+        "The list of active rooms kept changing, try again".into(),
+    )
+}
+
 pub async fn get_active_rooms(
-    dynamodb: &aws_sdk_dynamodb::Client,
+    dynamodb: &Db,
 ) -> Result<Vec<HashMap<String, AttributeValue>>, ChatError> {
     let room_ids_csv = get_active_rooms_scalar(dynamodb).await?;
 
@@ -175,20 +904,27 @@ pub async fn get_active_rooms(
         return Ok(vec![]);
     }
 
-    let output = dynamodb
-        .batch_get_item()
-        .request_items(
-            "messages",
-            KeysAndAttributes::builder()
-                .set_keys(Some(room_ids))
-                .projection_expression("room_id,#n")
-                .expression_attribute_names("#n", "name")
-                .build(),
-        )
-        .send()
-        .await?;
+    let output = call(
+        "BatchGetItem",
+        &dynamodb.tables.messages,
+        dynamodb
+            .client
+            .batch_get_item()
+            .request_items(
+                &dynamodb.tables.messages,
+                KeysAndAttributes::builder()
+                    .set_keys(Some(room_ids))
+                    .projection_expression(
+                        "room_id,#n,slow_mode_seconds,duplicate_window_seconds,changed_at,history_changed_at",
+                    )
+                    .expression_attribute_names("#n", "name")
+                    .build(),
+            )
+            .send(),
+    )
+    .await?;
 
-    let mut rooms = output.responses.ok_or_else(query_error)?["messages"].clone();
+    let mut rooms = output.responses.ok_or_else(query_error)?[&dynamodb.tables.messages].clone();
 
     // We want to sort them by ascending index, because rightmost entry in the
     // stack is most recently used room.
@@ -205,32 +941,163 @@ pub async fn get_active_rooms(
 
 // Users
 
+/// Creates a user, unless somebody already has the name (or one that looks
+/// like it). Returns whether they were created.
+///
+/// The name is claimed by putting its `name_key` in the user names table, in
+/// the same transaction as the user, so two people signing up with the same
+/// name at once can't both get it.
 pub async fn create_user(
-    dynamodb: &aws_sdk_dynamodb::Client,
+    dynamodb: &Db,
     user_id: &str,
     user_name: &str,
-) -> Result<PutItemOutput, SdkError<PutItemError>> {
-    Ok(dynamodb
-        .put_item()
-        .table_name("users")
+    name_key: &str,
+) -> Result<bool, ChatError> {
+    let user = Put::builder()
+        .table_name(&dynamodb.tables.users)
         .item("user_id", AttributeValue::N(user_id.to_owned()))
         .item("name", AttributeValue::S(user_name.to_owned()))
-        .send()
-        .await?)
+        .item("name_key", AttributeValue::S(name_key.to_owned()))
+        .condition_expression("attribute_not_exists(user_id)")
+        .build();
+    let result = call(
+        "TransactWriteItems",
+        &dynamodb.tables.users,
+        dynamodb
+            .client
+            .transact_write_items()
+            .transact_items(TransactWriteItem::builder().put(user).build())
+            .transact_items(claim_name(dynamodb, name_key, user_id, user_name))
+            .send(),
+    )
+    .await;
+    match result {
+        Ok(_) => Ok(true),
+        Err(error) if condition_failed(&error, 1) => Ok(false),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Puts a `name_key` in the user names table for a user, for a transaction.
+/// It fails the transaction if somebody else has it already.
+pub fn claim_name(
+    dynamodb: &Db,
+    name_key: &str,
+    user_id: &str,
+    user_name: &str,
+) -> TransactWriteItem {
+    TransactWriteItem::builder()
+        .put(
+            Put::builder()
+                .table_name(&dynamodb.tables.user_names)
+                .item("name_key", AttributeValue::S(name_key.to_owned()))
+                .item("user_id", AttributeValue::N(user_id.to_owned()))
+                .item("name", AttributeValue::S(user_name.to_owned()))
+                .condition_expression("attribute_not_exists(name_key) OR user_id = :id")
+                .expression_attribute_values(":id", AttributeValue::N(user_id.to_owned()))
+                .build(),
+        )
+        .build()
+}
+
+/// Deletes a user's `name_key` from the user names table, for a
+/// transaction, so that somebody else can have it. It fails the transaction if
+/// the name isn't theirs anymore.
+pub fn release_name(dynamodb: &Db, name_key: &str, user_id: &str) -> TransactWriteItem {
+    TransactWriteItem::builder()
+        .delete(
+            Delete::builder()
+                .table_name(&dynamodb.tables.user_names)
+                .key("name_key", AttributeValue::S(name_key.to_owned()))
+                .condition_expression("user_id = :id")
+                .expression_attribute_values(":id", AttributeValue::N(user_id.to_owned()))
+                .build(),
+        )
+        .build()
+}
+
+/// Whether a transaction was canceled because the condition on its `index`th
+/// item (counting from 0) wasn't met.
+pub fn condition_failed(error: &SdkError<TransactWriteItemsError>, index: usize) -> bool {
+    match error {
+        SdkError::ServiceError { err, .. } => match &err.kind {
+            TransactWriteItemsErrorKind::TransactionCanceledException(canceled) => {
+                canceled
+                    .cancellation_reasons
+                    .as_ref()
+                    .and_then(|reasons| reasons.get(index))
+                    .and_then(|reason| reason.code.as_deref())
+                    == Some("ConditionalCheckFailed")
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The ID and name of the user who has a `name_key`, if anybody does. Unlike
+/// `get_user_by_name_key`, this sees names claimed a moment ago.
+pub async fn get_name_owner(
+    dynamodb: &Db,
+    name_key: &str,
+) -> Result<Option<(String, String)>, ChatError> {
+    let output = call(
+        "GetItem",
+        &dynamodb.tables.user_names,
+        dynamodb
+            .client
+            .get_item()
+            .table_name(&dynamodb.tables.user_names)
+            .key("name_key", AttributeValue::S(name_key.to_owned()))
+            .consistent_read(true)
+            .send(),
+    )
+    .await?;
+
+    Ok(output.item.and_then(|item| {
+        let user_id = item.get("user_id")?.as_n().ok()?;
+        let name = item.get("name")?.as_s().ok()?;
+        Some((user_id.clone(), name.clone()))
+    }))
+}
+
+/// Finds the user who has a name (or one that looks like it), and creates
+/// them if nobody does. Returns their ID and name, and whether they were just
+/// created.
+pub async fn find_or_create_user(
+    dynamodb: &Db,
+    user_name: &str,
+    name_key: &str,
+) -> Result<(String, String, bool), ChatError> {
+    loop {
+        if let Some((user_id, name)) = get_name_owner(dynamodb, name_key).await? {
+            return Ok((user_id, name, false));
+        }
+        let user_id = crate::uuid();
+        if create_user(dynamodb, &user_id, user_name, name_key).await? {
+            return Ok((user_id, user_name.to_owned(), true));
+        }
+        // Somebody else took the name in between, so look again.
+    }
 }
 
 pub async fn get_user_by_id(
-    dynamodb: &aws_sdk_dynamodb::Client,
+    dynamodb: &Db,
     user_id: &str,
 ) -> Result<HashMap<String, AttributeValue>, ChatError> {
-    let output = dynamodb
-        .get_item()
-        .table_name("users")
-        .key("user_id", AttributeValue::N(user_id.to_owned()))
-        .projection_expression("user_id,#n")
-        .expression_attribute_names("#n", "name")
-        .send()
-        .await?;
+    let output = call(
+        "GetItem",
+        &dynamodb.tables.users,
+        dynamodb
+            .client
+            .get_item()
+            .table_name(&dynamodb.tables.users)
+            .key("user_id", AttributeValue::N(user_id.to_owned()))
+            .projection_expression("user_id,#n")
+            .expression_attribute_names("#n", "name")
+            .send(),
+    )
+    .await?;
 
     Ok(output
         .item
@@ -239,19 +1106,24 @@ pub async fn get_user_by_id(
 }
 
 pub async fn get_user_by_name(
-    dynamodb: &aws_sdk_dynamodb::Client,
+    dynamodb: &Db,
     user_name: &str,
 ) -> Result<HashMap<String, AttributeValue>, ChatError> {
-    let output = dynamodb
-        .query()
-        .table_name("users")
-        .index_name("name-index")
-        .projection_expression("user_id,#n")
-        .key_condition_expression("#n=:n")
-        .expression_attribute_names("#n", "name")
-        .expression_attribute_values(":n", AttributeValue::S(user_name.to_owned()))
-        .send()
-        .await?;
+    let output = call(
+        "Query",
+        &dynamodb.tables.users,
+        dynamodb
+            .client
+            .query()
+            .table_name(&dynamodb.tables.users)
+            .index_name("name-index")
+            .projection_expression("user_id,#n")
+            .key_condition_expression("#n=:n")
+            .expression_attribute_names("#n", "name")
+            .expression_attribute_values(":n", AttributeValue::S(user_name.to_owned()))
+            .send(),
+    )
+    .await?;
 
     if output.count() != 1 {
         return Err(ChatError::new(None, "Result DNE or is ambiguous".into()));
@@ -265,10 +1137,106 @@ pub async fn get_user_by_name(
         .clone())
 }
 
-pub async fn user_exists(dynamodb: &aws_sdk_dynamodb::Client, user_name: &str) -> bool {
-    matches!(get_user_by_name(dynamodb, user_name).await, Ok(_))
+/// Looks a user up by `validation::name_key`, which ignores case and
+/// look-alike characters.
+pub async fn get_user_by_name_key(
+    dynamodb: &Db,
+    name_key: &str,
+) -> Result<Option<HashMap<String, AttributeValue>>, ChatError> {
+    let output = call(
+        "Query",
+        &dynamodb.tables.users,
+        dynamodb
+            .client
+            .query()
+            .table_name(&dynamodb.tables.users)
+            .index_name("name-key-index")
+            .key_condition_expression("name_key=:k")
+            .expression_attribute_values(":k", AttributeValue::S(name_key.to_owned()))
+            .send(),
+    )
+    .await?;
+
+    Ok(output.items.and_then(|items| items.into_iter().next()))
 }
 
 fn query_error() -> ChatError {
     ChatError::new(None, "Query error".into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(slow_mode_seconds: u32, duplicate_window_seconds: u32) -> RoomSettings {
+        RoomSettings {
+            slow_mode_seconds,
+            duplicate_window_seconds,
+        }
+    }
+
+    fn broken(
+        settings: &RoomSettings,
+        ago_ms: i64,
+        is_duplicate: bool,
+    ) -> (String, Option<Duration>) {
+        let now = 1_700_000_000_000;
+        let error = broken_rule(settings, now - ago_ms, is_duplicate, now);
+        assert_eq!(error.status, http::StatusCode::TOO_MANY_REQUESTS);
+        (error.display, error.retry_after)
+    }
+
+    #[test]
+    fn slow_mode_waits_from_the_last_post() {
+        assert_eq!(
+            broken(&settings(10, 0), 2_000, false),
+            (
+                "Slow mode is on, you can post again in 8 seconds".into(),
+                Some(Duration::from_secs(8))
+            )
+        );
+        // Retry-After rounds up, so that clients aren't early.
+        assert_eq!(
+            broken(&settings(10, 0), 2_500, false),
+            (
+                "Slow mode is on, you can post again in 8 seconds".into(),
+                Some(Duration::from_millis(7_500))
+            )
+        );
+        // Sending the same message again doesn't matter without a window.
+        assert_eq!(
+            broken(&settings(10, 0), 2_000, true).1,
+            Some(Duration::from_secs(8))
+        );
+    }
+
+    #[test]
+    fn duplicates_wait_out_the_window() {
+        assert_eq!(
+            broken(&settings(0, 60), 30_000, true),
+            (
+                "You already sent that message, you can send it again in 30 seconds".into(),
+                Some(Duration::from_secs(30))
+            )
+        );
+        // With both on, whichever is longer is what the user has to wait.
+        assert_eq!(
+            broken(&settings(10, 60), 5_000, true).1,
+            Some(Duration::from_secs(55))
+        );
+        assert_eq!(
+            broken(&settings(10, 60), 5_000, false),
+            (
+                "Slow mode is on, you can post again in 5 seconds".into(),
+                Some(Duration::from_secs(5))
+            )
+        );
+        assert_eq!(
+            broken(&settings(30, 20), 5_000, true),
+            (
+                "Slow mode is on, you can post again in 25 seconds".into(),
+                Some(Duration::from_secs(25))
+            )
+        );
+    }
+}
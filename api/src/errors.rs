@@ -1,14 +1,20 @@
 use aws_sdk_dynamodb::{
     error::{
-        BatchGetItemError, DeleteItemError, GetItemError, PutItemError, QueryError, ScanError,
-        UpdateItemError,
+        BatchGetItemError, BatchWriteItemError, DeleteItemError, GetItemError, PutItemError,
+        QueryError, ScanError, TransactWriteItemsError, UpdateItemError,
     },
     model::AttributeValue,
     types::SdkError,
 };
 use axum::response::{IntoResponse, Response};
+use http::header::{CONTENT_TYPE, RETRY_AFTER};
 use hyper::StatusCode;
-use std::num::{ParseFloatError, ParseIntError};
+use serde::Serialize;
+use std::{
+    num::{ParseFloatError, ParseIntError},
+    time::Duration,
+};
+use utoipa::ToSchema;
 
 /// This is our project's private error type. It is a very simple wrapper that
 /// has both an optional message to log to CloudWatch (if filled) and a message
@@ -23,21 +29,71 @@ use std::num::{ParseFloatError, ParseIntError};
 ///
 /// For the most part, you can ignore this file, the TL;DR is that this is just
 /// a mechanism that allows the app to send a uniform error back to Axum.
+///
+/// Errors are 500's unless they say otherwise. Errors that tell the client to
+/// back off (429) carry a `retry_after` which is sent as a `Retry-After`
+/// header, in whole seconds. Errors about bad input (400) carry a list of
+/// `fields` that are wrong, and are sent as JSON so the client can show each
+/// message next to the field it belongs to.
 pub struct ChatError {
     pub debug: Option<String>,
     pub display: String,
+    pub status: StatusCode,
+    pub retry_after: Option<Duration>,
+    pub fields: Vec<FieldError>,
+}
+
+/// Something wrong with one field of a request.
+#[derive(Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, message: &str) -> Self {
+        Self {
+            field,
+            message: message.to_owned(),
+        }
+    }
+}
+
+/// The body of a 400 caused by invalid fields.
+#[derive(Serialize, ToSchema)]
+pub struct InvalidRequest<'a> {
+    error: &'a str,
+    fields: &'a [FieldError],
 }
 
 impl IntoResponse for ChatError {
     fn into_response(self) -> Response {
-        let id = fastrand::u64(u64::MIN..u64::MAX);
+        // The request ID is logged along with the error, and is in the
+        // response's `X-Request-Id` too, so a user can quote it.
         let mut id_string = String::new();
-        if let Some(debug) = self.debug {
-            println!("FATAL ({}) {}", id, debug);
+        if let Some(detail) = self.debug {
+            tracing::error!(error = %detail, "{}", self.display);
+            let id = crate::telemetry::request_id()
+                .filter(|id| !id.is_empty())
+                .unwrap_or_else(|| format!("{:x}", fastrand::u64(..)));
             id_string.push_str(&format!(" ({})", id));
         }
-        Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
+        let mut response = Response::builder().status(self.status);
+        if let Some(retry_after) = self.retry_after {
+            response = response.header(RETRY_AFTER, retry_after_seconds(retry_after));
+        }
+        if !self.fields.is_empty() {
+            let body = serde_json::to_string(&InvalidRequest {
+                error: &self.display,
+                fields: &self.fields,
+            })
+            .unwrap();
+            return response
+                .header(CONTENT_TYPE, "application/json")
+                .body(axum::body::boxed(axum::body::Full::from(body)))
+                .unwrap();
+        }
+        response
             .body(axum::body::boxed(axum::body::Full::from(format!(
                 "{}{}",
                 self.display, id_string
@@ -48,7 +104,56 @@ impl IntoResponse for ChatError {
 
 impl ChatError {
     pub fn new(debug: Option<String>, display: String) -> Self {
-        Self { debug, display }
+        Self {
+            debug,
+            display,
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            retry_after: None,
+            fields: vec![],
+        }
+    }
+
+    /// An error with a status other than 500, e.g. a 413 for a body that is
+    /// too large. These are the client's fault, so there's nothing to log.
+    pub fn with_status(status: StatusCode, display: String) -> Self {
+        Self {
+            debug: None,
+            display,
+            status,
+            retry_after: None,
+            fields: vec![],
+        }
+    }
+
+    pub fn invalid(fields: Vec<FieldError>) -> Self {
+        Self {
+            debug: None,
+            display: "Invalid request".into(),
+            status: StatusCode::BAD_REQUEST,
+            retry_after: None,
+            fields,
+        }
+    }
+
+    pub fn too_many_requests(display: String, retry_after: Duration) -> Self {
+        Self {
+            debug: None,
+            display,
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after: Some(retry_after),
+            fields: vec![],
+        }
+    }
+}
+
+/// `Retry-After` only takes whole seconds. Round up so that clients that do
+/// exactly what we say don't get rejected again for being a few ms early.
+pub fn retry_after_seconds(retry_after: Duration) -> u64 {
+    let seconds = retry_after.as_secs();
+    if retry_after.subsec_nanos() > 0 {
+        seconds + 1
+    } else {
+        seconds.max(1)
     }
 }
 
@@ -70,6 +175,15 @@ impl From<SdkError<BatchGetItemError>> for ChatError {
     }
 }
 
+impl From<SdkError<BatchWriteItemError>> for ChatError {
+    fn from(error: SdkError<BatchWriteItemError>) -> Self {
+        Self::new(
+            Some(format!("{:?}", error)),
+            "Internal server error".to_string(),
+        )
+    }
+}
+
 impl From<SdkError<UpdateItemError>> for ChatError {
     fn from(error: SdkError<UpdateItemError>) -> Self {
         Self::new(
@@ -97,6 +211,15 @@ impl From<SdkError<DeleteItemError>> for ChatError {
     }
 }
 
+impl From<SdkError<TransactWriteItemsError>> for ChatError {
+    fn from(error: SdkError<TransactWriteItemsError>) -> Self {
+        Self::new(
+            Some(format!("{:?}", error)),
+            "Internal server error".to_string(),
+        )
+    }
+}
+
 impl From<SdkError<QueryError>> for ChatError {
     fn from(error: SdkError<QueryError>) -> Self {
         Self::new(
@@ -141,3 +264,9 @@ impl From<&AttributeValue> for ChatError {
         )
     }
 }
+
+impl From<FieldError> for ChatError {
+    fn from(error: FieldError) -> Self {
+        Self::invalid(vec![error])
+    }
+}
@@ -0,0 +1,211 @@
+use crate::json_ld::{Format, JsonLd, Negotiated};
+use axum::{
+    async_trait,
+    extract::{FromRequest, RequestParts},
+    response::{IntoResponse, Response},
+};
+use http::{
+    header::{ETAG, IF_NONE_MATCH, VARY},
+    HeaderValue, StatusCode,
+};
+use std::convert::Infallible;
+
+/// An entity tag for a response, so that clients that poll can send it back
+/// in `If-None-Match` and get an empty `304 Not Modified` when nothing has
+/// changed.
+///
+/// Tags are weak, since the same data can be serialized with its fields in a
+/// different order, and are different for JSON and JSON-LD, and for each base
+/// URL, since the URIs in the body depend on it.
+pub struct ETag(String);
+
+impl ETag {
+    /// Tags a response by its body.
+    pub fn of<T: JsonLd>(format: &Format, body: &T) -> Self {
+        let mut hash = Fnv1a::new();
+        hash.write(format_key(format).as_bytes());
+        hash.write(&serde_json::to_vec(body).unwrap_or_default());
+        Self(format!("W/\"{:016x}\"", hash.finish()))
+    }
+
+    /// Tags a response by a counter that goes up every time what it's built
+    /// from changes, so that it can be checked without building it. `id` is
+    /// the URI of the resource, which has the base URL in it.
+    pub fn versioned(format: &Format, id: &str, version: u64) -> Self {
+        let mut hash = Fnv1a::new();
+        hash.write(format_key(format).as_bytes());
+        hash.write(id.as_bytes());
+        Self(format!("W/\"{}-{:016x}\"", version, hash.finish()))
+    }
+
+    fn header(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.0).unwrap()
+    }
+}
+
+/// What sets one representation apart from another, apart from the body.
+fn format_key(format: &Format) -> &str {
+    match format {
+        Format::Json => "json",
+        Format::JsonLd { context } => context,
+    }
+}
+
+/// 64 bit FNV-1a. The tags have to stay the same across restarts and
+/// replicas, which `std`'s hasher doesn't promise.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+        // Keeps "ab" + "c" apart from "a" + "bc".
+        self.0 ^= 0xff;
+        self.0 = self.0.wrapping_mul(0x100000001b3);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The tags in a request's `If-None-Match` header, if it has one.
+pub struct IfNoneMatch(Vec<String>);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for IfNoneMatch {
+    type Rejection = Infallible;
+
+    async fn from_request(request: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        Ok(IfNoneMatch(
+            request
+                .headers()
+                .map(|headers| {
+                    headers
+                        .get_all(IF_NONE_MATCH)
+                        .iter()
+                        .filter_map(|value| value.to_str().ok())
+                        .flat_map(|value| value.split(','))
+                        .map(|tag| tag.trim().to_owned())
+                        .filter(|tag| !tag.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        ))
+    }
+}
+
+impl IfNoneMatch {
+    /// Whether the client already has the representation tagged `etag`. Uses
+    /// the weak comparison, as RFC 9110 says to for `If-None-Match`.
+    pub fn matches(&self, etag: &ETag) -> bool {
+        let opaque = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_owned();
+        let etag = opaque(&etag.0);
+        self.0.iter().any(|tag| tag == "*" || opaque(tag) == etag)
+    }
+
+    /// Answers with a `304 Not Modified` if the client already has the
+    /// response, and with the response and its tag otherwise.
+    pub fn respond<T: JsonLd>(&self, etag: ETag, format: Format, body: T) -> Conditional<T> {
+        if self.matches(&etag) {
+            Conditional::NotModified(etag)
+        } else {
+            Conditional::Modified(etag, Negotiated(format, body))
+        }
+    }
+}
+
+/// A response to a conditional `GET`.
+pub enum Conditional<T: JsonLd> {
+    NotModified(ETag),
+    Modified(ETag, Negotiated<T>),
+}
+
+impl<T: JsonLd> IntoResponse for Conditional<T> {
+    fn into_response(self) -> Response {
+        match self {
+            Conditional::NotModified(etag) => {
+                // A 304 has to have the headers the 200 would have had that
+                // say how to cache it.
+                let mut response = StatusCode::NOT_MODIFIED.into_response();
+                let headers = response.headers_mut();
+                headers.insert(ETAG, etag.header());
+                headers.append(VARY, HeaderValue::from_static("accept"));
+                response
+            }
+            Conditional::Modified(etag, negotiated) => {
+                let mut response = negotiated.into_response();
+                response.headers_mut().insert(ETAG, etag.header());
+                response
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Request;
+
+    async fn if_none_match(values: &[&str]) -> IfNoneMatch {
+        let mut request = Request::builder();
+        for value in values {
+            request = request.header(IF_NONE_MATCH, *value);
+        }
+        let mut request = RequestParts::new(request.body(()).unwrap());
+        IfNoneMatch::from_request(&mut request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn tags_are_compared_weakly() {
+        let etag = ETag::versioned(&Format::Json, "http://api/rooms/1/messages", 3);
+        let opaque = etag.0.strip_prefix("W/").unwrap().to_owned();
+
+        assert!(if_none_match(&[&etag.0]).await.matches(&etag));
+        assert!(if_none_match(&[&opaque]).await.matches(&etag));
+        assert!(!if_none_match(&["W/\"3-0000000000000000\""])
+            .await
+            .matches(&etag));
+        assert!(!if_none_match(&[]).await.matches(&etag));
+    }
+
+    #[tokio::test]
+    async fn any_tag_in_a_list_or_a_star_matches() {
+        let etag = ETag::versioned(&Format::Json, "http://api/rooms/1/messages", 3);
+        let list = format!("W/\"2-0000000000000000\", {} ,\"other\"", etag.0);
+
+        assert!(if_none_match(&[&list]).await.matches(&etag));
+        assert!(if_none_match(&["\"other\"", &etag.0]).await.matches(&etag));
+        assert!(if_none_match(&["*"]).await.matches(&etag));
+        assert!(!if_none_match(&["\"other\", ,W/\"another\""])
+            .await
+            .matches(&etag));
+    }
+
+    #[test]
+    fn tags_differ_by_version_format_and_base_url() {
+        let tag = |format: &Format, id: &str, version| ETag::versioned(format, id, version).0;
+        let json_ld = Format::JsonLd {
+            context: "http://api/context.jsonld".into(),
+        };
+        let id = "http://api/rooms/1/messages";
+
+        assert_eq!(tag(&Format::Json, id, 3), tag(&Format::Json, id, 3));
+        assert_ne!(tag(&Format::Json, id, 3), tag(&Format::Json, id, 4));
+        assert_ne!(tag(&Format::Json, id, 3), tag(&json_ld, id, 3));
+        assert_ne!(
+            tag(&Format::Json, id, 3),
+            tag(
+                &Format::Json,
+                "https://chat.example.com/rooms/1/messages",
+                3
+            )
+        );
+    }
+}
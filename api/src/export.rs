@@ -0,0 +1,336 @@
+use crate::{
+    db::{self, Db},
+    errors::{ChatError, FieldError},
+    models::MessageProperties,
+};
+use aws_sdk_dynamodb::model::AttributeValue;
+use std::collections::HashMap;
+
+type Item = HashMap<String, AttributeValue>;
+
+/// How many messages are read at a time. Each page is written out before the
+/// next one is read, so an export never holds more than this in memory.
+const PAGE_SIZE: i32 = 100;
+
+/// The columns of the CSV export, which are the fields of `MessageProperties`.
+const COLUMNS: [&str; 5] = ["date_time", "sender_name", "message", "room", "user"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// One JSON array of messages.
+    Json,
+    /// A JSON message per line.
+    Ndjson,
+    Csv,
+    /// A page that needs nothing else to be read, not even a stylesheet.
+    Html,
+    /// Plain text, one message per line, like a chat log.
+    Txt,
+}
+
+impl Format {
+    pub fn parse(field: &'static str, format: &str) -> Result<Self, FieldError> {
+        match format {
+            "json" => Ok(Format::Json),
+            "ndjson" => Ok(Format::Ndjson),
+            "csv" => Ok(Format::Csv),
+            "html" => Ok(Format::Html),
+            "txt" => Ok(Format::Txt),
+            _ => Err(FieldError::new(
+                field,
+                &format!("{:?} isn't one of json, ndjson, csv, html or txt", format),
+            )),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Html => "text/html; charset=utf-8",
+            Format::Txt => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+            Format::Csv => "csv",
+            Format::Html => "html",
+            Format::Txt => "txt",
+        }
+    }
+}
+
+/// A room's whole history, oldest message first, written out a page at a
+/// time. Call `next` until it returns `None`, and write out each chunk as it
+/// comes:
+///
+/// ```ignore
+/// let mut export = Export::open(dynamodb, room_id, base_url, Format::Csv).await?;
+/// while let Some(chunk) = export.next().await? {
+///     file.write_all(chunk.as_bytes()).await?;
+/// }
+/// ```
+pub struct Export {
+    dynamodb: Db,
+    room_id: String,
+    room_name: String,
+    base_url: String,
+    format: Format,
+    state: State,
+    /// Whether a message has been written yet, for the commas in JSON.
+    written: bool,
+}
+
+enum State {
+    Start,
+    /// The key the next page starts after, `None` for the first page.
+    Page(Option<Item>),
+    End,
+    Done,
+}
+
+impl Export {
+    /// Looks up the room, so that a room that doesn't exist is an error
+    /// before anything is written.
+    pub async fn open(
+        dynamodb: Db,
+        room_id: &str,
+        base_url: &str,
+        format: Format,
+    ) -> Result<Self, ChatError> {
+        let room = db::get_room(&dynamodb, room_id).await?;
+        let room_name = room
+            .get("name")
+            .and_then(|name| name.as_s().ok())
+            .cloned()
+            .unwrap_or_default();
+        Ok(Self {
+            dynamodb,
+            room_id: room_id.to_owned(),
+            room_name,
+            base_url: base_url.to_owned(),
+            format,
+            state: State::Start,
+            written: false,
+        })
+    }
+
+    pub fn room_name(&self) -> &str {
+        &self.room_name
+    }
+
+    /// The next chunk of the export, or `None` once it's all been written.
+    pub async fn next(&mut self) -> Result<Option<String>, ChatError> {
+        match std::mem::replace(&mut self.state, State::Done) {
+            State::Start => {
+                self.state = State::Page(None);
+                Ok(Some(self.header()))
+            }
+            State::Page(start) => {
+                let (messages, next) =
+                    db::get_messages_page(&self.dynamodb, &self.room_id, start, PAGE_SIZE).await?;
+                self.state = match next {
+                    Some(next) => State::Page(Some(next)),
+                    None => State::End,
+                };
+                let mut chunk = String::new();
+                for message in &messages {
+                    let message = self.properties(message)?;
+                    self.write(&mut chunk, &message);
+                }
+                Ok(Some(chunk))
+            }
+            State::End => Ok(Some(self.footer())),
+            State::Done => Ok(None),
+        }
+    }
+
+    /// The same fields `GET /rooms/:room_id/messages` has for a message.
+    fn properties(&self, message: &Item) -> Result<MessageProperties, ChatError> {
+        let field = |key: &str| {
+            message
+                .get(key)
+                .and_then(|value| value.as_s().or_else(|_| value.as_n()).ok())
+                .ok_or_else(|| {
+                    ChatError::new(
+                        Some(format!("Message has no {}: {:?}", key, message)),
+                        "Internal server error".into(),
+                    )
+                })
+        };
+        Ok(MessageProperties {
+            date_time: field("sort")?.trim_start_matches("message.").to_owned(),
+            sender_name: field("sender_name")?.clone(),
+            message: field("message")?.clone(),
+            room: format!("{}/rooms/{}", self.base_url, self.room_id),
+            user: format!("{}/users/{}", self.base_url, field("sender_id")?),
+        })
+    }
+
+    fn header(&self) -> String {
+        match self.format {
+            Format::Json => "[".into(),
+            Format::Ndjson => String::new(),
+            Format::Csv => format!("{}\r\n", COLUMNS.join(",")),
+            Format::Html => format!(
+                "<!DOCTYPE html>\n\
+                 <html>\n\
+                 <head>\n\
+                 <meta charset=\"utf-8\">\n\
+                 <title>{name}</title>\n\
+                 <style>\n\
+                 body {{ font-family: sans-serif; margin: 2em; }}\n\
+                 table {{ border-collapse: collapse; }}\n\
+                 th, td {{ border-bottom: 1px solid #ddd; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }}\n\
+                 td.message {{ white-space: pre-wrap; }}\n\
+                 </style>\n\
+                 </head>\n\
+                 <body>\n\
+                 <h1>{name}</h1>\n\
+                 <p><a href=\"{room}\">{room}</a></p>\n\
+                 <table>\n\
+                 <thead><tr><th>Time</th><th>Sender</th><th>Message</th></tr></thead>\n\
+                 <tbody>\n",
+                name = escape_html(&self.room_name),
+                room = escape_html(&format!("{}/rooms/{}", self.base_url, self.room_id)),
+            ),
+            Format::Txt => format!("{}\n\n", self.room_name),
+        }
+    }
+
+    fn write(&mut self, chunk: &mut String, message: &MessageProperties) {
+        match self.format {
+            Format::Json => {
+                if self.written {
+                    chunk.push(',');
+                }
+                chunk.push('\n');
+                chunk.push_str(&serde_json::to_string(message).unwrap_or_default());
+            }
+            Format::Ndjson => {
+                chunk.push_str(&serde_json::to_string(message).unwrap_or_default());
+                chunk.push('\n');
+            }
+            Format::Csv => {
+                let row = [
+                    &message.date_time,
+                    &message.sender_name,
+                    &message.message,
+                    &message.room,
+                    &message.user,
+                ];
+                let row: Vec<_> = row.iter().map(|field| escape_csv(field)).collect();
+                chunk.push_str(&row.join(","));
+                chunk.push_str("\r\n");
+            }
+            Format::Html => chunk.push_str(&format!(
+                "<tr><td><time datetime=\"{time}\">{time}</time></td>\
+                 <td><a href=\"{user}\">{sender}</a></td>\
+                 <td class=\"message\">{message}</td></tr>\n",
+                time = escape_html(&message.date_time),
+                user = escape_html(&message.user),
+                sender = escape_html(&message.sender_name),
+                message = escape_html(&message.message),
+            )),
+            // Lines after the first are indented, so that a message can't
+            // pass itself off as another one.
+            Format::Txt => chunk.push_str(&format!(
+                "[{}] {}: {}\n",
+                message.date_time,
+                message.sender_name,
+                message.message.replace('\n', "\n    ")
+            )),
+        }
+        self.written = true;
+    }
+
+    fn footer(&self) -> String {
+        match self.format {
+            Format::Json if self.written => "\n]\n".into(),
+            Format::Json => "]\n".into(),
+            Format::Html => "</tbody>\n</table>\n</body>\n</html>\n".into(),
+            Format::Ndjson | Format::Csv | Format::Txt => String::new(),
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quotes a field if it needs to be, per RFC 4180. Fields that a spreadsheet
+/// would take for a formula, like a message saying `=HYPERLINK(...)`, get a
+/// `'` in front so that they're shown as text instead, as OWASP recommends.
+fn escape_csv(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", field)
+    } else {
+        field.to_owned()
+    };
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted_when_they_need_to_be() {
+        assert_eq!(escape_csv("hello"), "hello");
+        assert_eq!(escape_csv(""), "");
+        assert_eq!(escape_csv("a, b"), "\"a, b\"");
+        assert_eq!(escape_csv("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape_csv("two\nlines"), "\"two\nlines\"");
+        assert_eq!(escape_csv("two\r\nlines"), "\"two\r\nlines\"");
+    }
+
+    #[test]
+    fn csv_fields_are_never_formulas() {
+        assert_eq!(escape_csv("=1+1"), "'=1+1");
+        assert_eq!(escape_csv("+1"), "'+1");
+        assert_eq!(escape_csv("-1"), "'-1");
+        assert_eq!(escape_csv("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(escape_csv("\t=1"), "'\t=1");
+        assert_eq!(
+            escape_csv("=HYPERLINK(\"http://example.com\", \"x\")"),
+            "\"'=HYPERLINK(\"\"http://example.com\"\", \"\"x\"\")\""
+        );
+        assert_eq!(escape_csv("\r=1"), "\"'\r=1\"");
+        // Only the start of a field can make it a formula.
+        assert_eq!(escape_csv("1+1=2"), "1+1=2");
+        assert_eq!(escape_csv("a@example.com"), "a@example.com");
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        assert_eq!(escape_html("hello"), "hello");
+        assert_eq!(
+            escape_html("<script>alert('x')</script>"),
+            "&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;"
+        );
+        assert_eq!(
+            escape_html("\" onmouseover=\"x"),
+            "&quot; onmouseover=&quot;x"
+        );
+        assert_eq!(escape_html("&lt; & é"), "&amp;lt; &amp; é");
+    }
+}
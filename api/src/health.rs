@@ -0,0 +1,181 @@
+use crate::{
+    config::Config,
+    db::Db,
+    schema::{self, Table},
+};
+use aws_sdk_dynamodb::{
+    model::{IndexStatus, TableDescription, TableStatus},
+    types::SdkError,
+};
+use axum::{extract::Extension, Json};
+use http::StatusCode;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
+
+/// How long a dependency gets to answer before it counts as down. Load
+/// balancers and orchestrators give up on the whole check after a few
+/// seconds, and a dependency that slow is as good as down anyway.
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How the API is doing, and how each of its dependencies is.
+#[derive(Serialize, ToSchema)]
+pub struct Health {
+    /// `ok` if every dependency is, `degraded` otherwise.
+    status: &'static str,
+    /// Keyed by the setting that names the dependency, like
+    /// `tables.messages`.
+    checks: BTreeMap<&'static str, Check>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Check {
+    /// `ok` or `down`.
+    status: &'static str,
+    latency_ms: u64,
+    /// What's wrong, if it's down.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Says that the process is up and serving requests. It doesn't look at
+/// anything else, so that an orchestrator doesn't restart the API because
+/// DynamoDB is down, which wouldn't help.
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    tag = "meta",
+    responses((status = 200, description = "The API is up", body = Health))
+)]
+pub async fn live() -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        checks: BTreeMap::new(),
+    })
+}
+
+/// Says whether the API can do its job: every table it uses exists and is
+/// active, with the indexes it queries. Each is checked at the same time,
+/// and gets `TIMEOUT` to answer. Load balancers should only send traffic to
+/// an API that's ready.
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    tag = "meta",
+    responses(
+        (status = 200, description = "Every dependency is ok", body = Health),
+        (status = 503, description = "A dependency is down", body = Health),
+    )
+)]
+pub async fn ready(
+    Extension(dynamodb): Extension<Db>,
+    Extension(config): Extension<Arc<Config>>,
+) -> (StatusCode, Json<Health>) {
+    let checks: Vec<_> = schema::tables(&config)
+        .into_iter()
+        .map(|table| {
+            let dynamodb = dynamodb.clone();
+            (
+                table.setting,
+                tokio::spawn(async move { check_table(&dynamodb, &table).await }),
+            )
+        })
+        .collect();
+
+    let mut results = BTreeMap::new();
+    for (setting, check) in checks {
+        let check = check.await.unwrap_or_else(|error| Check {
+            status: "down",
+            latency_ms: 0,
+            error: Some(error.to_string()),
+        });
+        results.insert(setting, check);
+    }
+
+    let (status_code, status) = if results.values().all(|check| check.status == "ok") {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+    };
+    (
+        status_code,
+        Json(Health {
+            status,
+            checks: results,
+        }),
+    )
+}
+
+async fn check_table(dynamodb: &Db, table: &Table) -> Check {
+    let start = Instant::now();
+    let result = tokio::time::timeout(
+        TIMEOUT,
+        crate::db::call(
+            "DescribeTable",
+            &table.name,
+            dynamodb
+                .client
+                .describe_table()
+                .table_name(&table.name)
+                .send(),
+        ),
+    )
+    .await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+
+    let error = match result {
+        Err(_) => Some(format!("No answer within {} seconds", TIMEOUT.as_secs())),
+        Ok(Err(SdkError::ServiceError { err, .. })) if err.is_resource_not_found_exception() => {
+            Some(format!("Table {} is missing", table.name))
+        }
+        Ok(Err(error)) => {
+            tracing::warn!(table = %table.name, ?error, "Health check failed");
+            Some(format!("Could not describe table {}", table.name))
+        }
+        Ok(Ok(output)) => output.table().map_or(
+            Some(format!("Table {} is missing", table.name)),
+            |description| problem(table, description),
+        ),
+    };
+    Check {
+        status: if error.is_none() { "ok" } else { "down" },
+        latency_ms,
+        error,
+    }
+}
+
+/// What's wrong with a table that exists, if anything.
+fn problem(table: &Table, description: &TableDescription) -> Option<String> {
+    match description.table_status() {
+        Some(TableStatus::Active) | Some(TableStatus::Updating) => {}
+        status => {
+            return Some(format!(
+                "Table {} is {}",
+                table.name,
+                status.map_or("in an unknown state", |status| status.as_str())
+            ))
+        }
+    }
+    let indexes = description.global_secondary_indexes().unwrap_or_default();
+    table.indexes.iter().find_map(|index| {
+        let name = index.name;
+        match indexes
+            .iter()
+            .find(|found| found.index_name() == Some(name))
+            .map(|index| index.index_status())
+        {
+            None => Some(format!("Index {} on {} is missing", name, table.name)),
+            Some(Some(IndexStatus::Active)) | Some(Some(IndexStatus::Updating)) => None,
+            Some(status) => Some(format!(
+                "Index {} on {} is {}",
+                name,
+                table.name,
+                status.map_or("in an unknown state", |status| status.as_str())
+            )),
+        }
+    })
+}
@@ -0,0 +1,458 @@
+use crate::{
+    db::{self, Db},
+    errors::ChatError,
+    limits::Limits,
+};
+use aws_sdk_dynamodb::{
+    model::AttributeValue,
+    types::{Blob, SdkError},
+};
+use axum::{
+    body::{self, Body, Bytes, Full},
+    extract::OriginalUri,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+};
+use http::{
+    header::{ACCEPT, CONTENT_TYPE},
+    request::Parts,
+    HeaderValue, Method, Request, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// DynamoDB's limit on the size of a partition key.
+const MAX_KEY_BYTES: usize = 2048;
+
+/// Idempotency settings.
+///
+/// - `ttl_seconds`: how long a key is remembered. A retry with the same key
+///   within this window gets the original response instead of doing the
+///   request again. Defaults to a day.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    #[serde(rename = "ttl_seconds", with = "crate::config::seconds")]
+    pub ttl: Duration,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Makes a route safe to retry. Clients that send an `Idempotency-Key` header
+/// (any unique string, like a UUID) can send the same request again, say
+/// after a timeout, without doing it twice: the retry gets the response the
+/// first one got, with `Idempotency-Replayed: true`.
+///
+/// Keys are kept per method and path, and a key that comes back with a
+/// different request is turned away with a 422 rather than replayed. That
+/// includes the `Accept` header, so a retry can't get a response in a format
+/// it didn't ask for. Keys aren't tied to the client's IP, since a phone that
+/// retries after switching networks has a new one; they're meant to be random
+/// enough (like a UUID) that nobody else can guess them.
+///
+/// Only successful responses are remembered, so a request that failed can be
+/// retried with the same key. Requests without the header are left alone.
+pub fn idempotent(route: MethodRouter) -> MethodRouter {
+    route.layer(middleware::from_fn(replay))
+}
+
+/// A remembered request, in the idempotency keys table.
+///
+/// The item is put before the request is handled, with no `status`, so that a
+/// retry that comes in while the first one is still going can tell. It gets
+/// the response once there is one, or is deleted if the request failed. Until
+/// then it only lasts as long as the request is allowed to take, so that a key
+/// isn't stuck if the server goes down halfway through.
+async fn replay(request: Request<Body>, next: Next<Body>) -> Result<Response, ChatError> {
+    let key = match request.headers().get(IDEMPOTENCY_KEY) {
+        None => return Ok(next.run(request).await),
+        Some(key) => key
+            .to_str()
+            .ok()
+            .filter(|key| (1..=255).contains(&key.len()))
+            .ok_or_else(|| {
+                ChatError::with_status(
+                    StatusCode::BAD_REQUEST,
+                    "Idempotency-Key has to be 1-255 visible ASCII characters".into(),
+                )
+            })?
+            .to_owned(),
+    };
+    let (dynamodb, ttl, lease) = match (
+        request.extensions().get::<Db>().cloned(),
+        request.extensions().get::<IdempotencyConfig>(),
+        request.extensions().get::<Limits>(),
+    ) {
+        (Some(dynamodb), Some(config), Some(limits)) => {
+            (dynamodb, config.ttl, limits.request_timeout)
+        }
+        _ => return Ok(next.run(request).await),
+    };
+
+    let (parts, request_body) = request.into_parts();
+    let request_body = hyper::body::to_bytes(request_body).await.map_err(|_| {
+        ChatError::with_status(
+            StatusCode::BAD_REQUEST,
+            "Could not read request body".into(),
+        )
+    })?;
+    let fingerprint = fingerprint(&parts, &request_body);
+    let key = stored_key(&parts.method, path(&parts), &key);
+    if key.len() > MAX_KEY_BYTES {
+        return Err(ChatError::with_status(
+            StatusCode::BAD_REQUEST,
+            "The path is too long to use an Idempotency-Key with".into(),
+        ));
+    }
+
+    if !claim(&dynamodb, &key, &fingerprint, lease).await? {
+        let item = get(&dynamodb, &key).await?;
+        return remembered(item, &fingerprint);
+    }
+
+    let response = next
+        .run(Request::from_parts(parts, Body::from(request_body)))
+        .await;
+    // The request has been handled by now, so if the key can't be released
+    // or stored, the client still gets the response. The key stays claimed
+    // until its lease runs out, and retries get a 409 until then.
+    if !response.status().is_success() {
+        if let Err(error) = release(&dynamodb, &key).await {
+            tracing::error!(error = %error.display, debug = ?error.debug, "Could not release an idempotency key");
+        }
+        return Ok(response);
+    }
+
+    let (parts, response_body) = response.into_parts();
+    let response_body = hyper::body::to_bytes(response_body)
+        .await
+        .map_err(|error| {
+            ChatError::new(Some(format!("{:?}", error)), "Internal server error".into())
+        })?;
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    if let Err(error) = store(
+        &dynamodb,
+        &key,
+        ttl,
+        parts.status,
+        &content_type,
+        response_body.clone(),
+    )
+    .await
+    {
+        tracing::error!(error = %error.display, debug = ?error.debug, "Could not remember a response for an idempotency key");
+    }
+
+    Ok(Response::from_parts(
+        parts,
+        body::boxed(Full::from(response_body)),
+    ))
+}
+
+/// The path as the client sent it, prefix and all.
+fn path(parts: &Parts) -> &str {
+    parts
+        .extensions
+        .get::<OriginalUri>()
+        .map_or(&parts.uri, |OriginalUri(uri)| uri)
+        .path()
+}
+
+/// Where a key is kept in the idempotency keys table.
+fn stored_key(method: &Method, path: &str, key: &str) -> String {
+    format!("{} {} {}", method, path, key)
+}
+
+/// What counts as the same request, for a key that comes back: the method,
+/// the path, the formats the client accepts, and the body, which
+/// `limits::limit_body` has already capped.
+fn fingerprint(parts: &Parts, body: &[u8]) -> String {
+    let accept: Vec<_> = parts
+        .headers
+        .get_all(ACCEPT)
+        .iter()
+        .map(|value| String::from_utf8_lossy(value.as_bytes()))
+        .collect();
+    format!(
+        "{} {}\nAccept: {}\n{}",
+        parts.method,
+        path(parts),
+        accept.join(", "),
+        String::from_utf8_lossy(body)
+    )
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Puts a new item for `key`, unless there's already one that hasn't expired.
+/// DynamoDB TTL can take a while to delete expired items, so those are
+/// treated as gone. Returns whether the key was free.
+async fn claim(
+    dynamodb: &Db,
+    key: &str,
+    fingerprint: &str,
+    lease: Duration,
+) -> Result<bool, ChatError> {
+    let now = now();
+    let result = db::call(
+        "PutItem",
+        &dynamodb.tables.idempotency_keys,
+        dynamodb
+            .client
+            .put_item()
+            .table_name(&dynamodb.tables.idempotency_keys)
+            .item("key", AttributeValue::S(key.to_owned()))
+            .item("request", AttributeValue::S(fingerprint.to_owned()))
+            .item(
+                "expires_at",
+                AttributeValue::N((now + lease).as_secs().to_string()),
+            )
+            .condition_expression("attribute_not_exists(#k) OR expires_at < :now")
+            .expression_attribute_names("#k", "key")
+            .expression_attribute_values(":now", AttributeValue::N(now.as_secs().to_string()))
+            .send(),
+    )
+    .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(SdkError::ServiceError { err, .. }) if err.is_conditional_check_failed_exception() => {
+            Ok(false)
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+async fn get(dynamodb: &Db, key: &str) -> Result<HashMap<String, AttributeValue>, ChatError> {
+    let output = db::call(
+        "GetItem",
+        &dynamodb.tables.idempotency_keys,
+        dynamodb
+            .client
+            .get_item()
+            .table_name(&dynamodb.tables.idempotency_keys)
+            .key("key", AttributeValue::S(key.to_owned()))
+            .consistent_read(true)
+            .send(),
+    )
+    .await?;
+
+    // It expired and was deleted in between. Rare enough that the client can
+    // just try again.
+    output.item.ok_or_else(in_progress)
+}
+
+/// The response for a key that was already claimed.
+fn remembered(
+    item: HashMap<String, AttributeValue>,
+    fingerprint: &str,
+) -> Result<Response, ChatError> {
+    if item
+        .get("request")
+        .and_then(|value| value.as_s().ok())
+        .map(String::as_str)
+        != Some(fingerprint)
+    {
+        return Err(ChatError::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Idempotency-Key was already used for a different request".into(),
+        ));
+    }
+    let status = match item.get("status") {
+        None => return Err(in_progress()),
+        Some(status) => StatusCode::from_u16(status.as_n()?.parse()?).map_err(|error| {
+            ChatError::new(Some(format!("{:?}", error)), "Internal server error".into())
+        })?,
+    };
+    let content_type = item
+        .get("content_type")
+        .and_then(|value| value.as_s().ok())
+        .and_then(|value| HeaderValue::from_str(value).ok());
+    let body = match item.get("body") {
+        Some(body) => Bytes::copy_from_slice(body.as_b()?.as_ref()),
+        None => Bytes::new(),
+    };
+
+    let mut response = (status, body).into_response();
+    let headers = response.headers_mut();
+    match content_type {
+        Some(content_type) => headers.insert(CONTENT_TYPE, content_type),
+        None => headers.remove(CONTENT_TYPE),
+    };
+    headers.insert("idempotency-replayed", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+fn in_progress() -> ChatError {
+    ChatError::with_status(
+        StatusCode::CONFLICT,
+        "A request with this Idempotency-Key is still in progress, try again".into(),
+    )
+}
+
+/// Remembers the response for a key, for `ttl` from now.
+async fn store(
+    dynamodb: &Db,
+    key: &str,
+    ttl: Duration,
+    status: StatusCode,
+    content_type: &str,
+    body: Bytes,
+) -> Result<(), ChatError> {
+    db::call(
+        "UpdateItem",
+        &dynamodb.tables.idempotency_keys,
+        dynamodb
+            .client
+            .update_item()
+            .table_name(&dynamodb.tables.idempotency_keys)
+            .key("key", AttributeValue::S(key.to_owned()))
+            .update_expression("SET #s = :s, content_type = :c, #b = :b, expires_at = :e")
+            .expression_attribute_names("#s", "status")
+            .expression_attribute_names("#b", "body")
+            .expression_attribute_values(":s", AttributeValue::N(status.as_u16().to_string()))
+            .expression_attribute_values(":c", AttributeValue::S(content_type.to_owned()))
+            .expression_attribute_values(":b", AttributeValue::B(Blob::new(body.to_vec())))
+            .expression_attribute_values(
+                ":e",
+                AttributeValue::N((now() + ttl).as_secs().to_string()),
+            )
+            .send(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Forgets a key, so that the request can be tried again.
+async fn release(dynamodb: &Db, key: &str) -> Result<(), ChatError> {
+    db::call(
+        "DeleteItem",
+        &dynamodb.tables.idempotency_keys,
+        dynamodb
+            .client
+            .delete_item()
+            .table_name(&dynamodb.tables.idempotency_keys)
+            .key("key", AttributeValue::S(key.to_owned()))
+            .send(),
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(method: Method, uri: &str, accept: Option<&str>) -> Parts {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(accept) = accept {
+            request = request.header(ACCEPT, accept);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    fn item(
+        fingerprint: &str,
+        response: Option<(u16, &str, &str)>,
+    ) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::from([
+            ("key".to_owned(), AttributeValue::S("PUT /rooms 1".into())),
+            ("request".to_owned(), AttributeValue::S(fingerprint.into())),
+        ]);
+        if let Some((status, content_type, body)) = response {
+            item.insert("status".into(), AttributeValue::N(status.to_string()));
+            item.insert(
+                "content_type".into(),
+                AttributeValue::S(content_type.into()),
+            );
+            item.insert("body".into(), AttributeValue::B(Blob::new(body.as_bytes())));
+        }
+        item
+    }
+
+    #[test]
+    fn requests_are_the_same_if_everything_but_the_client_is() {
+        let body = br#"{"name":"Fan club"}"#;
+        let first = fingerprint(&parts(Method::PUT, "/rooms", None), body);
+        let mut retry = parts(Method::PUT, "/rooms", None);
+        retry
+            .headers
+            .insert("x-forwarded-for", "10.0.0.2".parse().unwrap());
+        assert_eq!(fingerprint(&retry, body), first);
+
+        assert_ne!(
+            fingerprint(&parts(Method::PUT, "/rooms", None), b"{}"),
+            first
+        );
+        assert_ne!(
+            fingerprint(&parts(Method::PUT, "/v2/rooms", None), body),
+            first
+        );
+        assert_ne!(
+            fingerprint(
+                &parts(Method::PUT, "/rooms", Some("application/ld+json")),
+                body
+            ),
+            first
+        );
+
+        assert_eq!(stored_key(&Method::PUT, "/rooms", "abc"), "PUT /rooms abc");
+        assert_ne!(
+            stored_key(&Method::PUT, "/v1/rooms", "abc"),
+            stored_key(&Method::PUT, "/rooms", "abc")
+        );
+    }
+
+    #[tokio::test]
+    async fn the_same_request_gets_the_response_replayed() {
+        let item = item(
+            "PUT /rooms\n{}",
+            Some((200, "application/ld+json", r#"{"@id":"/rooms/1"}"#)),
+        );
+        let response = remembered(item, "PUT /rooms\n{}").ok().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["idempotency-replayed"], "true");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/ld+json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], br#"{"@id":"/rooms/1"}"#);
+    }
+
+    #[test]
+    fn a_request_still_going_is_a_conflict() {
+        let error = remembered(item("PUT /rooms\n{}", None), "PUT /rooms\n{}")
+            .err()
+            .unwrap();
+        assert_eq!(error.status, StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn a_different_request_with_the_same_key_is_unprocessable() {
+        for response in [None, Some((200, "application/json", "{}"))] {
+            let error = remembered(item("PUT /rooms\n{}", response), "PUT /rooms\n[]")
+                .err()
+                .unwrap();
+            assert_eq!(error.status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+}
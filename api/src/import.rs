@@ -0,0 +1,1058 @@
+use crate::{
+    admin,
+    config::Secret,
+    db::{self, Db},
+    errors::{ChatError, FieldError},
+    validation,
+};
+use aws_sdk_dynamodb::model::AttributeValue;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+// Imports chat history from other chat apps' exports. An export is first read
+// into channels of messages (`read`), and then written out (`import`): each
+// channel becomes the room with the same name, each author the user with the
+// same name, both created if they don't exist yet, and each message keeps
+// the time it was originally sent as its sort key.
+//
+// Importing the same export again only writes what's missing, so an import
+// that crashed or timed out partway through is finished by running it again.
+
+/// Where the admin endpoint for imports lives. Uploads to it are allowed to be
+/// bigger than other request bodies, see `Limits::max_import_bytes`.
+pub const IMPORTS_PATH: &str = "/admin/imports";
+
+/// At most this many problems are listed in a report, the rest are counted.
+const MAX_PROBLEMS: usize = 100;
+
+/// At most this many messages can be sent at exactly the same time in a room,
+/// see `import_message`.
+const MAX_SAME_TIME: u32 = 1000;
+
+/// How much a Slack export can unzip to, so that a small zip can't unzip to
+/// more than the server has memory for. Each JSON file in it can be at most
+/// `file` bytes, and all of them together `total`.
+const MAX_UNZIPPED: Unzipped = Unzipped {
+    file: 64 * 1024 * 1024,
+    total: 256 * 1024 * 1024,
+};
+
+#[derive(Clone, Copy, Debug, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// The zip from Slack's "Export data" in the workspace settings.
+    Slack,
+    /// A channel exported as JSON by DiscordChatExporter.
+    Discord,
+}
+
+impl Source {
+    pub fn parse(field: &'static str, source: &str) -> Result<Self, FieldError> {
+        match source {
+            "slack" => Ok(Source::Slack),
+            "discord" => Ok(Source::Discord),
+            _ => Err(FieldError::new(
+                field,
+                &format!("{:?} isn't slack or discord", source),
+            )),
+        }
+    }
+}
+
+/// A channel from an export, with its messages oldest first.
+pub struct Channel {
+    pub name: String,
+    pub messages: Vec<Message>,
+}
+
+pub struct Message {
+    /// The author's name, as the other app showed it.
+    pub author: String,
+    pub time: DateTime<Utc>,
+    pub text: String,
+}
+
+/// How an import went. While an import is running, these are the counts so
+/// far.
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct Report {
+    pub rooms_created: usize,
+    pub users_created: usize,
+    /// Messages written by this import.
+    pub messages_imported: usize,
+    /// Messages that an earlier import of the same export already wrote.
+    pub messages_already_imported: usize,
+    /// Messages that can't be imported, like ones that are too long, or by
+    /// an author whose name can't be used here.
+    pub messages_rejected: usize,
+    /// Why messages or channels were rejected, up to 100 of them.
+    pub problems: Vec<String>,
+}
+
+impl Report {
+    fn problem(&mut self, problem: String) {
+        if self.problems.len() < MAX_PROBLEMS {
+            self.problems.push(problem);
+        }
+    }
+}
+
+/// Reads an export into channels. This is all in memory, and takes a while
+/// for a big export, so the API runs it on a blocking thread.
+pub fn read(source: Source, export: &[u8]) -> Result<Vec<Channel>, ChatError> {
+    match source {
+        Source::Slack => read_slack(export, MAX_UNZIPPED),
+        Source::Discord => read_discord(export),
+    }
+}
+
+/// Writes channels out as rooms, users and messages, and says how it went.
+/// `progress` is called after each channel with the counts so far.
+pub async fn import<F: Fn(&str, &Report)>(
+    dynamodb: &Db,
+    channels: Vec<Channel>,
+    progress: F,
+) -> Result<Report, ChatError> {
+    let mut report = Report::default();
+    // Author name to user ID and name, or `None` for authors whose name
+    // can't be used.
+    let mut users: HashMap<String, Option<(String, String)>> = HashMap::new();
+
+    for channel in channels {
+        let name = match validation::name("name", &channel.name) {
+            Ok(name) => name,
+            Err(error) => {
+                report.messages_rejected += channel.messages.len();
+                report.problem(format!(
+                    "Channel {:?} can't be a room: {}",
+                    channel.name, error.message
+                ));
+                continue;
+            }
+        };
+        let room_id = match admin::room_by_name(dynamodb, &name).await? {
+            Some(room_id) => room_id,
+            None => {
+                let room_id = crate::uuid();
+                db::create_room(dynamodb, &room_id, &name).await?;
+                report.rooms_created += 1;
+                room_id
+            }
+        };
+
+        let mut imported = 0;
+        for message in channel.messages {
+            let user = match users.get(&message.author) {
+                Some(user) => user.clone(),
+                None => {
+                    let user = user(dynamodb, &message.author, &mut report).await?;
+                    users.insert(message.author.clone(), user.clone());
+                    user
+                }
+            };
+            let (user_id, user_name) = match user {
+                Some(user) => user,
+                None => {
+                    report.messages_rejected += 1;
+                    continue;
+                }
+            };
+            let text = match validation::message("message", &message.text) {
+                Ok(text) => text,
+                Err(error) => {
+                    report.messages_rejected += 1;
+                    report.problem(format!(
+                        "Message by {} in {} at {} can't be imported: {}",
+                        message.author,
+                        channel.name,
+                        message.time.to_rfc3339(),
+                        error.message
+                    ));
+                    continue;
+                }
+            };
+            if import_message(
+                dynamodb,
+                &room_id,
+                message.time,
+                &text,
+                &user_id,
+                &user_name,
+            )
+            .await?
+            {
+                report.messages_imported += 1;
+                imported += 1;
+            } else {
+                report.messages_already_imported += 1;
+            }
+        }
+
+        // Puts the room on the list, even if a crash kept an earlier import
+        // from getting this far.
+        if imported > 0 {
+            db::rewrite_room_history(dynamodb, &room_id).await?;
+        }
+        db::bump_room(dynamodb, &room_id).await?;
+        progress(&name, &report);
+    }
+    Ok(report)
+}
+
+/// Finds the user an author's messages go to, by name, and creates them if
+/// there's nobody by that name yet.
+async fn user(
+    dynamodb: &Db,
+    author: &str,
+    report: &mut Report,
+) -> Result<Option<(String, String)>, ChatError> {
+    let name = match validation::name("name", author) {
+        Ok(name) => name,
+        Err(error) => {
+            report.problem(format!(
+                "{:?} can't be a user, so their messages are left out: {}",
+                author, error.message
+            ));
+            return Ok(None);
+        }
+    };
+    let (user_id, name, created) =
+        db::find_or_create_user(dynamodb, &name, &validation::name_key(&name)).await?;
+    if created {
+        report.users_created += 1;
+    }
+    Ok(Some((user_id, name)))
+}
+
+/// Writes a message at the time it was sent, unless it's already there.
+/// Returns whether it was written.
+///
+/// A message's time is its key, so two messages sent at the same time (to
+/// the precision the export has) would be one message. When the time is
+/// taken by another message, the message is moved a nanosecond later, until
+/// it finds a free time or itself. Messages are always imported in the same
+/// order, so they end up at the same times every time.
+async fn import_message(
+    dynamodb: &Db,
+    room_id: &str,
+    time: DateTime<Utc>,
+    text: &str,
+    user_id: &str,
+    user_name: &str,
+) -> Result<bool, ChatError> {
+    for date_time in free_times(time) {
+        if db::post_message_if_new(dynamodb, room_id, text, user_id, user_name, &date_time).await? {
+            return Ok(true);
+        }
+        let existing = db::get_message_by_id(dynamodb, room_id, &date_time).await?;
+        let field = |key: &str| {
+            existing
+                .get(key)
+                .and_then(|value: &AttributeValue| value.as_s().or_else(|_| value.as_n()).ok())
+                .map(String::as_str)
+        };
+        if field("sender_id") == Some(user_id) && field("message") == Some(text) {
+            return Ok(false);
+        }
+    }
+    Err(ChatError::new(
+        Some(format!(
+            "More than {} messages at {} in room {}",
+            MAX_SAME_TIME, time, room_id
+        )),
+        "Too many messages were sent at the same time".into(),
+    ))
+}
+
+/// The times a message sent at `time` is tried at, in order: its own, then a
+/// nanosecond later each time. There are fewer than a microsecond's worth, so
+/// they never reach the time of a message sent after it.
+fn free_times(time: DateTime<Utc>) -> impl Iterator<Item = String> {
+    (0..MAX_SAME_TIME)
+        .map(move |nanoseconds| (time + Duration::nanoseconds(nanoseconds.into())).to_rfc3339())
+}
+
+// Slack
+
+#[derive(Deserialize)]
+struct SlackUser {
+    id: String,
+    name: String,
+    #[serde(default)]
+    real_name: Option<String>,
+    #[serde(default)]
+    profile: SlackProfile,
+}
+
+#[derive(Default, Deserialize)]
+struct SlackProfile {
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    real_name: String,
+}
+
+#[derive(Deserialize)]
+struct SlackChannel {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct SlackMessage {
+    #[serde(default)]
+    subtype: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    user_profile: Option<SlackProfile>,
+    #[serde(default)]
+    text: String,
+    ts: String,
+    #[serde(default)]
+    files: Vec<SlackFile>,
+}
+
+#[derive(Deserialize)]
+struct SlackFile {
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// Reads a Slack export: `users.json`, `channels.json` (and `groups.json`
+/// for private channels, if the export has them), and a folder for each
+/// channel with a JSON file of messages for each day.
+///
+/// Only messages people wrote are read. Joins, topic changes, bots and the
+/// like are left out.
+fn read_slack(export: &[u8], mut unzipped: Unzipped) -> Result<Vec<Channel>, ChatError> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(export))
+        .map_err(|error| invalid_export(format!("The Slack export isn't a zip file: {}", error)))?;
+
+    let users: Vec<SlackUser> = match zip_json(&mut zip, "users.json", &mut unzipped)? {
+        Some(users) => users,
+        None => return Err(invalid_export("The Slack export has no users.json".into())),
+    };
+    let names: HashMap<String, String> = users
+        .into_iter()
+        .map(|user| {
+            let name = slack_name(&user.profile, user.real_name.as_deref(), &user.name);
+            (user.id, name)
+        })
+        .collect();
+
+    let mut slack_channels: Vec<SlackChannel> =
+        match zip_json(&mut zip, "channels.json", &mut unzipped)? {
+            Some(channels) => channels,
+            None => {
+                return Err(invalid_export(
+                    "The Slack export has no channels.json".into(),
+                ))
+            }
+        };
+    slack_channels.extend(
+        zip_json::<Vec<SlackChannel>>(&mut zip, "groups.json", &mut unzipped)?.unwrap_or_default(),
+    );
+
+    let mut files: Vec<String> = zip.file_names().map(str::to_owned).collect();
+    files.sort();
+    let mut channels = Vec::new();
+    for slack_channel in slack_channels {
+        let folder = format!("{}/", slack_channel.name);
+        let mut messages = Vec::new();
+        for file in files
+            .iter()
+            .filter(|file| file.starts_with(&folder) && file.ends_with(".json"))
+        {
+            let day: Vec<SlackMessage> =
+                zip_json(&mut zip, file, &mut unzipped)?.unwrap_or_default();
+            for message in day {
+                let by_person = matches!(
+                    message.subtype.as_deref(),
+                    None | Some("me_message") | Some("thread_broadcast") | Some("file_share")
+                );
+                let user = match (by_person, &message.user) {
+                    (true, Some(user)) => user,
+                    _ => continue,
+                };
+                let author = match (names.get(user), &message.user_profile) {
+                    (Some(name), _) => name.clone(),
+                    (None, Some(profile)) => slack_name(profile, None, user),
+                    (None, None) => user.clone(),
+                };
+                let time = slack_time(&message.ts).ok_or_else(|| {
+                    invalid_export(format!(
+                        "{}: {:?} isn't a Slack timestamp",
+                        file, message.ts
+                    ))
+                })?;
+                let mut text = slack_text(&message.text, &names);
+                for file in &message.files {
+                    if let Some(name) = &file.name {
+                        if !text.is_empty() {
+                            text.push('\n');
+                        }
+                        text.push_str(&format!("[file: {}]", name));
+                    }
+                }
+                messages.push(Message { author, time, text });
+            }
+        }
+        messages.sort_by_key(|message| message.time);
+        channels.push(Channel {
+            name: slack_channel.name,
+            messages,
+        });
+    }
+    Ok(channels)
+}
+
+/// The name Slack shows for somebody: their display name, or their full
+/// name if they didn't pick one, or their username.
+fn slack_name(profile: &SlackProfile, real_name: Option<&str>, username: &str) -> String {
+    [
+        profile.display_name.as_str(),
+        profile.real_name.as_str(),
+        real_name.unwrap_or_default(),
+        username,
+    ]
+    .into_iter()
+    .find(|name| !name.trim().is_empty())
+    .unwrap_or(username)
+    .to_owned()
+}
+
+/// Slack timestamps are seconds since the epoch with microseconds after the
+/// dot, like `1614000000.000200`.
+fn slack_time(ts: &str) -> Option<DateTime<Utc>> {
+    let (seconds, micros) = ts.split_once('.').unwrap_or((ts, "0"));
+    let micros = format!("{:0<6}", micros);
+    Utc.timestamp_opt(
+        seconds.parse().ok()?,
+        micros.get(..6)?.parse::<u32>().ok()? * 1000,
+    )
+    .single()
+}
+
+/// Turns Slack's markup into what a person would have seen: `<@U123>`
+/// mentions become `@name`, `<#C123|general>` becomes `#general`, links
+/// become their URL (and label), and `&lt;`, `&gt;` and `&amp;` are
+/// unescaped.
+fn slack_text(text: &str, names: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        let inner = &rest[start + 1..end];
+        let (target, label) = match inner.split_once('|') {
+            Some((target, label)) => (target, Some(label)),
+            None => (inner, None),
+        };
+        match (target.chars().next(), label) {
+            (Some('@'), _) => {
+                let id = &target[1..];
+                let name = names.get(id).map(String::as_str).or(label).unwrap_or(id);
+                out.push_str(&format!("@{}", name));
+            }
+            (Some('#'), Some(label)) => out.push_str(&format!("#{}", label)),
+            (Some('!'), Some(label)) => out.push_str(label),
+            (Some('!'), None) => out.push_str(&format!("@{}", &target[1..])),
+            (_, Some(label)) if label != target => out.push_str(&format!("{} ({})", label, target)),
+            _ => out.push_str(target),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// How many bytes can still be unzipped, see `MAX_UNZIPPED`.
+#[derive(Clone, Copy)]
+struct Unzipped {
+    file: u64,
+    total: u64,
+}
+
+/// Reads a JSON file out of a zip, or `None` if the zip doesn't have it. What
+/// it unzips to counts against `unzipped`, and it's rejected as soon as it
+/// goes over, without reading the rest.
+fn zip_json<T: serde::de::DeserializeOwned>(
+    zip: &mut zip::ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+    unzipped: &mut Unzipped,
+) -> Result<Option<T>, ChatError> {
+    let file = match zip.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(error) => return Err(invalid_export(format!("{}: {}", name, error))),
+    };
+    let limit = unzipped.file.min(unzipped.total);
+    let mut contents = Vec::new();
+    file.take(limit + 1)
+        .read_to_end(&mut contents)
+        .map_err(|error| invalid_export(format!("{}: {}", name, error)))?;
+    if contents.len() as u64 > limit {
+        return Err(invalid_export(if limit == unzipped.file {
+            format!(
+                "{}: unzips to more than {} bytes, the most a file can",
+                name, limit
+            )
+        } else {
+            format!(
+                "{}: unzips to more than the {} bytes the export has left to unzip to",
+                name, limit
+            )
+        }));
+    }
+    unzipped.total -= contents.len() as u64;
+    serde_json::from_slice(&contents)
+        .map(Some)
+        .map_err(|error| invalid_export(format!("{}: {}", name, error)))
+}
+
+// Discord
+
+#[derive(Deserialize)]
+struct DiscordExport {
+    channel: DiscordChannel,
+    messages: Vec<DiscordMessage>,
+}
+
+#[derive(Deserialize)]
+struct DiscordChannel {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscordMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    timestamp: String,
+    #[serde(default)]
+    content: String,
+    author: DiscordAuthor,
+    #[serde(default)]
+    attachments: Vec<DiscordAttachment>,
+}
+
+#[derive(Deserialize)]
+struct DiscordAuthor {
+    name: String,
+    #[serde(default)]
+    nickname: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscordAttachment {
+    file_name: String,
+}
+
+/// Reads a channel exported by DiscordChatExporter in its JSON format. Only
+/// messages people wrote (and replies) are read, not pins, joins and calls.
+fn read_discord(export: &[u8]) -> Result<Vec<Channel>, ChatError> {
+    let export: DiscordExport = serde_json::from_slice(export)
+        .map_err(|error| invalid_export(format!("The Discord export can't be read: {}", error)))?;
+    let mut messages = Vec::new();
+    for message in export.messages {
+        if !matches!(message.kind.as_str(), "Default" | "Reply") {
+            continue;
+        }
+        let time = DateTime::parse_from_rfc3339(&message.timestamp)
+            .map_err(|_| {
+                invalid_export(format!(
+                    "{:?} isn't an RFC 3339 timestamp",
+                    message.timestamp
+                ))
+            })?
+            .with_timezone(&Utc);
+        let mut text = message.content;
+        for attachment in &message.attachments {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&format!("[file: {}]", attachment.file_name));
+        }
+        let author = message
+            .author
+            .nickname
+            .filter(|nickname| !nickname.trim().is_empty())
+            .unwrap_or(message.author.name);
+        messages.push(Message { author, time, text });
+    }
+    messages.sort_by_key(|message| message.time);
+    Ok(vec![Channel {
+        name: export.channel.name,
+        messages,
+    }])
+}
+
+fn invalid_export(display: String) -> ChatError {
+    ChatError::with_status(StatusCode::BAD_REQUEST, display)
+}
+
+// The admin endpoint
+
+/// Checks the `Authorization: Bearer <token>` header of a request to an
+/// admin endpoint. Without an `admin_token` in the config, the admin
+/// endpoints are off and act like they aren't there.
+pub fn authorize(token: Option<&Secret>, headers: &HeaderMap) -> Result<(), ChatError> {
+    let token = match token {
+        Some(token) => token,
+        None => {
+            return Err(ChatError::with_status(
+                StatusCode::NOT_FOUND,
+                "Not found".into(),
+            ))
+        }
+    };
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // Compares every byte, so how long it takes doesn't give the token away.
+    let matches = given.len() == token.expose().len()
+        && given
+            .bytes()
+            .zip(token.expose().bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0;
+    if matches {
+        Ok(())
+    } else {
+        Err(ChatError::with_status(
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized".into(),
+        ))
+    }
+}
+
+/// An import started through the admin endpoint.
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Job {
+    /// Where to check on the import.
+    pub id: String,
+    pub source: Source,
+    /// `running`, `finished` or `failed`.
+    pub status: &'static str,
+    /// Why it failed, if it did. Importing the same export again picks up
+    /// where it stopped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub report: Report,
+}
+
+/// How long an import is kept around to be looked at after it's finished.
+const JOB_TTL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Imports started through the admin endpoint, which run in the background
+/// since they take longer than a request can.
+///
+/// Like presence, this is kept in memory, so each replica only knows about
+/// the imports it's running, and a restart forgets them. An import that was
+/// cut off by a restart can just be started again. Finished imports are
+/// forgotten after `JOB_TTL`.
+#[derive(Clone, Default)]
+pub struct Imports {
+    jobs: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+struct Entry {
+    job: Job,
+    finished_at: Option<Instant>,
+}
+
+impl Imports {
+    /// Starts importing `channels` in the background, and returns the job,
+    /// which `get` finds by `import_id` from then on.
+    pub fn start(
+        &self,
+        dynamodb: Db,
+        import_id: String,
+        uri: String,
+        source: Source,
+        channels: Vec<Channel>,
+    ) -> Job {
+        self.sweep(Instant::now());
+        let id = import_id;
+        let job = Job {
+            id: uri,
+            source,
+            status: "running",
+            error: None,
+            report: Report::default(),
+        };
+        self.jobs.lock().unwrap().insert(
+            id.clone(),
+            Entry {
+                job: job.clone(),
+                finished_at: None,
+            },
+        );
+
+        let span = tracing::info_span!("import", import_id = %id);
+        let jobs = self.jobs.clone();
+        let update = move |update: &dyn Fn(&mut Job)| {
+            if let Some(entry) = jobs.lock().unwrap().get_mut(&id) {
+                update(&mut entry.job);
+                if entry.job.status != "running" {
+                    entry.finished_at.get_or_insert_with(Instant::now);
+                }
+            }
+        };
+        tokio::spawn(
+            async move {
+                let result = import(&dynamodb, channels, |_, report| {
+                    update(&|job| job.report = report.clone())
+                })
+                .await;
+                match result {
+                    Ok(report) => update(&|job| {
+                        job.status = "finished";
+                        job.report = report.clone();
+                    }),
+                    Err(error) => {
+                        tracing::error!(
+                            error = %error.display,
+                            debug = ?error.debug,
+                            "Import failed partway through"
+                        );
+                        update(&|job| {
+                            job.status = "failed";
+                            job.error = Some(error.display.clone());
+                        })
+                    }
+                }
+            }
+            .instrument(span),
+        );
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.sweep(Instant::now());
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|entry| entry.job.clone())
+    }
+
+    /// Forgets imports that finished more than `JOB_TTL` before `now`.
+    fn sweep(&self, now: Instant) {
+        self.jobs.lock().unwrap().retain(|_, entry| {
+            entry
+                .finished_at
+                .is_none_or(|finished_at| now.saturating_duration_since(finished_at) < JOB_TTL)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    /// A zip of `files`, compressed like Slack's are.
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn slack_exports_cant_unzip_to_too_much() {
+        let limit = Unzipped {
+            file: 1000,
+            total: 1500,
+        };
+        let spaces = |count| format!("[{}]", " ".repeat(count)).into_bytes();
+
+        let export = zip(&[("users.json", &spaces(10)), ("channels.json", &spaces(10))]);
+        assert!(matches!(read_slack(&export, limit), Ok(channels) if channels.is_empty()));
+
+        // One file that's too big on its own.
+        let export = zip(&[
+            ("users.json", &spaces(1000)),
+            ("channels.json", &spaces(10)),
+        ]);
+        let error = read_slack(&export, limit).err().unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert!(
+            error.display.starts_with("users.json:"),
+            "{}",
+            error.display
+        );
+
+        // Files that are fine on their own, but too big together.
+        let export = zip(&[
+            ("users.json", &spaces(900)),
+            ("channels.json", &spaces(900)),
+        ]);
+        let error = read_slack(&export, limit).err().unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert!(
+            error.display.starts_with("channels.json:"),
+            "{}",
+            error.display
+        );
+    }
+
+    fn time(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn slack_times_are_read_to_the_microsecond() {
+        let read = |ts| slack_time(ts).map(|time| time.to_rfc3339());
+        assert_eq!(
+            read("1614000000.000200").as_deref(),
+            Some("2021-02-22T13:20:00.000200+00:00")
+        );
+        assert_eq!(
+            read("1614000000").as_deref(),
+            Some("2021-02-22T13:20:00+00:00")
+        );
+        // Short micros are tenths, hundredths and so on, not micros.
+        assert_eq!(
+            read("1614000000.2").as_deref(),
+            Some("2021-02-22T13:20:00.200+00:00")
+        );
+        assert_eq!(
+            read("1614000000.0002009").as_deref(),
+            Some("2021-02-22T13:20:00.000200+00:00")
+        );
+        assert_eq!(read(""), None);
+        assert_eq!(read("yesterday"), None);
+        assert_eq!(read("1614000000.abc"), None);
+    }
+
+    #[test]
+    fn slack_markup_is_what_people_saw() {
+        let names = HashMap::from([("U1".to_owned(), "alice".to_owned())]);
+        let text = |markup| slack_text(markup, &names);
+        assert_eq!(text("hi <@U1>"), "hi @alice");
+        assert_eq!(text("<@U2|bob> left"), "@bob left");
+        assert_eq!(text("<@U3>"), "@U3");
+        assert_eq!(text("see <#C1|general>"), "see #general");
+        assert_eq!(text("<!here> <!channel>"), "@here @channel");
+        assert_eq!(text("<!subteam^S1|@team>"), "@team");
+        assert_eq!(text("<https://example.com>"), "https://example.com");
+        assert_eq!(
+            text("<https://example.com|the site>"),
+            "the site (https://example.com)"
+        );
+        assert_eq!(
+            text("<https://example.com|https://example.com>"),
+            "https://example.com"
+        );
+        assert_eq!(text("1 &lt; 2 &amp;&amp; 3 &gt; 2"), "1 < 2 && 3 > 2");
+        // Unescaped once, like Slack does.
+        assert_eq!(text("&amp;lt;"), "&lt;");
+        assert_eq!(text("a < b"), "a < b");
+        assert_eq!(text("<@U1> < b"), "@alice < b");
+    }
+
+    #[test]
+    fn slack_exports_are_read() {
+        let export = zip(&[
+            (
+                "users.json",
+                br#"[
+                    {"id": "U1", "name": "alice", "profile": {"display_name": "Alice"}},
+                    {"id": "U2", "name": "bob", "real_name": "Bob Smith", "profile": {}}
+                ]"#,
+            ),
+            ("channels.json", br#"[{"name": "general"}]"#),
+            ("groups.json", br#"[{"name": "secret"}]"#),
+            (
+                "general/2021-02-23.json",
+                br#"[
+                    {"user": "U1", "text": "later", "ts": "1614100000.000000"}
+                ]"#,
+            ),
+            (
+                "general/2021-02-22.json",
+                br#"[
+                    {"user": "U1", "subtype": "channel_join", "text": "joined", "ts": "1614000000.000000"},
+                    {"subtype": "bot_message", "text": "beep", "ts": "1614000001.000000"},
+                    {"user": "U2", "text": "hi <@U1>", "ts": "1614000002.000000"},
+                    {"user": "U9", "user_profile": {"real_name": "Carol"}, "text": "", "ts": "1614000003.000000",
+                     "subtype": "file_share", "files": [{"name": "cat.png"}]}
+                ]"#,
+            ),
+            (
+                "secret/2021-02-22.json",
+                br#"[{"user": "U9", "text": "psst", "ts": "1614000000.000000"}]"#,
+            ),
+        ]);
+        let channels = read_slack(&export, MAX_UNZIPPED).ok().unwrap();
+        let read: Vec<_> = channels
+            .iter()
+            .map(|channel| {
+                let messages: Vec<_> = channel
+                    .messages
+                    .iter()
+                    .map(|message| (message.author.as_str(), message.text.as_str()))
+                    .collect();
+                (channel.name.as_str(), messages)
+            })
+            .collect();
+        assert_eq!(
+            read,
+            [
+                (
+                    "general",
+                    vec![
+                        ("Bob Smith", "hi @Alice"),
+                        ("Carol", "[file: cat.png]"),
+                        ("Alice", "later"),
+                    ]
+                ),
+                ("secret", vec![("U9", "psst")]),
+            ]
+        );
+        assert_eq!(channels[0].messages[0].time, time("2021-02-22T13:20:02Z"));
+
+        let bad_ts = zip(&[
+            ("users.json", b"[]"),
+            ("channels.json", br#"[{"name": "general"}]"#),
+            (
+                "general/2021-02-22.json",
+                br#"[{"user": "U1", "ts": "soon"}]"#,
+            ),
+        ]);
+        let error = read_slack(&bad_ts, MAX_UNZIPPED).err().unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+
+        let no_users = zip(&[("channels.json", b"[]")]);
+        assert!(read_slack(&no_users, MAX_UNZIPPED).is_err());
+        assert!(read_slack(b"not a zip", MAX_UNZIPPED).is_err());
+    }
+
+    #[test]
+    fn discord_exports_are_read() {
+        let export = br#"{
+            "guild": {"name": "Server"},
+            "channel": {"name": "general"},
+            "messages": [
+                {"type": "Default", "timestamp": "2021-02-22T14:20:05.123+01:00", "content": "second",
+                 "author": {"name": "bob", "nickname": "  "}},
+                {"type": "Default", "timestamp": "2021-02-22T13:20:00+00:00", "content": "first",
+                 "author": {"name": "alice", "nickname": "Alice"}},
+                {"type": "GuildMemberJoin", "timestamp": "2021-02-22T13:20:01+00:00", "content": "",
+                 "author": {"name": "carol"}},
+                {"type": "Reply", "timestamp": "2021-02-22T13:20:06+00:00", "content": "look",
+                 "author": {"name": "carol"}, "attachments": [{"fileName": "cat.png"}]}
+            ]
+        }"#;
+        let channels = read_discord(export).ok().unwrap();
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].name, "general");
+        let read: Vec<_> = channels[0]
+            .messages
+            .iter()
+            .map(|message| (message.author.as_str(), message.text.as_str(), message.time))
+            .collect();
+        assert_eq!(
+            read,
+            [
+                ("Alice", "first", time("2021-02-22T13:20:00Z")),
+                ("bob", "second", time("2021-02-22T13:20:05.123Z")),
+                (
+                    "carol",
+                    "look\n[file: cat.png]",
+                    time("2021-02-22T13:20:06Z")
+                ),
+            ]
+        );
+
+        let bad_time = br#"{"channel": {"name": "general"}, "messages": [
+            {"type": "Default", "timestamp": "yesterday", "author": {"name": "bob"}}
+        ]}"#;
+        let error = read_discord(bad_time).err().unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        let error = read_discord(b"{}").err().unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+    }
+
+    /// Messages sent at the same time are moved apart without passing the
+    /// next message, wherever in the second they were sent.
+    #[test]
+    fn messages_at_the_same_time_stay_in_order() {
+        for sent in [
+            "2021-02-22T13:20:00Z",
+            "2021-02-22T13:20:00.2Z",
+            "2021-02-22T13:20:00.000200Z",
+            "2021-02-22T13:20:00.999999Z",
+        ] {
+            let sent = time(sent);
+            let next = (sent + Duration::microseconds(1)).to_rfc3339();
+            let times: Vec<_> = free_times(sent).collect();
+            assert_eq!(times.len(), MAX_SAME_TIME as usize);
+            assert_eq!(times[0], sent.to_rfc3339());
+            for pair in times.windows(2) {
+                assert!(pair[0] < pair[1], "{} isn't before {}", pair[0], pair[1]);
+            }
+            assert!(times[times.len() - 1] < next);
+        }
+    }
+
+    #[test]
+    fn finished_imports_are_forgotten() {
+        let imports = Imports::default();
+        let now = Instant::now();
+        let job = |status| Job {
+            id: String::new(),
+            source: Source::Slack,
+            status,
+            error: None,
+            report: Report::default(),
+        };
+        let mut jobs = imports.jobs.lock().unwrap();
+        jobs.insert(
+            "running".into(),
+            Entry {
+                job: job("running"),
+                finished_at: None,
+            },
+        );
+        jobs.insert(
+            "finished".into(),
+            Entry {
+                job: job("finished"),
+                finished_at: Some(now),
+            },
+        );
+        drop(jobs);
+
+        imports.sweep(now + JOB_TTL / 2);
+        assert!(imports.jobs.lock().unwrap().contains_key("finished"));
+        imports.sweep(now + JOB_TTL);
+        assert!(!imports.jobs.lock().unwrap().contains_key("finished"));
+        assert!(imports.get("running").is_some());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{
+    base_url::BaseUrl,
+    errors::ChatError,
+    versioning::{RequestedVersion, Version},
+};
+use axum::{
+    async_trait,
+    extract::{FromRequest, RequestParts},
+    response::{Headers, IntoResponse, Response},
+    Json,
+};
+use http::{
+    header::{ACCEPT, CONTENT_TYPE, VARY},
+    HeaderValue, StatusCode,
+};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+pub const JSON: &str = "application/json";
+pub const JSON_LD: &str = "application/ld+json";
+
+/// Where the JSON-LD context is served, relative to the base URL.
+pub const CONTEXT_PATH: &str = "/context.jsonld";
+
+/// Something that can be written out as a JSON-LD node. Its plain JSON form is
+/// whatever `Serialize` makes of it.
+pub trait JsonLd: Serialize {
+    fn to_json_ld(&self) -> Value;
+}
+
+/// The `@type` of a kind of object.
+pub trait LdType {
+    const TYPE: &'static str;
+}
+
+/// The representation a client asked for in its `Accept` header.
+///
+/// In v1, plain JSON is the default, so that clients that don't say what they
+/// want (or say `*/*`) keep getting what they always got. JSON-LD is only sent
+/// to clients that prefer `application/ld+json` over `application/json`. In v2
+/// it's the other way around.
+pub enum Format {
+    Json,
+    JsonLd { context: String },
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Format {
+    type Rejection = ChatError;
+
+    async fn from_request(request: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let accept: Vec<(String, f32)> = request
+            .headers()
+            .map(|headers| {
+                headers
+                    .get_all(ACCEPT)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .filter_map(media_range)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let version = request
+            .extensions()
+            .and_then(|extensions| extensions.get::<RequestedVersion>())
+            .map_or(Version::V1, |requested| requested.version);
+
+        let (json, json_ld) = if accept.is_empty() {
+            (1.0, 1.0)
+        } else {
+            (quality(&accept, JSON), quality(&accept, JSON_LD))
+        };
+        let prefer_json_ld = match version {
+            Version::V1 => json_ld > json,
+            Version::V2 => json_ld > 0.0 && json_ld >= json,
+        };
+        if prefer_json_ld {
+            let BaseUrl(base_url) = BaseUrl::from_request(request).await?;
+            Ok(Format::JsonLd {
+                context: format!("{}{}", base_url, CONTEXT_PATH),
+            })
+        } else if json > 0.0 {
+            Ok(Format::Json)
+        } else {
+            Err(ChatError::with_status(
+                StatusCode::NOT_ACCEPTABLE,
+                format!("Responses are either {} or {}", JSON, JSON_LD),
+            ))
+        }
+    }
+}
+
+/// Splits `type/subtype;q=0.5` into the lowercased media range and its
+/// quality, which defaults to 1.
+fn media_range(value: &str) -> Option<(String, f32)> {
+    let mut parts = value.split(';');
+    let range = parts.next()?.trim().to_ascii_lowercase();
+    if range.is_empty() {
+        return None;
+    }
+    let q = parts
+        .filter_map(|parameter| parameter.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+        .and_then(|(_, q)| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+    Some((range, q))
+}
+
+/// How much a client wants `media_type`, going by the most specific range
+/// that matches it.
+fn quality(accept: &[(String, f32)], media_type: &str) -> f32 {
+    let (kind, _) = media_type.split_once('/').unwrap_or_default();
+    let specificity = |range: &str| {
+        if range == media_type {
+            Some(2)
+        } else if range.strip_suffix("/*") == Some(kind) {
+            Some(1)
+        } else if range == "*/*" {
+            Some(0)
+        } else {
+            None
+        }
+    };
+    accept
+        .iter()
+        .filter_map(|(range, q)| specificity(range).map(|specificity| (specificity, *q)))
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, q)| q)
+        .unwrap_or(0.0)
+}
+
+/// A response in whichever `Format` the client asked for.
+pub struct Negotiated<T: JsonLd>(pub Format, pub T);
+
+impl<T: JsonLd> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        let Negotiated(format, body) = self;
+        let mut response = match format {
+            Format::Json => Json(body).into_response(),
+            Format::JsonLd { context } => {
+                let mut node = body.to_json_ld();
+                if let Value::Object(node) = &mut node {
+                    node.insert("@context".into(), Value::String(context));
+                }
+                let mut response = Json(node).into_response();
+                response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static(JSON_LD));
+                response
+            }
+        };
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("accept"));
+        response
+    }
+}
+
+/// A node with an `@id` and `@type`, and its properties next to them.
+pub fn node(id: &str, kind: &str, properties: &impl Serialize) -> Value {
+    let mut node = match serde_json::to_value(properties) {
+        Ok(Value::Object(properties)) => properties,
+        _ => Map::new(),
+    };
+    node.insert("@id".into(), Value::String(id.to_owned()));
+    node.insert("@type".into(), Value::String(kind.to_owned()));
+    Value::Object(node)
+}
+
+/// Serves the JSON-LD context that every JSON-LD response points to.
+///
+/// It maps the property names used in responses to terms in this API's
+/// vocabulary, which lives under `/vocab#` and borrows from schema.org where
+/// the meaning is the same, and marks which properties are links to other
+/// resources.
+///
+/// ```http
+/// GET /context.jsonld
+/// ```
+pub async fn context(BaseUrl(base_url): BaseUrl) -> impl IntoResponse {
+    let link = |id: &str| json!({ "@id": id, "@type": "@id" });
+    let context = json!({
+        "@context": {
+            "@vocab": format!("{}/vocab#", base_url),
+            "schema": "https://schema.org/",
+            "xsd": "http://www.w3.org/2001/XMLSchema#",
+            "User": "schema:Person",
+            "Room": "Room",
+            "Message": "schema:Message",
+            "Collection": "Collection",
+            "Presence": "Presence",
+            "Changes": "Changes",
+            "EntryPoint": "EntryPoint",
+            "name": "schema:name",
+            "message": "schema:text",
+            "sender_name": "sender_name",
+            "date_time": { "@id": "schema:dateCreated", "@type": "xsd:dateTime" },
+            "room": link("room"),
+            "user": link("user"),
+            "messages": link("messages"),
+            "items": { "@id": "items", "@container": "@list" },
+            "online": { "@id": "online", "@container": "@set" },
+            "typing": { "@id": "typing", "@type": "xsd:boolean" },
+            "resync": { "@id": "resync", "@type": "xsd:boolean" },
+            "has_more": { "@id": "has_more", "@type": "xsd:boolean" },
+            "slow_mode_seconds": {
+                "@id": "slow_mode_seconds",
+                "@type": "xsd:nonNegativeInteger"
+            },
+            "duplicate_window_seconds": {
+                "@id": "duplicate_window_seconds",
+                "@type": "xsd:nonNegativeInteger"
+            },
+        }
+    });
+    (Headers([(CONTENT_TYPE, JSON_LD)]), Json(context))
+}
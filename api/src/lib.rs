@@ -0,0 +1,1840 @@
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_dynamodb::{model::AttributeValue, Credentials, Endpoint, Region};
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract, middleware,
+    response::{Headers, IntoResponse},
+    routing::{get, patch, post, put},
+    Json, Router,
+};
+use base_url::BaseUrl;
+use config::{Config, DynamoDbConfig};
+use db::Db;
+use errors::{ChatError, FieldError, InvalidRequest};
+use etag::{Conditional, ETag, IfNoneMatch};
+use http::{
+    header::{CONTENT_DISPOSITION, CONTENT_TYPE, LOCATION},
+    HeaderMap, Method, StatusCode,
+};
+use hyper::Uri;
+use json_ld::{Format, Negotiated};
+use models::*;
+use presence::PresenceTracker;
+use rate_limit::RateLimiter;
+use routes::{Links, Route};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use sync::Token;
+use tower::{
+    limit::GlobalConcurrencyLimitLayer, load_shed::LoadShedLayer, ServiceBuilder, ServiceExt,
+};
+use tower_http::{
+    request_id::{PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{field, instrument, Instrument, Span};
+use versioning::{RequestedVersion, VersionHeaders};
+
+pub mod admin;
+mod base_url;
+pub mod config;
+mod cors;
+pub mod db;
+pub mod errors;
+mod etag;
+pub mod export;
+mod health;
+mod idempotency;
+pub mod import;
+mod json_ld;
+mod limits;
+mod metrics;
+pub mod migrations;
+mod models;
+mod openapi;
+#[cfg(feature = "otel")]
+mod otel;
+mod presence;
+mod rate_limit;
+mod routes;
+pub mod schema;
+mod shutdown;
+mod sync;
+mod telemetry;
+pub mod validation;
+mod versioning;
+
+/// Runs the API: reads the config, sets up the tables, and serves requests
+/// until it's told to shut down. This is all the `api` binary does; `chatctl`
+/// shares the rest of the crate.
+pub async fn run() {
+    let args = match config::Args::parse() {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!(
+                "{}\nUsage: api [--config <path>] [--print-config] [--print-openapi]",
+                error
+            );
+            std::process::exit(2);
+        }
+    };
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("Invalid configuration:");
+            for error in errors {
+                eprintln!("  - {}", error);
+            }
+            std::process::exit(1);
+        }
+    };
+    if args.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+    if args.print_openapi {
+        let (_, links) = routes::build(routes(&config));
+        let drift = openapi::drift(&links);
+        if !drift.is_empty() {
+            eprintln!("The OpenAPI document is out of date:");
+            for error in drift {
+                eprintln!("  - {}", error);
+            }
+            std::process::exit(1);
+        }
+        println!("{}", openapi::json());
+        return;
+    }
+
+    telemetry::init(&config.log, &config.otel);
+
+    let dynamodb = Db {
+        client: dynamodb_client(&config.dynamodb).await,
+        tables: config.tables.prefixed(),
+    };
+    if let Err(problems) = schema::apply(&dynamodb, &schema::tables(&config), &config.schema).await
+    {
+        eprintln!("The DynamoDB tables don't match what the API needs:");
+        for problem in problems {
+            eprintln!("  - {}", problem);
+        }
+        std::process::exit(1);
+    }
+    if let Err(error) = migrations::run(&dynamodb).await {
+        eprintln!("{}", error.debug.unwrap_or(error.display));
+        std::process::exit(1);
+    }
+    let limits = &config.limits;
+    let app = app(&config, dynamodb);
+
+    let (draining, drained) = tokio::sync::watch::channel(false);
+    let server = axum::Server::bind(&config.listen_address)
+        .http1_header_read_timeout(limits.header_read_timeout)
+        .serve(
+            app.into_make_service_with_connect_info::<SocketAddr, _>()
+                .map_response(metrics::Connection::new),
+        )
+        .with_graceful_shutdown(shutdown::signal(draining));
+    let result = shutdown::drain(server, drained, limits.drain_timeout).await;
+
+    telemetry::shutdown().await;
+    if result.is_err() {
+        std::process::exit(1);
+    }
+}
+
+/// The routes, under each version prefix, with the middleware and extensions
+/// every request goes through.
+fn app(config: &Config, dynamodb: Db) -> Router {
+    let limits = &config.limits;
+    let (router, links) = routes::build(routes(config));
+
+    // The same routes are served under each version prefix. The route trees
+    // share their handlers (and so their concurrency limits).
+    let mut app = Router::new();
+    for requested in RequestedVersion::TREES {
+        let tree = router
+            .clone()
+            .layer(middleware::from_fn(versioning::tag))
+            .layer(middleware::from_fn(metrics::route))
+            .layer(extract::Extension(VersionHeaders::new(
+                requested,
+                &config.deprecations,
+            )));
+        app = match requested.prefix {
+            "" => app.merge(tree),
+            prefix => app.nest(prefix, tree),
+        };
+    }
+
+    app.layer(middleware::from_fn(limits::limit_body))
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(limits::handle_error))
+                .layer(LoadShedLayer::new())
+                .layer(GlobalConcurrencyLimitLayer::new(
+                    limits.max_concurrent_requests,
+                )),
+        )
+        .layer(middleware::from_fn(rate_limit::limit))
+        .layer(middleware::from_fn(limits::limit_time))
+        .layer(extract::Extension(limits.clone()))
+        .layer(extract::Extension(config.idempotency.clone()))
+        .layer(extract::Extension(RateLimiter::new(
+            &config.rate_limits,
+            dynamodb.clone(),
+        )))
+        .layer(extract::Extension(dynamodb))
+        .layer(extract::Extension(PresenceTracker::default()))
+        .layer(extract::Extension(import::Imports::default()))
+        .layer(extract::Extension(Arc::new(config.clone())))
+        .layer(extract::Extension(links))
+        .layer(middleware::from_fn(metrics::track))
+        .layer(middleware::from_fn(telemetry::scope_request_id))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(telemetry::make_span)
+                .on_response(telemetry::on_response)
+                .on_failure(()),
+        )
+        .layer(config.cors.layer())
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(telemetry::MakeId))
+}
+
+/// Every route the API serves. The router, the root document and the OpenAPI
+/// drift check are all built from this.
+fn routes(config: &Config) -> Vec<Route> {
+    let writes = config.limits.max_concurrent_writes;
+    let mut routes = vec![
+        Route::new("root", "/", &[Method::GET], get(hateos)),
+        Route::new(
+            "sign_up",
+            "/sign-up",
+            &[Method::POST],
+            limits::concurrency_limit(post(sign_up), writes),
+        ),
+        Route::new("sign_in", "/sign-in", &[Method::POST], post(sign_in)),
+        Route::new("user", "/users/:user_id", &[Method::GET], get(get_user)),
+        Route::new(
+            "message",
+            "/rooms/:room_id/messages/:message_id",
+            &[Method::GET],
+            get(get_message_by_id),
+        ),
+        Route::new(
+            "messages",
+            "/rooms/:room_id/messages",
+            &[Method::GET, Method::PUT],
+            limits::concurrency_limit(idempotency::idempotent(put(put_message)), writes)
+                .get(get_messages),
+        ),
+        Route::new(
+            "export",
+            "/rooms/:room_id/export",
+            &[Method::GET],
+            get(get_export),
+        ),
+        Route::new(
+            "room",
+            "/rooms/:room_id",
+            &[Method::PATCH],
+            limits::concurrency_limit(patch(patch_room), writes),
+        ),
+        Route::new(
+            "presence",
+            "/rooms/:room_id/presence",
+            &[Method::GET, Method::PUT],
+            get(get_presence).put(put_presence),
+        ),
+        Route::new(
+            "rooms",
+            "/rooms",
+            &[Method::GET, Method::PUT],
+            limits::concurrency_limit(idempotency::idempotent(put(put_room)), writes)
+                .get(get_rooms),
+        ),
+        Route::new("sync", "/sync", &[Method::GET], get(get_sync)),
+        Route::new(
+            "imports",
+            import::IMPORTS_PATH,
+            &[Method::POST],
+            post(post_import),
+        ),
+        Route::new(
+            "import",
+            "/admin/imports/:import_id",
+            &[Method::GET],
+            get(get_import),
+        ),
+        Route::new(
+            "context",
+            json_ld::CONTEXT_PATH,
+            &[Method::GET],
+            get(json_ld::context),
+        ),
+        Route::new("status", "/status", &[Method::GET], get(status)),
+        Route::new("live", "/health/live", &[Method::GET], get(health::live)),
+        Route::new("ready", "/health/ready", &[Method::GET], get(health::ready)),
+        Route::new("metrics", "/metrics", &[Method::GET], get(metrics::metrics)),
+        Route::new(
+            "openapi",
+            openapi::OPENAPI_PATH,
+            &[Method::GET],
+            get(openapi::openapi),
+        ),
+    ];
+    if config.swagger_ui {
+        routes.push(Route::new(
+            "docs",
+            openapi::SWAGGER_UI_PATH,
+            &[Method::GET],
+            get(openapi::swagger_ui),
+        ));
+    }
+    routes
+}
+
+/// A macro that makes consuming `HashMap<String, AttributeValue>` safer and
+/// easier.
+///
+/// DynamoDB returns data as a HashMap<String, AttributeValue>. This can be
+/// tiresome to parse and handle all situations, so we define a macro. The
+/// following statements are equivalent:
+/// ```ignore
+/// S!(map, "key")
+/// map["key"].to_s()?
+/// ```
+/// However, we have additional error handling added via the macro that raises
+/// an error when an index does not exist.
+/// Generally speaking, macros aren't the clearest thing in the world to use,
+/// but for situations like this where you want to generate a custom error
+/// message based on inputs, I think there's no better tool.
+macro_rules! S {
+    ($map:ident,$key:literal) => {
+        $map.get($key)
+            .ok_or_else(|| {
+                let map = stringify!($map);
+                ChatError::new(
+                    Some(format!("Could not index {} by {}", map, $key)),
+                    "Internal server error".into(),
+                )
+            })?
+            .as_s()?
+    };
+}
+
+/// See explanation for `S`, however, this works with attribute type `N`.
+macro_rules! N {
+    ($map:ident,$key:literal) => {
+        $map.get($key)
+            .ok_or_else(|| {
+                let map = stringify!($map);
+                ChatError::new(
+                    Some(format!("Could not index {} by {}", map, $key)),
+                    "Internal server error".into(),
+                )
+            })?
+            .as_n()?
+    };
+}
+
+/// Builds the DynamoDB client.
+///
+/// The client is built once on startup and handed to the handlers (inside of
+/// a `Db`) as an extension, so every handler can trace where its data comes
+/// from back to `main`.
+pub async fn dynamodb_client(config: &DynamoDbConfig) -> aws_sdk_dynamodb::Client {
+    let region_provider = RegionProviderChain::first_try(config.region.clone().map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"));
+    let mut loader = aws_config::from_env().region(region_provider);
+    if let (Some(access_key_id), Some(secret_access_key)) =
+        (&config.access_key_id, &config.secret_access_key)
+    {
+        loader = loader.credentials_provider(Credentials::new(
+            access_key_id,
+            secret_access_key.expose(),
+            None,
+            None,
+            "config",
+        ));
+    }
+    let sdk_config = loader.load().await;
+    let builder = aws_sdk_dynamodb::config::Builder::from(&sdk_config);
+    let builder = match &config.hostname {
+        Some(hostname) => builder.endpoint_resolver(Endpoint::immutable(
+            format!("http://{}:{}", hostname, config.port)
+                .parse::<Uri>()
+                .unwrap(),
+        )),
+        None => builder,
+    };
+    aws_sdk_dynamodb::Client::from_conf(builder.build())
+}
+
+/// Generates a UUID for new objects.
+///
+/// This is good enough for now. Ideally
+/// what you want for DynamoDB is something that generates a random value
+/// between 0 and 99..99 (38 9's). Left-substring is a cheap way to get this
+/// but this means that multiple random outputs can have the same uuid()
+///
+/// Numbers below 10^37 have fewer than 38 digits, so they're left out.
+pub fn uuid() -> String {
+    fastrand::u128(10u128.pow(37)..u128::MAX).to_string()[..38].to_owned()
+}
+
+/// The sign up handler. Creates a user whose name isn't already taken.
+///
+/// ```http
+/// POST /sign-up
+/// Accept: application/json
+/// Content-Type: application/json
+///
+/// {"name": "Ryan"}
+/// ```
+///
+/// ```http
+/// 200 OK
+/// Content-Type: application/json
+///
+/// {
+///   "id": "http://localhost:5050/users/123456",
+///   "properties": {
+///     "name": "Ryan"
+///   }
+/// }
+/// ```
+#[utoipa::path(
+    post,
+    path = "/sign-up",
+    tag = "users",
+    request_body = NameRequest,
+    responses(
+        (status = 200, description = "The new user", body = User),
+        (status = 400, description = "The name is invalid or taken", body = InvalidRequest),
+    )
+)]
+#[instrument(skip_all, fields(user_id = field::Empty))]
+async fn sign_up(
+    extract::Json(name_request): extract::Json<NameRequest>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+) -> Result<Negotiated<User>, ChatError> {
+    let name = validation::name("name", &name_request.name)?;
+    let name_key = validation::name_key(&name);
+
+    let id = uuid();
+    Span::current().record("user_id", id.as_str());
+    if !db::create_user(&dynamodb, &id, &name, &name_key).await? {
+        return Err(FieldError::new("name", "Name already registered").into());
+    }
+    Ok(Negotiated(
+        format,
+        Object::user(&format!("{}/users/{}", base_url, &id), &name),
+    ))
+}
+
+/// The sign in handler. Signs a user in whose name is recognized.
+///
+/// ```http
+/// POST /sign-in
+/// Accept: application/json
+/// Content-Type: application/json
+///
+/// {"name": "Ryan"}
+/// ```
+///
+/// ```http
+/// 200 OK
+/// Content-Type: application/json
+///
+/// {
+///   "id": "http://localhost:5050/users/123456",
+///   "properties": {
+///     "name": "Ryan"
+///   }
+/// }
+/// ```
+#[utoipa::path(
+    post,
+    path = "/sign-in",
+    tag = "users",
+    request_body = NameRequest,
+    responses(
+        (status = 200, description = "The user with that name", body = User),
+        (status = 400, description = "The name is invalid", body = InvalidRequest),
+        (status = 500, description = "Nobody has that name", body = String, content_type = "text/plain"),
+    )
+)]
+#[instrument(skip_all, fields(user_id = field::Empty))]
+async fn sign_in(
+    extract::Json(name_request): extract::Json<NameRequest>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+) -> Result<Negotiated<User>, ChatError> {
+    let name = validation::name("name", &name_request.name)?;
+
+    let user = match db::get_user_by_name_key(&dynamodb, &validation::name_key(&name)).await? {
+        Some(user) => user,
+        None => db::get_user_by_name(&dynamodb, &name).await?,
+    };
+    Span::current().record("user_id", N!(user, "user_id").as_str());
+    Ok(Negotiated(
+        format,
+        Object::user(
+            &format!("{}/users/{}", base_url, N!(user, "user_id")),
+            S!(user, "name"),
+        ),
+    ))
+}
+
+/// The get user handler. Retrieves a user by ID.
+///
+/// ```http
+/// GET /users/123456
+/// ```
+///
+/// ```http
+/// 200 OK
+/// Content-Type: application/json
+///
+/// {
+///   "id": "http://localhost:5050/users/123456",
+///   "properties": {
+///     "name": "Ryan"
+///   }
+/// }
+/// ```
+#[utoipa::path(
+    get,
+    path = "/users/{user_id}",
+    tag = "users",
+    params(
+        ("user_id" = String, Path, description = "ID of the user"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of the copy the client has"),
+    ),
+    responses(
+        (status = 200, description = "The user", body = User, headers(("ETag" = String))),
+        (status = 304, description = "The user hasn't changed"),
+        (status = 400, description = "The ID is invalid", body = InvalidRequest),
+    )
+)]
+#[instrument(skip_all, fields(user_id = %user_id))]
+async fn get_user(
+    extract::Path(user_id): extract::Path<String>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+    if_none_match: IfNoneMatch,
+) -> Result<Conditional<User>, ChatError> {
+    let user_id = validation::id_from_uri("user_id", &user_id)?;
+
+    let user = db::get_user_by_id(&dynamodb, user_id).await?;
+    let user = Object::user(
+        &format!("{}/users/{}", base_url, N!(user, "user_id")),
+        S!(user, "name"),
+    );
+    let etag = ETag::of(&format, &user);
+    Ok(if_none_match.respond(etag, format, user))
+}
+
+/// Retrieves a message by it's ID. This handler wasn't asked for in the
+/// requirements but I found it necessary to add because otherwise the ID for
+/// a message would be a URI to a 404, which seems uncool.
+#[utoipa::path(
+    get,
+    path = "/rooms/{room_id}/messages/{message_id}",
+    tag = "messages",
+    params(
+        ("room_id" = String, Path, description = "ID of the room"),
+        ("message_id" = String, Path, description = "ID of the message"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of the copy the client has"),
+    ),
+    responses(
+        (status = 200, description = "The message", body = Message, headers(("ETag" = String))),
+        (status = 304, description = "The message hasn't changed"),
+    )
+)]
+#[instrument(skip_all, fields(room_id = %room_id, message_id = %message_id))]
+async fn get_message_by_id(
+    extract::Path((room_id, message_id)): extract::Path<(String, String)>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+    if_none_match: IfNoneMatch,
+) -> Result<Conditional<Message>, ChatError> {
+    let message = db::get_message_by_id(&dynamodb, &room_id, &message_id).await?;
+
+    // Date time is part of the sort key. It is of the format `message.TIME`
+    // so we simply do a substring from index 8. Dangerous? You betcha.
+    // A better solution might be to confirm that length > 8 and that the
+    // substring [8..] is formatted like a date.
+    let date_time = &S!(message, "sort")[8..];
+
+    let message = Object::message(
+        &format!(
+            "{}/rooms/{}/messages/{}",
+            base_url,
+            room_id,
+            S!(message, "sort")
+        ),
+        date_time,
+        S!(message, "sender_name"),
+        S!(message, "message"),
+        &format!("{}/rooms/{}", base_url, room_id),
+        &format!("{}/users/{}", base_url, N!(message, "sender_id")),
+    );
+    let etag = ETag::of(&format, &message);
+    Ok(if_none_match.respond(etag, format, message))
+}
+
+/// Retrieves the latest messages in a room.
+///
+/// The ETag comes from the room's message version, so a client polling with
+/// `If-None-Match` costs one `GetItem` when nothing was posted, instead of a
+/// query for the whole page.
+#[utoipa::path(
+    get,
+    path = "/rooms/{room_id}/messages",
+    tag = "messages",
+    params(
+        ("room_id" = String, Path, description = "ID of the room"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of the copy the client has"),
+    ),
+    responses(
+        (status = 200, description = "The latest messages, newest first", body = Vec<Message>, headers(("ETag" = String))),
+        (status = 304, description = "No messages have been posted since"),
+    )
+)]
+#[instrument(skip_all, fields(room_id = %room_id))]
+async fn get_messages(
+    extract::Path(room_id): extract::Path<String>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+    if_none_match: IfNoneMatch,
+) -> Result<Conditional<Collection<MessageProperties>>, ChatError> {
+    // The version is read before the messages. If a message is posted in
+    // between, the page goes out with the old version's tag, and the client
+    // just gets it again next time, rather than missing it.
+    let id = format!("{}/rooms/{}/messages", base_url, room_id);
+    let version = db::get_room_version(&dynamodb, &room_id).await?;
+    let etag = ETag::versioned(&format, &id, version);
+    if if_none_match.matches(&etag) {
+        return Ok(Conditional::NotModified(etag));
+    }
+
+    let mut messages = Vec::new();
+
+    for message in db::get_messages(&dynamodb, &room_id, 50).await? {
+        let date_time = &S!(message, "sort")[8..];
+        messages.push(Object::message(
+            &format!(
+                "{}/rooms/{}/messages/{}",
+                base_url,
+                room_id,
+                S!(message, "sort")
+            ),
+            date_time,
+            S!(message, "sender_name"),
+            S!(message, "message"),
+            &format!("{}/rooms/{}", base_url, room_id),
+            &format!("{}/users/{}", base_url, N!(message, "sender_id")),
+        ))
+    }
+
+    Ok(Conditional::Modified(
+        etag,
+        Negotiated(format, Collection::new(&id, messages)),
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/rooms/{room_id}/messages",
+    tag = "messages",
+    params(
+        ("room_id" = String, Path, description = "ID of the room"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe, see `idempotency`"),
+    ),
+    request_body = MessageRequest,
+    responses(
+        (status = 200, description = "The message that was sent", body = Message),
+        (status = 400, description = "The room ID, message or sender is invalid", body = InvalidRequest),
+        (status = 409, description = "A request with the same Idempotency-Key is still in progress", body = String, content_type = "text/plain"),
+        (status = 422, description = "The Idempotency-Key was used for a different request", body = String, content_type = "text/plain"),
+        (status = 429, description = "Slow mode or duplicate suppression kicked in", body = String, content_type = "text/plain"),
+    )
+)]
+#[instrument(skip_all, fields(room_id = %room_id, user_id = field::Empty))]
+async fn put_message(
+    extract::Path(room_id): extract::Path<String>,
+    extract::Json(message_request): extract::Json<MessageRequest>,
+    extract::Extension(presence): extract::Extension<PresenceTracker>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+) -> Result<Negotiated<Message>, ChatError> {
+    let id = uuid();
+    let date_time = chrono::Utc::now().to_rfc3339();
+
+    // Message_request.sender_id is a URI so we just need to take off the last
+    // index. Check every field before returning so the client gets all of the
+    // errors at once.
+    let room_id = validation::id_from_uri("room_id", &room_id);
+    let message = validation::message("message", &message_request.message);
+    let sender_id = validation::id_from_uri("sender_id", &message_request.sender_id);
+    let (room_id, message, sender_id) = match (room_id, message, sender_id) {
+        (Ok(room_id), Ok(message), Ok(sender_id)) => (room_id, message, sender_id),
+        (room_id, message, sender_id) => {
+            return Err(ChatError::invalid(
+                room_id
+                    .err()
+                    .into_iter()
+                    .chain(message.err())
+                    .chain(sender_id.err())
+                    .collect(),
+            ))
+        }
+    };
+
+    Span::current().record("user_id", sender_id);
+
+    // Look up user
+    let user = db::get_user_by_id(&dynamodb, sender_id).await?;
+    let user_name = S!(user, "name");
+
+    // Enforce slow mode and duplicate suppression, if the room has them on
+    let room = db::get_room(&dynamodb, room_id).await?;
+    let settings = db::room_settings(&room)?;
+    let recorded = if settings.slow_mode_seconds > 0 || settings.duplicate_window_seconds > 0 {
+        Some(db::record_post(&dynamodb, room_id, sender_id, &message, &settings).await?)
+    } else {
+        None
+    };
+
+    // Insert message
+    let posted = db::post_message(
+        &dynamodb, room_id, &message, sender_id, user_name, &date_time,
+    )
+    .await;
+    if let Err(error) = posted {
+        // A message that wasn't posted shouldn't count against slow mode
+        if let Some(recorded) = recorded {
+            if let Err(undo_error) =
+                db::unrecord_post(&dynamodb, room_id, sender_id, recorded).await
+            {
+                tracing::warn!(
+                    error = %undo_error.display,
+                    debug = ?undo_error.debug,
+                    "Could not take back a post that failed"
+                );
+            }
+        }
+        return Err(error);
+    }
+
+    metrics::message_posted(&settings);
+
+    // Let clients polling the room know there's something new
+    db::bump_room_version(&dynamodb, room_id).await?;
+
+    // Bump room to top of room listing
+    db::bump_room(&dynamodb, room_id).await?;
+
+    // Whatever the sender was typing has now been sent
+    presence.message_sent(room_id, sender_id, user_name);
+
+    Ok(Negotiated(
+        format,
+        Object::message(
+            &format!("{}/rooms/{}/messages/{}", base_url, room_id, id),
+            &date_time,
+            user_name,
+            &message,
+            &format!("{}/rooms/{}", base_url, room_id),
+            &format!("{}/users/{}", base_url, sender_id),
+        ),
+    ))
+}
+
+/// Downloads a room's whole history, oldest message first, for archiving. The
+/// messages have the same fields as in `GET /rooms/:room_id/messages`, and
+/// `format` is one of `json` (the default), `ndjson`, `csv`, `html` or `txt`.
+///
+/// The history is read and sent a page at a time, so a big room doesn't have
+/// to fit in memory. Once the first page is out the status can't change, so
+/// if reading a later page fails, the response is cut off instead, and the
+/// client sees an incomplete download.
+///
+/// ```http
+/// GET /rooms/123/export?format=csv
+/// ```
+///
+/// ```http
+/// 200 OK
+/// Content-Type: text/csv; charset=utf-8
+/// Content-Disposition: attachment; filename="room-123.csv"
+///
+/// date_time,sender_name,message,room,user
+/// 2022-03-01T12:00:00+00:00,Ryan,Hello,http://localhost:5050/rooms/123,http://localhost:5050/users/123456
+/// ```
+#[utoipa::path(
+    get,
+    path = "/rooms/{room_id}/export",
+    tag = "messages",
+    params(
+        ("room_id" = String, Path, description = "ID of the room"),
+        ("format" = Option<String>, Query, description = "json, ndjson, csv, html or txt"),
+    ),
+    responses(
+        (status = 200, description = "Every message in the room, oldest first", body = Vec<MessageProperties>, headers(("Content-Disposition" = String))),
+        (status = 400, description = "The room or format is invalid", body = InvalidRequest),
+        (status = 500, description = "The room doesn't exist", body = String, content_type = "text/plain"),
+    )
+)]
+#[instrument(skip_all, fields(room_id = %room_id, format = ?query.format))]
+async fn get_export(
+    extract::Path(room_id): extract::Path<String>,
+    extract::Query(query): extract::Query<ExportQuery>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+) -> Result<impl IntoResponse, ChatError> {
+    let room_id = validation::id_from_uri("room_id", &room_id);
+    let format = match &query.format {
+        Some(format) => export::Format::parse("format", format).map(Some),
+        None => Ok(None),
+    };
+    let (room_id, format) = match (room_id, format) {
+        (Ok(room_id), Ok(format)) => (room_id, format.unwrap_or(export::Format::Json)),
+        (room_id, format) => {
+            return Err(ChatError::invalid(
+                room_id.err().into_iter().chain(format.err()).collect(),
+            ))
+        }
+    };
+
+    let mut export = export::Export::open(dynamodb, room_id, &base_url, format).await?;
+    let (mut sender, body) = hyper::Body::channel();
+    tokio::spawn(
+        async move {
+            loop {
+                match export.next().await {
+                    Ok(Some(chunk)) => {
+                        // The client went away
+                        if sender.send_data(chunk.into()).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => return,
+                    Err(error) => {
+                        tracing::error!(
+                            error = %error.display,
+                            debug = ?error.debug,
+                            "Export failed partway through"
+                        );
+                        sender.abort();
+                        return;
+                    }
+                }
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok((
+        Headers([
+            (CONTENT_TYPE, format.content_type().to_owned()),
+            (
+                CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"room-{}.{}\"",
+                    room_id,
+                    format.extension()
+                ),
+            ),
+        ]),
+        axum::body::boxed(body),
+    ))
+}
+
+/// Imports chat history from a Slack or Discord export, see `import`. Each
+/// channel becomes the room with the same name and each author the user with
+/// the same name, created if they don't exist yet, and messages keep the
+/// times they were originally sent. `source` is `slack`, for the zip Slack's
+/// export gives you, or `discord`, for a channel exported as JSON with
+/// DiscordChatExporter.
+///
+/// The export is read before responding, so one that can't be read is a
+/// `400`, and then imported in the background. Poll the `Location` to see how
+/// far along it is. Importing the same export again only writes what's
+/// missing, which is also how an import that failed partway is finished.
+///
+/// Needs `admin_token` to be set, and is a `404` otherwise.
+///
+/// ```http
+/// POST /admin/imports?source=slack
+/// Authorization: Bearer <admin_token>
+/// Content-Type: application/zip
+/// ```
+///
+/// ```http
+/// 202 Accepted
+/// Location: http://localhost:5050/admin/imports/123456
+///
+/// {"id": "http://localhost:5050/admin/imports/123456", "source": "slack", "status": "running", "report": {...}}
+/// ```
+#[utoipa::path(
+    post,
+    path = "/admin/imports",
+    tag = "admin",
+    params(
+        ("source" = String, Query, description = "slack or discord"),
+        ("Authorization" = String, Header, description = "Bearer and the admin token"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream", description = "The export, as downloaded"),
+    responses(
+        (status = 202, description = "The import started", body = import::Job, headers(("Location" = String))),
+        (status = 400, description = "The source is invalid or the export can't be read", body = InvalidRequest),
+        (status = 401, description = "The admin token is missing or wrong", body = String, content_type = "text/plain"),
+        (status = 404, description = "There's no admin token, so admin endpoints are off"),
+        (status = 413, description = "The export is bigger than `max_import_bytes`"),
+    )
+)]
+#[instrument(skip_all, fields(source = ?query.source, bytes = export.len()))]
+async fn post_import(
+    extract::Query(query): extract::Query<ImportQuery>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    extract::Extension(imports): extract::Extension<import::Imports>,
+    BaseUrl(base_url): BaseUrl,
+    headers: HeaderMap,
+    export: axum::body::Bytes,
+) -> Result<impl IntoResponse, ChatError> {
+    import::authorize(config.admin_token.as_ref(), &headers)?;
+    let source = match &query.source {
+        Some(source) => import::Source::parse("source", source)?,
+        None => return Err(FieldError::new("source", "Source is required").into()),
+    };
+
+    // Unzipping and parsing a big export takes a while
+    let channels = tokio::task::spawn_blocking(move || import::read(source, &export))
+        .await
+        .map_err(|error| {
+            ChatError::new(Some(format!("{:?}", error)), "Internal server error".into())
+        })??;
+
+    let import_id = uuid();
+    let uri = format!("{}{}/{}", base_url, import::IMPORTS_PATH, import_id);
+    let job = imports.start(dynamodb, import_id, uri.clone(), source, channels);
+    Ok((StatusCode::ACCEPTED, Headers([(LOCATION, uri)]), Json(job)))
+}
+
+/// How an import started with `POST /admin/imports` is going. Imports are
+/// only known to the replica running them, until it restarts or an hour
+/// after they finish.
+#[utoipa::path(
+    get,
+    path = "/admin/imports/{import_id}",
+    tag = "admin",
+    params(
+        ("import_id" = String, Path, description = "ID of the import"),
+        ("Authorization" = String, Header, description = "Bearer and the admin token"),
+    ),
+    responses(
+        (status = 200, description = "The import", body = import::Job),
+        (status = 401, description = "The admin token is missing or wrong", body = String, content_type = "text/plain"),
+        (status = 404, description = "The import doesn't exist, or admin endpoints are off", body = String, content_type = "text/plain"),
+    )
+)]
+#[instrument(skip_all, fields(import_id = %import_id))]
+async fn get_import(
+    extract::Path(import_id): extract::Path<String>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(imports): extract::Extension<import::Imports>,
+    headers: HeaderMap,
+) -> Result<Json<import::Job>, ChatError> {
+    import::authorize(config.admin_token.as_ref(), &headers)?;
+    imports.get(&import_id).map(Json).ok_or_else(|| {
+        ChatError::with_status(StatusCode::NOT_FOUND, "Import does not exist".into())
+    })
+}
+
+/// Tells the server that a user is in a room, and optionally that they are
+/// typing. Clients should call this every few seconds while a room is open;
+/// users who stop calling it drop off the presence list after
+/// `presence::HEARTBEAT_TTL`.
+///
+/// ```http
+/// PUT /rooms/123/presence
+/// Content-Type: application/json
+///
+/// {"user_id": "http://localhost:5050/users/123456", "typing": true}
+/// ```
+#[utoipa::path(
+    put,
+    path = "/rooms/{room_id}/presence",
+    tag = "rooms",
+    params(("room_id" = String, Path, description = "ID of the room")),
+    request_body = PresenceRequest,
+    responses(
+        (status = 200, description = "Who is online in the room", body = Presence),
+        (status = 400, description = "The room or user is invalid", body = InvalidRequest),
+        (status = 500, description = "The room or user doesn't exist", body = String, content_type = "text/plain"),
+    )
+)]
+#[instrument(skip_all, fields(room_id = %room_id, user_id = field::Empty))]
+async fn put_presence(
+    extract::Path(room_id): extract::Path<String>,
+    extract::Json(presence_request): extract::Json<PresenceRequest>,
+    extract::Extension(presence): extract::Extension<PresenceTracker>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+) -> Result<Negotiated<Presence>, ChatError> {
+    let room_id = validation::id_from_uri("room_id", &room_id);
+    let user_id = validation::id_from_uri("user_id", &presence_request.user_id);
+    let (room_id, user_id) = match (room_id, user_id) {
+        (Ok(room_id), Ok(user_id)) => (room_id, user_id),
+        (room_id, user_id) => {
+            return Err(ChatError::invalid(
+                room_id.err().into_iter().chain(user_id.err()).collect(),
+            ))
+        }
+    };
+    Span::current().record("user_id", user_id);
+
+    // Look up the user so that nobody can show up as a ghost, and the room so
+    // that heartbeats can't fill the tracker up with rooms that don't exist
+    let user = db::get_user_by_id(&dynamodb, user_id).await?;
+    db::get_room(&dynamodb, room_id).await?;
+    presence.heartbeat(room_id, user_id, S!(user, "name"), presence_request.typing);
+
+    Ok(Negotiated(
+        format,
+        room_presence(&base_url, &presence, room_id),
+    ))
+}
+
+/// Lists the users that are online in a room, and which of them are typing.
+/// This is the polling alternative to holding a live connection open.
+///
+/// ```http
+/// GET /rooms/123/presence
+/// ```
+///
+/// ```http
+/// 200 OK
+/// Content-Type: application/json
+///
+/// {
+///   "id": "http://localhost:5050/rooms/123/presence",
+///   "properties": {
+///     "room": "http://localhost:5050/rooms/123",
+///     "online": [
+///       {"user": "http://localhost:5050/users/123456", "name": "Ryan", "typing": true}
+///     ]
+///   }
+/// }
+/// ```
+#[utoipa::path(
+    get,
+    path = "/rooms/{room_id}/presence",
+    tag = "rooms",
+    params(("room_id" = String, Path, description = "ID of the room")),
+    responses(
+        (status = 200, description = "Who is online in the room", body = Presence),
+    )
+)]
+#[instrument(skip_all, fields(room_id = %room_id))]
+async fn get_presence(
+    extract::Path(room_id): extract::Path<String>,
+    extract::Extension(presence): extract::Extension<PresenceTracker>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+) -> Negotiated<Presence> {
+    Negotiated(format, room_presence(&base_url, &presence, &room_id))
+}
+
+fn room_presence(base_url: &str, presence: &PresenceTracker, room_id: &str) -> Presence {
+    let online = presence
+        .members(room_id)
+        .into_iter()
+        .map(|member| PresenceMember {
+            user: format!("{}/users/{}", base_url, member.user_id),
+            name: member.name,
+            typing: member.typing,
+        })
+        .collect();
+
+    Object::presence(
+        &format!("{}/rooms/{}/presence", base_url, room_id),
+        &format!("{}/rooms/{}", base_url, room_id),
+        online,
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/rooms",
+    tag = "rooms",
+    params(
+        ("If-None-Match" = Option<String>, Header, description = "ETag of the copy the client has"),
+    ),
+    responses(
+        (status = 200, description = "Rooms, most recently active first", body = Vec<Room>, headers(("ETag" = String))),
+        (status = 304, description = "The rooms haven't changed"),
+    )
+)]
+#[instrument(skip_all)]
+async fn get_rooms(
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+    if_none_match: IfNoneMatch,
+) -> Result<Conditional<Collection<RoomProperties>>, ChatError> {
+    let mut r = Vec::new();
+
+    for room in db::get_active_rooms(&dynamodb).await? {
+        let settings = db::room_settings(&room)?;
+        r.push(Object::room(
+            &format!("{}/rooms/{}", base_url, N!(room, "room_id")),
+            S!(room, "name"),
+            &format!("{}/rooms/{}/messages", base_url, N!(room, "room_id")),
+            settings.slow_mode_seconds,
+            settings.duplicate_window_seconds,
+        ));
+    }
+
+    let rooms = Collection::new(&format!("{}/rooms", base_url), r);
+    let etag = ETag::of(&format, &rooms);
+    Ok(if_none_match.respond(etag, format, rooms))
+}
+
+#[utoipa::path(
+    put,
+    path = "/rooms",
+    tag = "rooms",
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe, see `idempotency`"),
+    ),
+    request_body = NameRequest,
+    responses(
+        (status = 200, description = "The new room", body = Room),
+        (status = 400, description = "The name is invalid", body = InvalidRequest),
+        (status = 409, description = "A request with the same Idempotency-Key is still in progress", body = String, content_type = "text/plain"),
+        (status = 422, description = "The Idempotency-Key was used for a different request", body = String, content_type = "text/plain"),
+    )
+)]
+#[instrument(skip_all, fields(room_id = field::Empty))]
+async fn put_room(
+    extract::Json(name_request): extract::Json<NameRequest>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+) -> Result<Negotiated<Room>, ChatError> {
+    let id = uuid();
+    Span::current().record("room_id", id.as_str());
+    let name = validation::name("name", &name_request.name)?;
+    // todo: check if the room already exists
+    db::create_room(&dynamodb, &id, &name).await?;
+    db::bump_room(&dynamodb, &id).await?;
+    Ok(Negotiated(
+        format,
+        Object::room(
+            &format!("{}/rooms/{}", base_url, id),
+            &name,
+            &format!("{}/rooms/{}/messages", base_url, id),
+            0,
+            0,
+        ),
+    ))
+}
+
+/// Changes a room's flood control settings. Settings that are left out of the
+/// request are left alone, and 0 turns a setting off.
+///
+/// Users are whoever they say they are in this app, so there are no room
+/// owners or moderators to leave this to. It's an admin endpoint instead,
+/// like `POST /admin/imports`: it needs `admin_token`, and is a `404` without
+/// one.
+///
+/// ```http
+/// PATCH /rooms/123
+/// Authorization: Bearer <admin_token>
+/// Content-Type: application/json
+///
+/// {"slow_mode_seconds": 10, "duplicate_window_seconds": 60}
+/// ```
+///
+/// With these settings, each user can post once every 10 seconds, and can't
+/// post the same message twice within a minute. Users who post too soon get a
+/// 429 that says how long they have to wait.
+#[utoipa::path(
+    patch,
+    path = "/rooms/{room_id}",
+    tag = "rooms",
+    params(
+        ("room_id" = String, Path, description = "ID of the room"),
+        ("Authorization" = String, Header, description = "Bearer and the admin token"),
+    ),
+    request_body = RoomSettingsRequest,
+    responses(
+        (status = 200, description = "The room with its new settings", body = Room),
+        (status = 401, description = "The admin token is missing or wrong", body = String, content_type = "text/plain"),
+        (status = 404, description = "There's no admin token, so admin endpoints are off"),
+    )
+)]
+#[instrument(skip_all, fields(room_id = %room_id))]
+async fn patch_room(
+    extract::Path(room_id): extract::Path<String>,
+    extract::Json(settings_request): extract::Json<RoomSettingsRequest>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    BaseUrl(base_url): BaseUrl,
+    headers: HeaderMap,
+    format: Format,
+) -> Result<Negotiated<Room>, ChatError> {
+    import::authorize(config.admin_token.as_ref(), &headers)?;
+    let room = db::update_room_settings(
+        &dynamodb,
+        &room_id,
+        settings_request.slow_mode_seconds,
+        settings_request.duplicate_window_seconds,
+    )
+    .await?;
+    let settings = db::room_settings(&room)?;
+
+    Ok(Negotiated(
+        format,
+        Object::room(
+            &format!("{}/rooms/{}", base_url, room_id),
+            S!(room, "name"),
+            &format!("{}/rooms/{}/messages", base_url, room_id),
+            settings.slow_mode_seconds,
+            settings.duplicate_window_seconds,
+        ),
+    ))
+}
+
+/// Everything that changed since a client last synced, for clients that go
+/// offline and need to catch up: rooms that were created, changed, or had
+/// messages posted in them, and the new messages, oldest first. Every user can
+/// see every room, so it's the same for everyone.
+///
+/// Start without `since` (or whenever `resync` comes back), load rooms and
+/// messages as usual, and then sync from the `token` in the response. Tokens
+/// lag a few seconds behind, so that messages that were being posted while
+/// the token was made aren't missed, which means a message can come up twice.
+/// Tell them apart by `id`.
+///
+/// There are no edits, deletions or room memberships in this API, so none of
+/// those come up.
+///
+/// ```http
+/// GET /sync?since=1760788800000000
+/// ```
+///
+/// ```http
+/// 200 OK
+/// Content-Type: application/json
+///
+/// {
+///   "id": "http://localhost:5050/sync?since=1760788800000000",
+///   "properties": {
+///     "token": "1760788830000000",
+///     "resync": false,
+///     "has_more": false,
+///     "rooms": [...],
+///     "messages": [...]
+///   }
+/// }
+/// ```
+#[utoipa::path(
+    get,
+    path = "/sync",
+    tag = "rooms",
+    params(("since" = Option<String>, Query, description = "Token from the last sync")),
+    responses(
+        (status = 200, description = "What changed", body = Changes),
+        (status = 400, description = "The token is invalid", body = InvalidRequest),
+    )
+)]
+#[instrument(skip_all, fields(since = ?query.since))]
+async fn get_sync(
+    extract::Query(query): extract::Query<SyncQuery>,
+    extract::Extension(dynamodb): extract::Extension<Db>,
+    extract::Extension(limits): extract::Extension<limits::Limits>,
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+) -> Result<Negotiated<Changes>, ChatError> {
+    let since = match query
+        .since
+        .as_deref()
+        .map(|since| (since, Token::parse(since)))
+    {
+        None => None,
+        Some((_, Some(since))) => Some(since),
+        Some((since, None)) => {
+            return Err(ChatError::invalid(vec![FieldError::new(
+                "since",
+                &format!("{:?} is not a token from GET /sync", since),
+            )]))
+        }
+    };
+    let id = match &query.since {
+        Some(since) => format!("{}/sync?since={}", base_url, since),
+        None => format!("{}/sync", base_url),
+    };
+
+    // A message can be written up to a request timeout after the time in its
+    // sort key, so a token only vouches for messages up to then.
+    let now = Token::now();
+    let settled = now.saturating_sub(limits.request_timeout);
+    let resync = |format| {
+        Ok(Negotiated(
+            format,
+            Changes::new(
+                &id,
+                ChangesProperties {
+                    token: settled.to_string(),
+                    resync: true,
+                    has_more: false,
+                    rooms: Vec::new(),
+                    messages: Vec::new(),
+                },
+            ),
+        ))
+    };
+    let since = match since {
+        Some(since) if since >= now.saturating_sub(sync::MAX_AGE) => since,
+        _ => return resync(format),
+    };
+
+    let mut rooms = Vec::new();
+    let mut messages = Vec::new();
+    for room in db::get_active_rooms(&dynamodb).await? {
+        if db::room_changed_at(&room)?.is_none_or(|changed_at| changed_at < since) {
+            continue;
+        }
+        // Imported messages keep their old dates, and purged ones are just
+        // gone, so neither would come up as new. Tokens lag behind the request
+        // they came from, and whatever changed before that request was either
+        // loaded after a resync or checked here against an older token.
+        if db::room_history_changed_at(&room)?
+            .is_some_and(|changed_at| changed_at.saturating_sub(limits.request_timeout) >= since)
+        {
+            return resync(format);
+        }
+        let room_id = N!(room, "room_id");
+        let settings = db::room_settings(&room)?;
+        rooms.push(Object::room(
+            &format!("{}/rooms/{}", base_url, room_id),
+            S!(room, "name"),
+            &format!("{}/rooms/{}/messages", base_url, room_id),
+            settings.slow_mode_seconds,
+            settings.duplicate_window_seconds,
+        ));
+        for message in
+            db::get_messages_since(&dynamodb, room_id, since, sync::PAGE_SIZE + 1).await?
+        {
+            messages.push((room_id.to_owned(), message));
+        }
+    }
+
+    // Oldest first across all of the rooms, then cut down to a page. The next
+    // page starts at the last message on this one.
+    messages.sort_by(|(_, a), (_, b)| {
+        let sort = |message: &HashMap<String, AttributeValue>| {
+            message
+                .get("sort")
+                .and_then(|sort| sort.as_s().ok())
+                .cloned()
+        };
+        sort(a).cmp(&sort(b))
+    });
+    let has_more = messages.len() > sync::PAGE_SIZE;
+    messages.truncate(sync::PAGE_SIZE);
+    let token = match messages.last() {
+        Some((_, message)) if has_more => {
+            Token::from_sort_key(S!(message, "sort")).unwrap_or(since)
+        }
+        _ => settled.max(since),
+    };
+
+    let mut m = Vec::new();
+    for (room_id, message) in messages {
+        let date_time = &S!(message, "sort")[8..];
+        m.push(Object::message(
+            &format!(
+                "{}/rooms/{}/messages/{}",
+                base_url,
+                room_id,
+                S!(message, "sort")
+            ),
+            date_time,
+            S!(message, "sender_name"),
+            S!(message, "message"),
+            &format!("{}/rooms/{}", base_url, room_id),
+            &format!("{}/users/{}", base_url, N!(message, "sender_id")),
+        ));
+    }
+
+    Ok(Negotiated(
+        format,
+        Changes::new(
+            &id,
+            ChangesProperties {
+                token: token.to_string(),
+                resync: false,
+                has_more,
+                rooms,
+                messages: m,
+            },
+        ),
+    ))
+}
+
+/// Says that the API is up. It doesn't check anything, see `health` for
+/// checks that do.
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "meta",
+    responses((status = 200, description = "The API is up", body = String, content_type = "text/plain"))
+)]
+async fn status() -> &'static str {
+    "OK"
+}
+
+/// Returns the site map: a link to every route, generated from the route
+/// table in `main`. Routes with parameters are RFC 6570 URI templates.
+///
+/// ```http
+/// GET /
+/// ```
+///
+/// ```http
+/// 200 OK
+/// Content-Type: application/json
+///
+/// {
+///   "id": "http://localhost:5050/",
+///   "properties": {
+///     "messages": "http://localhost:5050/rooms/{room_id}/messages",
+///     "rooms": "http://localhost:5050/rooms",
+///     ...
+///   }
+/// }
+/// ```
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "meta",
+    responses(
+        (status = 200, description = "Links to every route", body = Root),
+    )
+)]
+async fn hateos(
+    BaseUrl(base_url): BaseUrl,
+    format: Format,
+    extract::Extension(links): extract::Extension<Links>,
+) -> Negotiated<Root> {
+    // The root of the API is `/`, but the root of a version is `/v1`, not
+    // `/v1/`.
+    let id = match base_url.parse::<Uri>() {
+        Ok(uri) if uri.path() != "/" => base_url.clone(),
+        _ => format!("{}/", base_url),
+    };
+    Negotiated(format, Object::root(&id, links.resolve(&base_url)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use http::{
+        header::{AUTHORIZATION, CONTENT_LENGTH, ETAG, IF_NONE_MATCH},
+        Method, Request, Response,
+    };
+    use limits::Limits;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    const ADMIN_TOKEN: &str = "an-admin-token-for-tests";
+
+    /// The app, talking to a DynamoDB that takes connections and never
+    /// answers, so a handler that calls it doesn't finish.
+    async fn app_with(limits: Limits) -> Router {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+        app_using(port, limits).await
+    }
+
+    /// The app, talking to a DynamoDB that answers every call with `output`.
+    async fn app_answered_with(output: &'static str) -> Router {
+        let dynamodb = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            tower::make::Shared::new(tower::service_fn(move |_: Request<Body>| async move {
+                Response::builder()
+                    .header(CONTENT_TYPE, "application/x-amz-json-1.0")
+                    .body(Body::from(output))
+            })),
+        );
+        let port = dynamodb.local_addr().port();
+        tokio::spawn(dynamodb);
+        app_using(port, Limits::default()).await
+    }
+
+    async fn app_using(port: u16, limits: Limits) -> Router {
+        let config = Config {
+            limits,
+            admin_token: toml::from_str::<Config>(&format!("admin_token = {:?}", ADMIN_TOKEN))
+                .unwrap()
+                .admin_token,
+            dynamodb: toml::from_str(&format!(
+                r#"
+                region = "us-east-1"
+                hostname = "127.0.0.1"
+                port = {}
+                access_key_id = "test"
+                secret_access_key = "test"
+                "#,
+                port
+            ))
+            .unwrap(),
+            ..Config::default()
+        };
+        let dynamodb = Db {
+            client: dynamodb_client(&config.dynamodb).await,
+            tables: config.tables.prefixed(),
+        };
+        app(&config, dynamodb)
+    }
+
+    fn request(method: Method, uri: &str, body: impl Into<Body>) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.into())
+            .unwrap()
+    }
+
+    /// Fills in a URI template's parameters.
+    fn expand(template: &str) -> String {
+        template
+            .split('/')
+            .map(|segment| {
+                if segment.starts_with('{') && segment.ends_with('}') {
+                    "1"
+                } else {
+                    segment
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    async fn status(app: &Router, request: Request<Body>) -> StatusCode {
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    /// Sends a request whose body never finishes arriving, and leaves it to
+    /// be handled in the background. Dropping the sender ends the body.
+    async fn stall(app: &Router, uri: &str) -> hyper::body::Sender {
+        let (sender, body) = Body::channel();
+        tokio::spawn(app.clone().oneshot(request(Method::POST, uri, body)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        sender
+    }
+
+    /// A sign-up that waits on DynamoDB, left to be handled in the background.
+    async fn hang(app: &Router) {
+        let sign_up = request(Method::POST, "/sign-up", r#"{"name":"Ryan"}"#);
+        tokio::spawn(app.clone().oneshot(sign_up));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_get_413() {
+        let app = app_with(Limits {
+            max_body_bytes: 64,
+            ..Limits::default()
+        })
+        .await;
+        let body = format!(r#"{{"name":"{}"}}"#, "a".repeat(100));
+
+        let mut said_up_front = request(Method::POST, "/sign-up", body.clone());
+        said_up_front
+            .headers_mut()
+            .insert(CONTENT_LENGTH, body.len().into());
+        assert_eq!(
+            status(&app, said_up_front).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let (mut sender, chunked) = Body::channel();
+        let sending = tokio::spawn(async move {
+            for chunk in body.as_bytes().chunks(40) {
+                if sender.send_data(chunk.to_vec().into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        assert_eq!(
+            status(&app, request(Method::POST, "/sign-up", chunked)).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        sending.await.unwrap();
+
+        // Imports have a limit of their own, but only for the admin.
+        let import = || request(Method::POST, "/admin/imports", "x".repeat(100));
+        assert_eq!(status(&app, import()).await, StatusCode::UNAUTHORIZED);
+        let mut admin = import();
+        admin.headers_mut().insert(
+            AUTHORIZATION,
+            format!("Bearer {}", ADMIN_TOKEN).parse().unwrap(),
+        );
+        assert_ne!(status(&app, admin).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    /// Only the admin gets to send a big body, so anyone else is turned away
+    /// before any of it is read, however big they say it is.
+    #[tokio::test]
+    async fn imports_are_authorized_before_they_are_read() {
+        let app = app_with(Limits::default()).await;
+
+        let (_sender, body) = Body::channel();
+        let mut import = request(Method::POST, "/admin/imports?source=slack", body);
+        import
+            .headers_mut()
+            .insert(CONTENT_LENGTH, (60 * 1024 * 1024).into());
+        let response = tokio::time::timeout(Duration::from_secs(1), app.oneshot(import))
+            .await
+            .expect("the body was waited for")
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    /// A big export takes longer to upload than other requests get.
+    #[tokio::test]
+    async fn big_imports_have_time_to_upload() {
+        let app = app_with(Limits {
+            request_timeout: Duration::from_millis(100),
+            ..Limits::default()
+        })
+        .await;
+
+        let (mut sender, body) = Body::channel();
+        let sending = tokio::spawn(async move {
+            let chunk = vec![b' '; 256 * 1024];
+            for _ in 0..16 {
+                sender.send_data(chunk.clone().into()).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        let mut import = request(Method::POST, "/admin/imports?source=discord", body);
+        import.headers_mut().insert(
+            AUTHORIZATION,
+            format!("Bearer {}", ADMIN_TOKEN).parse().unwrap(),
+        );
+        // All 4 MiB arrive, and are read as a (broken) Discord export.
+        assert_eq!(status(&app, import).await, StatusCode::BAD_REQUEST);
+        sending.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn slow_requests_get_408() {
+        let app = app_with(Limits {
+            request_timeout: Duration::from_millis(200),
+            ..Limits::default()
+        })
+        .await;
+
+        let sign_up = request(Method::POST, "/sign-up", r#"{"name":"Ryan"}"#);
+        assert_eq!(status(&app, sign_up).await, StatusCode::REQUEST_TIMEOUT);
+
+        let (_sender, body) = Body::channel();
+        let slow_body = request(Method::POST, "/sign-up", body);
+        assert_eq!(status(&app, slow_body).await, StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_limit_get_503() {
+        let app = app_with(Limits {
+            max_concurrent_requests: 1,
+            ..Limits::default()
+        })
+        .await;
+
+        let sender = stall(&app, "/sign-up").await;
+        let get_status = || request(Method::GET, "/status", Body::empty());
+        assert_eq!(
+            status(&app, get_status()).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        drop(sender);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(status(&app, get_status()).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn writes_over_the_limit_get_503() {
+        let app = app_with(Limits {
+            max_concurrent_writes: 1,
+            ..Limits::default()
+        })
+        .await;
+
+        hang(&app).await;
+        for uri in ["/sign-up", "/v1/sign-up"] {
+            let sign_up = request(Method::POST, uri, r#"{"name":"Peter"}"#);
+            assert_eq!(status(&app, sign_up).await, StatusCode::SERVICE_UNAVAILABLE);
+        }
+        // Other routes have limits of their own.
+        let get_status = request(Method::GET, "/status", Body::empty());
+        assert_eq!(status(&app, get_status).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn root_document_links_to_real_routes() {
+        let app = app_with(Limits::default()).await;
+        for root in ["/", "/v1", "/v2"] {
+            let response = app
+                .clone()
+                .oneshot(request(Method::GET, root, Body::empty()))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let document: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            // v2 puts the links next to the JSON-LD keywords, rather than
+            // under `properties`.
+            let links = document
+                .get("properties")
+                .unwrap_or(&document)
+                .as_object()
+                .unwrap();
+            assert!(links.contains_key("rooms"), "{}", document);
+
+            for (name, template) in links.iter().filter(|(name, _)| !name.starts_with('@')) {
+                let template = template.as_str().unwrap();
+                let path = template.strip_prefix("http://api").unwrap();
+                assert!(
+                    path.starts_with(root.trim_end_matches('/')),
+                    "{} is outside {}",
+                    template,
+                    root
+                );
+                // No route takes TRACE, so a route that exists says 405, and
+                // one that doesn't says 404, without running a handler.
+                let trace = request(Method::TRACE, &expand(path), Body::empty());
+                assert_eq!(
+                    status(&app, trace).await,
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "{} ({}) isn't a route",
+                    name,
+                    template
+                );
+            }
+        }
+    }
+
+    /// The OpenAPI drift check goes by the methods each route lists, so they
+    /// have to be the ones it handles.
+    #[tokio::test]
+    async fn routes_handle_the_methods_they_list() {
+        let app = app_with(Limits {
+            request_timeout: Duration::from_millis(100),
+            ..Limits::default()
+        })
+        .await;
+        let (_, links) = routes::build(routes(&Config::default()));
+        for (template, methods) in links.templates() {
+            for method in [
+                Method::GET,
+                Method::PUT,
+                Method::POST,
+                Method::PATCH,
+                Method::DELETE,
+            ] {
+                let request = request(method.clone(), &expand(template), Body::empty());
+                let status = status(&app, request).await;
+                assert_eq!(
+                    status == StatusCode::METHOD_NOT_ALLOWED,
+                    !methods.contains(&method),
+                    "{} {} says {}, but the route lists {:?}",
+                    method,
+                    template,
+                    status,
+                    methods
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_that_have_the_response_get_304_without_a_body() {
+        let app = app_answered_with(r#"{"Item":{"user_id":{"N":"1"},"name":{"S":"Ryan"}}}"#).await;
+        let get = |if_none_match: Option<&str>| {
+            let mut request = request(Method::GET, "/users/1", Body::empty());
+            if let Some(if_none_match) = if_none_match {
+                request
+                    .headers_mut()
+                    .insert(IF_NONE_MATCH, if_none_match.parse().unwrap());
+            }
+            app.clone().oneshot(request)
+        };
+
+        let response = get(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[ETAG].to_str().unwrap().to_owned();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Ryan"));
+
+        for if_none_match in [etag.clone(), format!("\"other\", {}", etag), "*".into()] {
+            let response = get(Some(&if_none_match)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(response.headers()[ETAG], etag.as_str());
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert!(body.is_empty());
+        }
+
+        let response = get(Some("W/\"other\"")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn messages_to_invalid_room_ids_get_400_without_asking_dynamodb() {
+        let app = app_with(Limits::default()).await;
+        let put = request(
+            Method::PUT,
+            "/rooms/lobby/messages",
+            r#"{"message":"Hello","sender_id":"http://api/users/"}"#,
+        );
+        let response = tokio::time::timeout(Duration::from_secs(5), app.oneshot(put))
+            .await
+            .expect("the handler waited on DynamoDB")
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let fields: Vec<_> = body["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| field["field"].as_str().unwrap())
+            .collect();
+        assert_eq!(fields, ["room_id", "sender_id"], "{}", body);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{config::Config, errors::ChatError, import};
+use axum::{
+    body::Body, error_handling::HandleErrorLayer, middleware::Next, response::Response,
+    routing::MethodRouter, BoxError,
+};
+use http::{header::CONTENT_LENGTH, Method, Request, StatusCode};
+use hyper::body::HttpBody;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tower::{limit::GlobalConcurrencyLimitLayer, load_shed::LoadShedLayer, ServiceBuilder};
+
+/// Limits that protect the server from clients that send too much, too
+/// slowly, or too often at once.
+///
+/// - `max_body_bytes`: largest request body accepted. Defaults to 16 KiB,
+///   which is plenty for a 2000 character message.
+/// - `max_import_bytes`: the same, but for exports uploaded to
+///   `POST /admin/imports`. Defaults to 64 MiB.
+/// - `request_timeout_seconds`: how long a request (reading the body included)
+///   can take before it's answered with a 408. Defaults to 10.
+/// - `import_timeout_seconds`: the same, but for `POST /admin/imports`, which
+///   has a big export to upload and unzip. Defaults to 600.
+/// - `header_read_timeout_seconds`: how long a client has to finish sending
+///   request headers before the connection is closed. This is what stops
+///   slow-loris clients. Defaults to 5.
+/// - `max_concurrent_requests`: requests handled at once across the whole
+///   server before new ones get a 503. Defaults to 512.
+/// - `max_concurrent_writes`: the same, but for each route that writes to
+///   DynamoDB. Defaults to 64.
+/// - `drain_timeout_seconds`: on shutdown, how long requests in flight get to
+///   finish before they're dropped. New connections aren't accepted in the
+///   meantime. Defaults to 15, a bit longer than a request can take.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_body_bytes: usize,
+    pub max_import_bytes: usize,
+    #[serde(rename = "request_timeout_seconds", with = "crate::config::seconds")]
+    pub request_timeout: Duration,
+    #[serde(rename = "import_timeout_seconds", with = "crate::config::seconds")]
+    pub import_timeout: Duration,
+    #[serde(
+        rename = "header_read_timeout_seconds",
+        with = "crate::config::seconds"
+    )]
+    pub header_read_timeout: Duration,
+    pub max_concurrent_requests: usize,
+    pub max_concurrent_writes: usize,
+    #[serde(rename = "drain_timeout_seconds", with = "crate::config::seconds")]
+    pub drain_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 16 * 1024,
+            max_import_bytes: 64 * 1024 * 1024,
+            request_timeout: Duration::from_secs(10),
+            import_timeout: Duration::from_secs(600),
+            header_read_timeout: Duration::from_secs(5),
+            max_concurrent_requests: 512,
+            max_concurrent_writes: 64,
+            drain_timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Turns errors from the tower load shedding layers into the same kind of
+/// error response as everything else.
+pub async fn handle_error(error: BoxError) -> ChatError {
+    if error.is::<tower::load_shed::error::Overloaded>() {
+        ChatError::with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Server is busy, try again".into(),
+        )
+    } else {
+        ChatError::new(Some(format!("{:?}", error)), "Internal server error".into())
+    }
+}
+
+/// Caps how many requests a single route handles at once. Requests over the
+/// cap are turned away with a 503 instead of waiting in line, because a
+/// client that is going to retry anyway is better off knowing right away.
+pub fn concurrency_limit(route: MethodRouter, max: usize) -> MethodRouter {
+    route.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(handle_error))
+            .layer(LoadShedLayer::new())
+            .layer(GlobalConcurrencyLimitLayer::new(max)),
+    )
+}
+
+/// Whether a request is for `POST /admin/imports`, in any version.
+fn is_import(request: &Request<Body>) -> bool {
+    request.method() == Method::POST && request.uri().path().ends_with(import::IMPORTS_PATH)
+}
+
+/// Middleware that answers requests that take longer than
+/// `Limits::request_timeout`, reading the body included, with a 408. Imports
+/// get `import_timeout` instead.
+pub async fn limit_time(request: Request<Body>, next: Next<Body>) -> Result<Response, ChatError> {
+    let timeout = match request.extensions().get::<Limits>() {
+        Some(limits) if is_import(&request) => limits.import_timeout,
+        Some(limits) => limits.request_timeout,
+        None => return Ok(next.run(request).await),
+    };
+    tokio::time::timeout(timeout, next.run(request))
+        .await
+        .map_err(|_| {
+            ChatError::with_status(StatusCode::REQUEST_TIMEOUT, "Request timed out".into())
+        })
+}
+
+/// Middleware that rejects request bodies over `Limits::max_body_bytes` with a
+/// 413. Requests that say how big they are up front are rejected before
+/// anything is read, and chunked requests are cut off as soon as they go over.
+///
+/// Request bodies in this API are small JSON documents, so the body is
+/// buffered here and handed on as a whole. The exception is imports, which
+/// are as big as the export being imported, and get `max_import_bytes`. Only
+/// the admin can send those, so the admin token is checked before any of the
+/// body is read.
+pub async fn limit_body(request: Request<Body>, next: Next<Body>) -> Result<Response, ChatError> {
+    let limits = match request.extensions().get::<Limits>() {
+        Some(limits) => limits.clone(),
+        None => return Ok(next.run(request).await),
+    };
+    let max = if is_import(&request) {
+        let token = request
+            .extensions()
+            .get::<Arc<Config>>()
+            .and_then(|config| config.admin_token.clone());
+        import::authorize(token.as_ref(), request.headers())?;
+        limits.max_import_bytes
+    } else {
+        limits.max_body_bytes
+    };
+    let too_large = || {
+        ChatError::with_status(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Request body can be at most {} bytes", max),
+        )
+    };
+
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > max) {
+        return Err(too_large());
+    }
+
+    let (parts, mut body) = request.into_parts();
+    // Content-Length is only what the client claims, so it doesn't get to
+    // decide how much is allocated before anything arrives.
+    let mut bytes = Vec::with_capacity(content_length.unwrap_or(0).min(limits.max_body_bytes));
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| {
+            ChatError::with_status(
+                StatusCode::BAD_REQUEST,
+                "Could not read request body".into(),
+            )
+        })?;
+        if bytes.len() + chunk.len() > max {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
@@ -0,0 +1,307 @@
+use crate::{db::RoomSettings, versioning::RequestedVersion};
+use axum::{
+    body::Body,
+    extract::MatchedPath,
+    middleware::Next,
+    response::{Headers, IntoResponse, Response},
+};
+use http::{header::CONTENT_TYPE, Request};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::{
+    sync::LazyLock,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::Service;
+
+/// Everything the API counts, served at `/metrics` in the Prometheus text
+/// format.
+///
+/// - `http_requests_total`: requests by `route`, `method`, `status` and
+///   `api_version`. `route` is the route's path template without the version
+///   prefix, like `/rooms/:room_id/messages`.
+/// - `http_request_duration_seconds`: how long requests took, by `route`,
+///   `method` and `api_version`.
+/// - `http_requests_in_flight`: requests being handled right now.
+/// - `http_connections`: open client connections.
+/// - `dynamodb_request_duration_seconds`: how long DynamoDB calls took, by
+///   `operation` (`GetItem`, `Query`, ...) and `table`.
+/// - `dynamodb_errors_total`: failed DynamoDB calls by `operation`, `table`
+///   and `code`. Flood control and idempotency keys rely on
+///   `ConditionalCheckFailedException`, so those are expected.
+/// - `messages_posted_total`: messages posted, by `room_class`, see
+///   `room_class`.
+/// - `rate_limit_errors_total`: requests let through without being rate
+///   limited, by `group`, because the rate limits table couldn't be reached.
+struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    requests_in_flight: IntGauge,
+    connections: IntGauge,
+    dynamodb_duration: HistogramVec,
+    dynamodb_errors: IntCounterVec,
+    messages_posted: IntCounterVec,
+    rate_limit_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+        let metrics = Self {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests"),
+                &["route", "method", "status", "api_version"],
+            )?,
+            request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "How long HTTP requests took",
+                ),
+                &["route", "method", "api_version"],
+            )?,
+            requests_in_flight: IntGauge::new(
+                "http_requests_in_flight",
+                "HTTP requests being handled",
+            )?,
+            connections: IntGauge::new("http_connections", "Open client connections")?,
+            dynamodb_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "dynamodb_request_duration_seconds",
+                    "How long DynamoDB calls took",
+                ),
+                &["operation", "table"],
+            )?,
+            dynamodb_errors: IntCounterVec::new(
+                Opts::new("dynamodb_errors_total", "Failed DynamoDB calls"),
+                &["operation", "table", "code"],
+            )?,
+            messages_posted: IntCounterVec::new(
+                Opts::new("messages_posted_total", "Messages posted"),
+                &["room_class"],
+            )?,
+            rate_limit_errors: IntCounterVec::new(
+                Opts::new(
+                    "rate_limit_errors_total",
+                    "Requests let through because rate limits couldn't be checked",
+                ),
+                &["group"],
+            )?,
+            registry,
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.requests.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.request_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.requests_in_flight.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.connections.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.dynamodb_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.dynamodb_errors.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.messages_posted.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.rate_limit_errors.clone()))?;
+        Ok(metrics)
+    }
+}
+
+// The names and labels are fixed, so this can only fail if they're wrong.
+static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("metrics should be valid"));
+
+/// The route a request was routed to, put in the response's extensions by
+/// `route`.
+#[derive(Clone)]
+struct RouteLabel(String);
+
+/// Middleware for a route tree. Remembers which route the request matched, for
+/// `track` further out to label it with. Outside of the route tree, only the
+/// version prefix has been matched.
+pub async fn route(request: Request<Body>, next: Next<Body>) -> Response {
+    let label = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| RouteLabel(crate::versioning::unversioned_path(path.as_str()).to_owned()));
+    let mut response = next.run(request).await;
+    if let Some(label) = label {
+        response.extensions_mut().insert(label);
+    }
+    response
+}
+
+/// Middleware that counts and times every request, including the ones that
+/// are turned away before they get to a route, like rate limited ones.
+pub async fn track(request: Request<Body>, next: Next<Body>) -> Response {
+    let method = request.method().clone();
+    let matched = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned());
+    let start = Instant::now();
+    let in_flight = InFlight::new();
+    let response = next.run(request).await;
+    drop(in_flight);
+
+    // Requests that didn't match a route are lumped together, so that
+    // scanners trying random paths can't make up new series. Out here, a
+    // request under `/v1` or `/v2` has only matched the version prefix, so one
+    // that was turned away before it got further is counted as `/v1/*`.
+    let route = match (response.extensions().get::<RouteLabel>(), matched) {
+        (Some(RouteLabel(route)), _) => route.clone(),
+        (None, Some(matched)) => match matched.split_once("/*") {
+            Some((prefix, _)) => format!("{}/*", prefix),
+            None => matched,
+        },
+        (None, None) => "unmatched".into(),
+    };
+    let api_version = response
+        .extensions()
+        .get::<RequestedVersion>()
+        .map_or("", |requested| requested.version.as_str());
+    METRICS
+        .requests
+        .with_label_values(&[
+            &route,
+            method.as_str(),
+            response.status().as_str(),
+            api_version,
+        ])
+        .inc();
+    METRICS
+        .request_duration
+        .with_label_values(&[&route, method.as_str(), api_version])
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
+
+/// Counts a request as in flight for as long as it's alive. A request whose
+/// client hangs up is dropped halfway, without running to the end of `track`,
+/// so the count goes down on drop.
+struct InFlight;
+
+impl InFlight {
+    fn new() -> Self {
+        METRICS.requests_in_flight.inc();
+        Self
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        METRICS.requests_in_flight.dec();
+    }
+}
+
+/// How many requests are being handled right now.
+pub fn requests_in_flight() -> i64 {
+    METRICS.requests_in_flight.get()
+}
+
+/// Records a DynamoDB call. `code` is the error code if it failed.
+pub fn dynamodb_call(operation: &str, table: &str, latency: Duration, code: Option<&str>) {
+    METRICS
+        .dynamodb_duration
+        .with_label_values(&[operation, table])
+        .observe(latency.as_secs_f64());
+    if let Some(code) = code {
+        METRICS
+            .dynamodb_errors
+            .with_label_values(&[operation, table, code])
+            .inc();
+    }
+}
+
+/// Counts a message posted in a room with these settings.
+pub fn message_posted(settings: &RoomSettings) {
+    METRICS
+        .messages_posted
+        .with_label_values(&[room_class(settings)])
+        .inc();
+}
+
+/// Counts a request in `group` that was let through without being rate
+/// limited.
+pub fn rate_limit_error(group: &str) {
+    METRICS.rate_limit_errors.with_label_values(&[group]).inc();
+}
+
+/// Rooms are told apart by their flood control, since per room counts would
+/// make a new series for every room.
+///
+/// - `slow_mode`: slow mode is on, whether or not duplicates are suppressed.
+/// - `duplicate_window`: only duplicates are suppressed.
+/// - `open`: neither.
+fn room_class(settings: &RoomSettings) -> &'static str {
+    if settings.slow_mode_seconds > 0 {
+        "slow_mode"
+    } else if settings.duplicate_window_seconds > 0 {
+        "duplicate_window"
+    } else {
+        "open"
+    }
+}
+
+/// Wraps the service for each client connection, to count how many are open.
+/// Used with `ServiceExt::map_response` on the make service.
+pub struct Connection<S> {
+    inner: S,
+}
+
+impl<S> Connection<S> {
+    pub fn new(inner: S) -> Self {
+        METRICS.connections.inc();
+        Self { inner }
+    }
+}
+
+impl<S> Drop for Connection<S> {
+    fn drop(&mut self) {
+        METRICS.connections.dec();
+    }
+}
+
+impl<S, R> Service<R> for Connection<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        self.inner.call(request)
+    }
+}
+
+/// Serves the metrics in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "meta",
+    responses((status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"))
+)]
+pub async fn metrics() -> impl IntoResponse {
+    let mut buffer = Vec::new();
+    if let Err(error) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer) {
+        tracing::error!(%error, "Could not encode metrics");
+    }
+    (Headers([(CONTENT_TYPE, prometheus::TEXT_FORMAT)]), buffer)
+}
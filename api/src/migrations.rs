@@ -0,0 +1,323 @@
+use crate::{
+    db::{self, Db},
+    errors::ChatError,
+    validation,
+};
+use aws_sdk_dynamodb::{model::AttributeValue, types::SdkError};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long an instance has to finish a migration before another one takes
+/// it over, assuming the first one died.
+const LEASE: Duration = Duration::from_secs(10 * 60);
+
+/// How often to check on a migration another instance is running.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+type Run = for<'a> fn(&'a Db) -> Pin<Box<dyn Future<Output = Result<(), ChatError>> + Send + 'a>>;
+
+/// A change to the data, as opposed to the tables (see `schema`).
+struct Migration {
+    /// Migrations run in order of version, and each runs once. Never reuse or
+    /// reorder a version once it has shipped.
+    version: u32,
+    name: &'static str,
+    run: Run,
+}
+
+/// Every migration, oldest first.
+///
+/// An instance that dies halfway through a migration leaves it to be run
+/// again, from the start, so migrations have to be safe to run twice.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "backfill users.name_key",
+        run: |dynamodb| Box::pin(backfill_name_keys(dynamodb)),
+    },
+    Migration {
+        version: 2,
+        name: "claim user_names",
+        run: |dynamodb| Box::pin(claim_user_names(dynamodb)),
+    },
+];
+
+/// Runs the migrations that haven't run yet, recording each in the
+/// migrations table once it has. When several instances start at once, one
+/// runs each migration and the others wait for it.
+pub async fn run(dynamodb: &Db) -> Result<(), ChatError> {
+    for migration in MIGRATIONS {
+        loop {
+            if finished(dynamodb, migration.version).await? {
+                break;
+            }
+            if !claim(dynamodb, migration).await? {
+                tracing::info!(
+                    version = migration.version,
+                    name = migration.name,
+                    "Waiting for another instance to finish a migration"
+                );
+                tokio::time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
+
+            tracing::info!(
+                version = migration.version,
+                name = migration.name,
+                "Running migration"
+            );
+            if let Err(error) = (migration.run)(dynamodb).await {
+                // Let the next instance to start try again, rather than
+                // waiting for the lease to run out.
+                release(dynamodb, migration.version).await?;
+                return Err(ChatError::new(
+                    Some(format!(
+                        "Migration {} ({}) failed: {}",
+                        migration.version,
+                        migration.name,
+                        error.debug.as_deref().unwrap_or(&error.display)
+                    )),
+                    error.display,
+                ));
+            }
+            finish(dynamodb, migration.version).await?;
+            tracing::info!(
+                version = migration.version,
+                name = migration.name,
+                "Ran migration"
+            );
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+async fn finished(dynamodb: &Db, version: u32) -> Result<bool, ChatError> {
+    let output = db::call(
+        "GetItem",
+        &dynamodb.tables.migrations,
+        dynamodb
+            .client
+            .get_item()
+            .table_name(&dynamodb.tables.migrations)
+            .key("version", AttributeValue::N(version.to_string()))
+            .consistent_read(true)
+            .send(),
+    )
+    .await?;
+    Ok(output
+        .item
+        .is_some_and(|item| item.contains_key("finished_at")))
+}
+
+/// Puts an item for the migration, unless another instance is running it and
+/// its lease hasn't run out. Returns whether this instance got it.
+async fn claim(dynamodb: &Db, migration: &Migration) -> Result<bool, ChatError> {
+    let now = now();
+    let result = db::call(
+        "PutItem",
+        &dynamodb.tables.migrations,
+        dynamodb
+            .client
+            .put_item()
+            .table_name(&dynamodb.tables.migrations)
+            .item("version", AttributeValue::N(migration.version.to_string()))
+            .item("name", AttributeValue::S(migration.name.to_owned()))
+            .item("started_at", AttributeValue::N(now.as_secs().to_string()))
+            .item(
+                "lease_expires_at",
+                AttributeValue::N((now + LEASE).as_secs().to_string()),
+            )
+            .condition_expression(
+                "attribute_not_exists(version) OR \
+                 (attribute_not_exists(finished_at) AND lease_expires_at < :now)",
+            )
+            .expression_attribute_values(":now", AttributeValue::N(now.as_secs().to_string()))
+            .send(),
+    )
+    .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(SdkError::ServiceError { err, .. }) if err.is_conditional_check_failed_exception() => {
+            Ok(false)
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+async fn finish(dynamodb: &Db, version: u32) -> Result<(), ChatError> {
+    db::call(
+        "UpdateItem",
+        &dynamodb.tables.migrations,
+        dynamodb
+            .client
+            .update_item()
+            .table_name(&dynamodb.tables.migrations)
+            .key("version", AttributeValue::N(version.to_string()))
+            .update_expression("SET finished_at = :now REMOVE lease_expires_at")
+            .expression_attribute_values(":now", AttributeValue::N(now().as_secs().to_string()))
+            .send(),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn release(dynamodb: &Db, version: u32) -> Result<(), ChatError> {
+    db::call(
+        "DeleteItem",
+        &dynamodb.tables.migrations,
+        dynamodb
+            .client
+            .delete_item()
+            .table_name(&dynamodb.tables.migrations)
+            .key("version", AttributeValue::N(version.to_string()))
+            .send(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Users created before `name_key` existed can't be found through
+/// `name-key-index`, so names that only differ by case or look-alike
+/// characters could still be taken twice. Gives them all a `name_key`.
+async fn backfill_name_keys(dynamodb: &Db) -> Result<(), ChatError> {
+    let table = &dynamodb.tables.users;
+    let mut start: Option<HashMap<String, AttributeValue>> = None;
+    let mut backfilled = 0;
+    loop {
+        let output = db::call(
+            "Scan",
+            table,
+            dynamodb
+                .client
+                .scan()
+                .table_name(table)
+                .filter_expression("attribute_not_exists(name_key)")
+                .projection_expression("user_id, #n")
+                .expression_attribute_names("#n", "name")
+                .set_exclusive_start_key(start)
+                .send(),
+        )
+        .await?;
+
+        for user in output.items.unwrap_or_default() {
+            let (user_id, name) = match (user.get("user_id"), user.get("name")) {
+                (Some(user_id), Some(AttributeValue::S(name))) => (user_id.clone(), name),
+                _ => continue,
+            };
+            let result = db::call(
+                "UpdateItem",
+                table,
+                dynamodb
+                    .client
+                    .update_item()
+                    .table_name(table)
+                    .key("user_id", user_id)
+                    .update_expression("SET name_key = :k")
+                    .condition_expression(
+                        "attribute_exists(user_id) AND attribute_not_exists(name_key)",
+                    )
+                    .expression_attribute_values(
+                        ":k",
+                        AttributeValue::S(validation::name_key(name)),
+                    )
+                    .send(),
+            )
+            .await;
+            match result {
+                Ok(_) => backfilled += 1,
+                // Signed up again or given a key in between.
+                Err(SdkError::ServiceError { err, .. })
+                    if err.is_conditional_check_failed_exception() => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        start = output.last_evaluated_key;
+        if start.is_none() {
+            break;
+        }
+    }
+    tracing::info!(backfilled, "Backfilled name keys");
+    Ok(())
+}
+
+/// Users created before the user names table existed haven't claimed their
+/// names in it, so anybody could sign up with them. Claims them all. When two
+/// users already have names that look alike, the first one found gets it, and
+/// the other is logged.
+async fn claim_user_names(dynamodb: &Db) -> Result<(), ChatError> {
+    let table = &dynamodb.tables.users;
+    let mut start: Option<HashMap<String, AttributeValue>> = None;
+    let mut claimed = 0;
+    loop {
+        let output = db::call(
+            "Scan",
+            table,
+            dynamodb
+                .client
+                .scan()
+                .table_name(table)
+                .filter_expression("attribute_exists(name_key)")
+                .projection_expression("user_id, #n, name_key")
+                .expression_attribute_names("#n", "name")
+                .set_exclusive_start_key(start)
+                .send(),
+        )
+        .await?;
+
+        for user in output.items.unwrap_or_default() {
+            let (user_id, name, name_key) =
+                match (user.get("user_id"), user.get("name"), user.get("name_key")) {
+                    (Some(user_id), Some(name), Some(name_key)) => (user_id, name, name_key),
+                    _ => continue,
+                };
+            let result = db::call(
+                "PutItem",
+                &dynamodb.tables.user_names,
+                dynamodb
+                    .client
+                    .put_item()
+                    .table_name(&dynamodb.tables.user_names)
+                    .item("name_key", name_key.clone())
+                    .item("user_id", user_id.clone())
+                    .item("name", name.clone())
+                    .condition_expression("attribute_not_exists(name_key) OR user_id = :id")
+                    .expression_attribute_values(":id", user_id.clone())
+                    .send(),
+            )
+            .await;
+            match result {
+                Ok(_) => claimed += 1,
+                Err(SdkError::ServiceError { err, .. })
+                    if err.is_conditional_check_failed_exception() =>
+                {
+                    tracing::warn!(
+                        user_id = user_id.as_n().map(String::as_str).unwrap_or_default(),
+                        name_key = name_key.as_s().map(String::as_str).unwrap_or_default(),
+                        "Another user has a name that looks like this user's"
+                    );
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        start = output.last_evaluated_key;
+        if start.is_none() {
+            break;
+        }
+    }
+    tracing::info!(claimed, "Claimed user names");
+    Ok(())
+}
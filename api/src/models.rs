@@ -1,4 +1,14 @@
+use crate::json_ld::{self, JsonLd, LdType};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{borrow::Cow, collections::BTreeMap};
+use utoipa::{
+    openapi::{
+        schema::{ObjectBuilder, SchemaFormat, Type},
+        Ref, RefOr, Schema,
+    },
+    PartialSchema, ToSchema,
+};
 
 #[derive(Serialize)]
 pub struct Object<T>
@@ -9,7 +19,80 @@ where
     properties: T,
 }
 
-#[derive(Deserialize)]
+/// In JSON-LD, the properties sit next to `@id` and `@type` instead of being
+/// nested, and the type comes from the properties.
+impl<T: Serialize + LdType> JsonLd for Object<T> {
+    fn to_json_ld(&self) -> Value {
+        json_ld::node(&self.id, T::TYPE, &self.properties)
+    }
+}
+
+/// Objects are described in the OpenAPI document under the name of their
+/// `@type`, so that `Object<UserProperties>` is `User` rather than all of
+/// them sharing one `Object` schema.
+impl<T: Serialize + ToSchema + LdType> PartialSchema for Object<T> {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .property(
+                "id",
+                ObjectBuilder::new()
+                    .schema_type(Type::String)
+                    .format(Some(SchemaFormat::Custom("uri".into()))),
+            )
+            .required("id")
+            .property("properties", Ref::from_schema_name(T::name()))
+            .required("properties")
+            .into()
+    }
+}
+
+impl<T: Serialize + ToSchema + LdType> ToSchema for Object<T> {
+    fn name() -> Cow<'static, str> {
+        Cow::Borrowed(T::TYPE)
+    }
+
+    fn schemas(schemas: &mut Vec<(String, RefOr<Schema>)>) {
+        schemas.push((T::name().into(), T::schema()));
+        T::schemas(schemas);
+    }
+}
+
+/// A list of objects. As plain JSON it's just an array of them, in JSON-LD it
+/// becomes a `Collection` node of its own, with the objects as its `items`.
+pub struct Collection<T>
+where
+    T: Serialize,
+{
+    id: String,
+    items: Vec<Object<T>>,
+}
+
+impl<T: Serialize> Collection<T> {
+    pub fn new(id: &str, items: Vec<Object<T>>) -> Self {
+        Self {
+            id: id.to_owned(),
+            items,
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for Collection<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.items.serialize(serializer)
+    }
+}
+
+impl<T: Serialize + LdType> JsonLd for Collection<T> {
+    fn to_json_ld(&self) -> Value {
+        json!({
+            "@id": self.id,
+            "@type": "Collection",
+            "items": self.items.iter().map(JsonLd::to_json_ld).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct MessageRequest {
     pub message: String,
     pub sender_id: String,
@@ -17,7 +100,7 @@ pub struct MessageRequest {
 
 pub type Message = Object<MessageProperties>;
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct MessageProperties {
     pub date_time: String,
     pub sender_name: String,
@@ -26,6 +109,10 @@ pub struct MessageProperties {
     pub user: String,
 }
 
+impl LdType for MessageProperties {
+    const TYPE: &'static str = "Message";
+}
+
 impl Object<MessageProperties> {
     pub fn message(
         id: &str,
@@ -48,38 +135,62 @@ impl Object<MessageProperties> {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct NameRequest {
     pub name: String,
 }
 
 pub type Room = Object<RoomProperties>;
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct RoomProperties {
     pub name: String,
     pub messages: String,
+    pub slow_mode_seconds: u32,
+    pub duplicate_window_seconds: u32,
+}
+
+impl LdType for RoomProperties {
+    const TYPE: &'static str = "Room";
 }
 
 impl Object<RoomProperties> {
-    pub fn room(id: &str, name: &str, messages: &str) -> Self {
+    pub fn room(
+        id: &str,
+        name: &str,
+        messages: &str,
+        slow_mode_seconds: u32,
+        duplicate_window_seconds: u32,
+    ) -> Self {
         Self {
             id: id.to_owned(),
             properties: RoomProperties {
                 name: name.to_owned(),
                 messages: messages.to_owned(),
+                slow_mode_seconds,
+                duplicate_window_seconds,
             },
         }
     }
 }
 
+#[derive(Deserialize, ToSchema)]
+pub struct RoomSettingsRequest {
+    pub slow_mode_seconds: Option<u32>,
+    pub duplicate_window_seconds: Option<u32>,
+}
+
 pub type User = Object<UserProperties>;
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct UserProperties {
     pub name: String,
 }
 
+impl LdType for UserProperties {
+    const TYPE: &'static str = "User";
+}
+
 impl Object<UserProperties> {
     pub fn user(id: &str, name: &str) -> Self {
         Self {
@@ -90,3 +201,128 @@ impl Object<UserProperties> {
         }
     }
 }
+
+#[derive(Deserialize, ToSchema)]
+pub struct PresenceRequest {
+    pub user_id: String,
+    #[serde(default)]
+    pub typing: bool,
+}
+
+pub type Presence = Object<PresenceProperties>;
+
+#[derive(Serialize, ToSchema)]
+pub struct PresenceProperties {
+    pub room: String,
+    pub online: Vec<PresenceMember>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PresenceMember {
+    pub user: String,
+    pub name: String,
+    pub typing: bool,
+}
+
+impl LdType for PresenceProperties {
+    const TYPE: &'static str = "Presence";
+}
+
+impl Object<PresenceProperties> {
+    pub fn presence(id: &str, room: &str, online: Vec<PresenceMember>) -> Self {
+        Self {
+            id: id.to_owned(),
+            properties: PresenceProperties {
+                room: room.to_owned(),
+                online,
+            },
+        }
+    }
+}
+
+pub type Root = Object<RootProperties>;
+
+/// Links to everything the API can do, by name.
+#[derive(Serialize, ToSchema)]
+#[serde(transparent)]
+pub struct RootProperties(BTreeMap<String, String>);
+
+impl LdType for RootProperties {
+    const TYPE: &'static str = "EntryPoint";
+}
+
+impl Object<RootProperties> {
+    pub fn root(id: &str, links: BTreeMap<String, String>) -> Self {
+        Self {
+            id: id.to_owned(),
+            properties: RootProperties(links),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SyncQuery {
+    pub since: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub format: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    pub source: Option<String>,
+}
+
+/// What changed since a sync token, see `GET /sync`.
+#[derive(Serialize, ToSchema)]
+pub struct Changes {
+    #[schema(format = "uri")]
+    id: String,
+    properties: ChangesProperties,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChangesProperties {
+    /// Send this as `since` next time.
+    pub token: String,
+    /// The token was too old to catch up from, there wasn't one, or messages
+    /// were imported into or purged from a room since. Nothing else is
+    /// listed, and the client should load everything it shows from scratch,
+    /// and sync from `token` afterwards.
+    pub resync: bool,
+    /// There were more messages than fit in one response. Sync again from
+    /// `token` right away for the rest.
+    pub has_more: bool,
+    /// Rooms that were created, had their settings changed, or had messages
+    /// posted in them.
+    pub rooms: Vec<Room>,
+    /// New messages in those rooms, oldest first.
+    pub messages: Vec<Message>,
+}
+
+impl Changes {
+    pub fn new(id: &str, properties: ChangesProperties) -> Self {
+        Self {
+            id: id.to_owned(),
+            properties,
+        }
+    }
+}
+
+/// The rooms and messages are nodes of their own in JSON-LD.
+impl JsonLd for Changes {
+    fn to_json_ld(&self) -> Value {
+        let properties = &self.properties;
+        json!({
+            "@id": self.id,
+            "@type": "Changes",
+            "token": properties.token,
+            "resync": properties.resync,
+            "has_more": properties.has_more,
+            "rooms": properties.rooms.iter().map(JsonLd::to_json_ld).collect::<Vec<_>>(),
+            "messages": properties.messages.iter().map(JsonLd::to_json_ld).collect::<Vec<_>>(),
+        })
+    }
+}
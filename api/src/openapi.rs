@@ -0,0 +1,190 @@
+use crate::routes::Links;
+use axum::response::{Headers, Html, IntoResponse};
+use http::{header::CONTENT_TYPE, Method};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::OnceLock,
+};
+use utoipa::{openapi::path::ParameterIn, OpenApi};
+
+/// Where the OpenAPI document and Swagger UI are served.
+pub const OPENAPI_PATH: &str = "/openapi.json";
+pub const SWAGGER_UI_PATH: &str = "/docs";
+
+/// The OpenAPI 3.1 document, put together from the `#[utoipa::path]`
+/// attributes on the handlers and the `ToSchema` types in `models.rs`.
+///
+/// It describes the plain JSON responses. Every `200` can also be had as
+/// JSON-LD by asking for `application/ld+json`, see `json_ld`.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Chat API",
+        description = "Users, rooms and messages. Every path is served as is (an alias of v1), \
+            and under /v1 and /v2. v2 responds with JSON-LD unless plain JSON is asked for."
+    ),
+    paths(
+        crate::hateos,
+        crate::status,
+        crate::health::live,
+        crate::health::ready,
+        crate::metrics::metrics,
+        crate::sign_up,
+        crate::sign_in,
+        crate::get_user,
+        crate::get_rooms,
+        crate::put_room,
+        crate::patch_room,
+        crate::get_presence,
+        crate::put_presence,
+        crate::get_messages,
+        crate::put_message,
+        crate::get_message_by_id,
+        crate::get_export,
+        crate::get_sync,
+        crate::post_import,
+        crate::get_import,
+    )
+)]
+pub struct ApiDoc;
+
+/// The document. The package has no license, so the empty one utoipa fills
+/// in from Cargo.toml is left out.
+fn document() -> utoipa::openapi::OpenApi {
+    let mut document = ApiDoc::openapi();
+    document.info.license = None;
+    document
+}
+
+/// The document as JSON. It never changes while the server is running, so it
+/// is only generated once.
+pub fn json() -> &'static str {
+    static JSON: OnceLock<String> = OnceLock::new();
+    JSON.get_or_init(|| document().to_pretty_json().unwrap())
+}
+
+/// Serves the OpenAPI document.
+pub async fn openapi() -> impl IntoResponse {
+    (Headers([(CONTENT_TYPE, "application/json")]), json())
+}
+
+/// Serves Swagger UI for the OpenAPI document. Only routed when
+/// `swagger_ui` is turned on in the config. The page itself is tiny, Swagger
+/// UI's scripts and styles come from a CDN.
+pub async fn swagger_ui() -> Html<String> {
+    Html(format!(
+        r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>Chat API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({{ url: "{}", dom_id: "#swagger-ui" }});</script>
+</body>
+</html>
+"##,
+        OPENAPI_PATH
+    ))
+}
+
+/// Checks that the document describes exactly the routes the router serves,
+/// apart from the ones that aren't part of the API proper (the JSON-LD
+/// context, and the OpenAPI document and its UI): the same methods on the
+/// same paths, with the same path parameters. Returns a message for each
+/// difference.
+pub fn drift(links: &Links) -> Vec<String> {
+    let mut documented = BTreeMap::new();
+    for (path, item) in document().paths.paths {
+        let operations = [
+            (Method::GET, &item.get),
+            (Method::PUT, &item.put),
+            (Method::POST, &item.post),
+            (Method::DELETE, &item.delete),
+            (Method::OPTIONS, &item.options),
+            (Method::HEAD, &item.head),
+            (Method::PATCH, &item.patch),
+            (Method::TRACE, &item.trace),
+        ];
+        for (method, operation) in operations {
+            if let Some(operation) = operation {
+                let parameters: BTreeSet<String> = operation
+                    .parameters
+                    .iter()
+                    .chain(&item.parameters)
+                    .flatten()
+                    .filter(|parameter| parameter.parameter_in == ParameterIn::Path)
+                    .map(|parameter| parameter.name.clone())
+                    .collect();
+                documented.insert(format!("{} {}", method, path), parameters);
+            }
+        }
+    }
+
+    let mut routed = BTreeMap::new();
+    for (path, methods) in links.templates() {
+        if [crate::json_ld::CONTEXT_PATH, OPENAPI_PATH, SWAGGER_UI_PATH].contains(&path) {
+            continue;
+        }
+        let parameters: BTreeSet<String> = path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(str::to_owned)
+            .collect();
+        for method in methods {
+            routed.insert(format!("{} {}", method, path), parameters.clone());
+        }
+    }
+
+    let mut drift = Vec::new();
+    for (operation, parameters) in &routed {
+        match documented.get(operation) {
+            None => drift.push(format!(
+                "{} is routed but not in the OpenAPI document",
+                operation
+            )),
+            Some(documented) if documented != parameters => drift.push(format!(
+                "{} has path parameters {:?}, but the OpenAPI document has {:?}",
+                operation, parameters, documented
+            )),
+            Some(_) => {}
+        }
+    }
+    for operation in documented.keys() {
+        if !routed.contains_key(operation) {
+            drift.push(format!(
+                "{} is in the OpenAPI document but not routed",
+                operation
+            ));
+        }
+    }
+    drift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, routes};
+
+    #[test]
+    fn checked_in_copy_is_up_to_date() {
+        assert!(
+            json().trim_end() == include_str!("../openapi.json").trim_end(),
+            "api/openapi.json is out of date, regenerate it with \
+             `cargo run -q -- --print-openapi > openapi.json`"
+        );
+    }
+
+    #[test]
+    fn document_matches_the_routes() {
+        let config = Config {
+            swagger_ui: true,
+            ..Config::default()
+        };
+        let (_, links) = routes::build(crate::routes(&config));
+        assert_eq!(drift(&links), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a heartbeat keeps a user "online" in a room. Clients are expected
+/// to heartbeat a few times inside of this window.
+pub const HEARTBEAT_TTL: Duration = Duration::from_secs(30);
+
+/// How long a `typing` event lasts before it stops on its own. Clients don't
+/// have to tell us when somebody stops typing, they just stop sending events.
+pub const TYPING_TTL: Duration = Duration::from_secs(5);
+
+/// How often every room is pruned, rather than only the one being read or
+/// written, so that rooms nobody looks at anymore don't stay in memory.
+const SWEEP_INTERVAL: Duration = HEARTBEAT_TTL;
+
+/// Tracks who is online (and who is typing) in each room.
+///
+/// This state is ephemeral. Nobody cares who was typing in a room 10 minutes
+/// ago, so there's no reason to pay for DynamoDB writes to keep track of it.
+/// The trade-off is that each replica of the API has its own view of who is
+/// online, which is fine while we only run one of them.
+///
+/// Expired entries are pruned lazily whenever a room is read or written, and
+/// every room is swept on a heartbeat once `SWEEP_INTERVAL` has passed, so
+/// there is no background task to babysit.
+#[derive(Clone)]
+pub struct PresenceTracker {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    rooms: HashMap<String, HashMap<String, Member>>,
+    swept: Instant,
+}
+
+impl Default for PresenceTracker {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                rooms: HashMap::new(),
+                swept: Instant::now(),
+            })),
+        }
+    }
+}
+
+struct Member {
+    name: String,
+    seen: Instant,
+    typing_until: Option<Instant>,
+}
+
+/// A snapshot of a single user in a room, as returned by `PresenceTracker::members`.
+pub struct MemberState {
+    pub user_id: String,
+    pub name: String,
+    pub typing: bool,
+}
+
+impl PresenceTracker {
+    /// Marks a user as online in a room. If `typing` is set, the user is also
+    /// shown as typing for the next `TYPING_TTL`.
+    pub fn heartbeat(&self, room_id: &str, user_id: &str, name: &str, typing: bool) {
+        self.heartbeat_at(room_id, user_id, name, typing, Instant::now());
+    }
+
+    /// Called when a user sends a message. Sending a message is as good as a
+    /// heartbeat, and whatever they were typing has now been sent.
+    pub fn message_sent(&self, room_id: &str, user_id: &str, name: &str) {
+        self.message_sent_at(room_id, user_id, name, Instant::now());
+    }
+
+    /// Lists everybody currently online in a room.
+    pub fn members(&self, room_id: &str) -> Vec<MemberState> {
+        self.members_at(room_id, Instant::now())
+    }
+
+    fn heartbeat_at(&self, room_id: &str, user_id: &str, name: &str, typing: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if now.duration_since(state.swept) >= SWEEP_INTERVAL {
+            state.rooms.retain(|_, room| {
+                prune(room, now);
+                !room.is_empty()
+            });
+            state.swept = now;
+        }
+        let room = state.rooms.entry(room_id.to_owned()).or_default();
+        prune(room, now);
+
+        let member = room.entry(user_id.to_owned()).or_insert_with(|| Member {
+            name: name.to_owned(),
+            seen: now,
+            typing_until: None,
+        });
+        member.name = name.to_owned();
+        member.seen = now;
+        if typing {
+            member.typing_until = Some(now + TYPING_TTL);
+        }
+    }
+
+    fn message_sent_at(&self, room_id: &str, user_id: &str, name: &str, now: Instant) {
+        self.heartbeat_at(room_id, user_id, name, false, now);
+        let mut state = self.state.lock().unwrap();
+        if let Some(member) = state
+            .rooms
+            .get_mut(room_id)
+            .and_then(|room| room.get_mut(user_id))
+        {
+            member.typing_until = None;
+        }
+    }
+
+    fn members_at(&self, room_id: &str, now: Instant) -> Vec<MemberState> {
+        let mut state = self.state.lock().unwrap();
+        let room = match state.rooms.get_mut(room_id) {
+            Some(room) => room,
+            None => return vec![],
+        };
+        prune(room, now);
+
+        let mut members: Vec<MemberState> = room
+            .iter()
+            .map(|(user_id, member)| MemberState {
+                user_id: user_id.clone(),
+                name: member.name.clone(),
+                typing: member.typing_until.is_some_and(|until| until > now),
+            })
+            .collect();
+        if room.is_empty() {
+            state.rooms.remove(room_id);
+        }
+
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+        members
+    }
+}
+
+fn prune(room: &mut HashMap<String, Member>, now: Instant) {
+    room.retain(|_, member| now.duration_since(member.seen) < HEARTBEAT_TTL);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Who is online in a room, and whether they're typing.
+    fn online(presence: &PresenceTracker, room_id: &str, now: Instant) -> Vec<(String, bool)> {
+        presence
+            .members_at(room_id, now)
+            .into_iter()
+            .map(|member| (member.name, member.typing))
+            .collect()
+    }
+
+    fn rooms(presence: &PresenceTracker) -> usize {
+        presence.state.lock().unwrap().rooms.len()
+    }
+
+    #[test]
+    fn heartbeats_expire() {
+        let presence = PresenceTracker::default();
+        let start = Instant::now();
+        presence.heartbeat_at("1", "10", "Ryan", false, start);
+        presence.heartbeat_at("1", "11", "Peter", false, start + HEARTBEAT_TTL / 2);
+
+        assert_eq!(
+            online(&presence, "1", start + HEARTBEAT_TTL / 2),
+            [("Peter".into(), false), ("Ryan".into(), false)]
+        );
+        assert_eq!(
+            online(&presence, "1", start + HEARTBEAT_TTL),
+            [("Peter".into(), false)]
+        );
+        assert!(online(&presence, "1", start + HEARTBEAT_TTL * 2).is_empty());
+        assert!(online(&presence, "2", start).is_empty());
+        assert_eq!(rooms(&presence), 0);
+    }
+
+    #[test]
+    fn typing_stops_on_its_own_or_when_the_message_is_sent() {
+        let presence = PresenceTracker::default();
+        let start = Instant::now();
+        presence.heartbeat_at("1", "10", "Ryan", true, start);
+        presence.heartbeat_at("1", "11", "Peter", true, start);
+        // A heartbeat that isn't typing doesn't stop the typing.
+        presence.heartbeat_at("1", "10", "Ryan", false, start + TYPING_TTL / 2);
+
+        assert_eq!(
+            online(&presence, "1", start + TYPING_TTL / 2),
+            [("Peter".into(), true), ("Ryan".into(), true)]
+        );
+        presence.message_sent_at("1", "11", "Peter", start + TYPING_TTL / 2);
+        assert_eq!(
+            online(&presence, "1", start + TYPING_TTL / 2),
+            [("Peter".into(), false), ("Ryan".into(), true)]
+        );
+        assert_eq!(
+            online(&presence, "1", start + TYPING_TTL),
+            [("Peter".into(), false), ("Ryan".into(), false)]
+        );
+    }
+
+    #[test]
+    fn rooms_nobody_reads_are_swept() {
+        let presence = PresenceTracker::default();
+        let start = presence.state.lock().unwrap().swept;
+        for room_id in ["1", "2", "3"] {
+            presence.heartbeat_at(room_id, "10", "Ryan", false, start);
+        }
+        assert_eq!(rooms(&presence), 3);
+
+        // Not swept again until SWEEP_INTERVAL has passed.
+        presence.heartbeat_at("4", "10", "Ryan", false, start + SWEEP_INTERVAL / 2);
+        assert_eq!(rooms(&presence), 4);
+
+        // By then, only the room that was just written has anybody in it.
+        let later = start + SWEEP_INTERVAL + HEARTBEAT_TTL / 4;
+        presence.heartbeat_at("5", "10", "Ryan", false, later);
+        assert_eq!(rooms(&presence), 2);
+        assert_eq!(online(&presence, "4", later), [("Ryan".into(), false)]);
+        assert_eq!(online(&presence, "5", later), [("Ryan".into(), false)]);
+    }
+}
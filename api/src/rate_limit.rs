@@ -0,0 +1,451 @@
+use crate::{
+    base_url,
+    db::{self, Db},
+    errors::ChatError,
+    metrics, versioning,
+};
+use aws_sdk_dynamodb::model::{AttributeValue, ReturnValue};
+use axum::{
+    body::Body,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{Method, Request};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Once the in-memory backend is tracking this many buckets, buckets that
+/// have refilled all the way are thrown away. A full bucket is the same thing
+/// as no bucket, so this doesn't change any outcomes.
+const MAX_BUCKETS: usize = 10_000;
+
+/// How often, at most, buckets are swept. Sweeping goes through every
+/// bucket, so it can't happen on every request once there are
+/// `MAX_BUCKETS` of them that are all in use.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Routes are rate limited in groups, each group having its own budget. Auth
+/// routes are the tightest because they are what you'd brute-force.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RouteGroup {
+    Auth,
+    Writes,
+    Reads,
+}
+
+impl RouteGroup {
+    fn of<B>(request: &Request<B>) -> Self {
+        match (
+            request.method(),
+            versioning::unversioned_path(request.uri().path()),
+        ) {
+            (_, "/sign-up") | (_, "/sign-in") => RouteGroup::Auth,
+            (&Method::GET, _) | (&Method::HEAD, _) | (&Method::OPTIONS, _) => RouteGroup::Reads,
+            _ => RouteGroup::Writes,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            RouteGroup::Auth => "auth",
+            RouteGroup::Writes => "writes",
+            RouteGroup::Reads => "reads",
+        }
+    }
+}
+
+/// A budget of `requests` per `per`. Written (and configured) as
+/// `requests/seconds`, so `10/60` is ten requests a minute.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Limit {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+impl TryFrom<String> for Limit {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("{:?} is not a rate limit like \"10/60\"", value);
+        let (requests, seconds) = value.split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let seconds: u64 = seconds.trim().parse().map_err(|_| invalid())?;
+        if requests == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            requests,
+            per: Duration::from_secs(seconds),
+        })
+    }
+}
+
+impl From<Limit> for String {
+    fn from(limit: Limit) -> Self {
+        format!("{}/{}", limit.requests, limit.per.as_secs())
+    }
+}
+
+impl Limit {
+    fn per_minute(requests: u32) -> Self {
+        Self {
+            requests,
+            per: Duration::from_secs(60),
+        }
+    }
+
+    fn refill_rate(&self) -> f64 {
+        self.requests as f64 / self.per.as_secs_f64()
+    }
+}
+
+/// Where the rate limiter keeps its counters.
+///
+/// The in-memory backend is a proper token bucket, but every replica has its
+/// own buckets, so with 3 replicas a client effectively gets 3x the budget.
+/// The DynamoDB backend shares counters between replicas. It uses a fixed
+/// window counter instead of a token bucket, because that's something that can
+/// be done in a single atomic `UpdateItem`.
+#[derive(Clone)]
+enum Backend {
+    Memory(Arc<Mutex<Buckets>>),
+    DynamoDb(Db),
+}
+
+struct Buckets {
+    buckets: HashMap<(RouteGroup, String), Bucket>,
+    swept: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill_rate()).min(limit.requests as f64);
+        self.updated = now;
+    }
+
+    fn take(&mut self, limit: &Limit) -> Result<(), Duration> {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.refill_rate(),
+            ))
+        }
+    }
+}
+
+/// Rate limiting settings.
+///
+/// - `backend`: `memory` (default) or `dynamodb` to share limits between
+///   replicas via the rate limits table.
+/// - `auth`, `writes`, `reads`: budgets for each route group, as
+///   `requests/seconds`. Defaults to `10/60`, `30/60` and `300/60`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub backend: BackendKind,
+    pub auth: Limit,
+    pub writes: Limit,
+    pub reads: Limit,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            backend: BackendKind::Memory,
+            auth: Limit::per_minute(10),
+            writes: Limit::per_minute(30),
+            reads: Limit::per_minute(300),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    Memory,
+    DynamoDb,
+}
+
+/// Token bucket rate limiting per route group, keyed by client.
+///
+/// There's no authentication in this app (users are whoever they claim to be
+/// in the request body), so the only key we can trust is the client IP.
+#[derive(Clone)]
+pub struct RateLimiter {
+    auth: Limit,
+    writes: Limit,
+    reads: Limit,
+    backend: Backend,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, dynamodb: Db) -> Self {
+        let backend = match config.backend {
+            BackendKind::Memory => Backend::Memory(Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            }))),
+            BackendKind::DynamoDb => Backend::DynamoDb(dynamodb),
+        };
+
+        Self {
+            auth: config.auth,
+            writes: config.writes,
+            reads: config.reads,
+            backend,
+        }
+    }
+
+    fn limit(&self, group: RouteGroup) -> &Limit {
+        match group {
+            RouteGroup::Auth => &self.auth,
+            RouteGroup::Writes => &self.writes,
+            RouteGroup::Reads => &self.reads,
+        }
+    }
+
+    /// Spends one request from `key`'s budget for `group`. Returns how long
+    /// the client has to wait if the budget is spent.
+    pub async fn check(&self, group: RouteGroup, key: &str) -> Result<(), Duration> {
+        let limit = self.limit(group);
+        match &self.backend {
+            Backend::Memory(buckets) => {
+                self.check_memory(&mut buckets.lock().unwrap(), group, key, Instant::now())
+            }
+            // If the shared backend is down, let the request through. Rate
+            // limiting is there to protect DynamoDB, not to take the whole
+            // API down with it. It's logged and counted, so that rate limiting
+            // being off doesn't go unnoticed.
+            Backend::DynamoDb(dynamodb) => match check_shared(dynamodb, group, key, limit).await {
+                Ok(result) => result,
+                Err(error) => {
+                    tracing::warn!(
+                        group = group.name(),
+                        error = %error.display,
+                        debug = ?error.debug,
+                        "Could not check the rate limit, letting the request through"
+                    );
+                    metrics::rate_limit_error(group.name());
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    fn check_memory(
+        &self,
+        buckets: &mut Buckets,
+        group: RouteGroup,
+        key: &str,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let limit = self.limit(group);
+        if buckets.buckets.len() >= MAX_BUCKETS
+            && now.duration_since(buckets.swept) >= SWEEP_INTERVAL
+        {
+            buckets.buckets.retain(|(group, _), bucket| {
+                let limit = self.limit(*group);
+                bucket.refill(limit, now);
+                bucket.tokens < limit.requests as f64
+            });
+            buckets.swept = now;
+        }
+        let bucket = buckets
+            .buckets
+            .entry((group, key.to_owned()))
+            .or_insert_with(|| Bucket {
+                tokens: limit.requests as f64,
+                updated: now,
+            });
+        bucket.refill(limit, now);
+        bucket.take(limit)
+    }
+}
+
+async fn check_shared(
+    dynamodb: &Db,
+    group: RouteGroup,
+    key: &str,
+    limit: &Limit,
+) -> Result<Result<(), Duration>, ChatError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let window = limit.per.as_secs();
+    let window_start = now.as_secs() / window * window;
+    let window_end = Duration::from_secs(window_start + window);
+
+    let output = db::call(
+        "UpdateItem",
+        &dynamodb.tables.rate_limits,
+        dynamodb
+            .client
+            .update_item()
+            .table_name(&dynamodb.tables.rate_limits)
+            .key(
+                "key",
+                AttributeValue::S(format!("{}#{}#{}", group.name(), key, window_start)),
+            )
+            .update_expression("ADD hits :one SET expires_at = :e")
+            .expression_attribute_values(":one", AttributeValue::N("1".into()))
+            .expression_attribute_values(":e", AttributeValue::N(window_end.as_secs().to_string()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send(),
+    )
+    .await?;
+
+    let hits: u32 = output
+        .attributes
+        .as_ref()
+        .and_then(|attributes| attributes.get("hits"))
+        .ok_or_else(|| ChatError::new(None, "Query error".into()))?
+        .as_n()?
+        .parse()?;
+
+    if hits > limit.requests {
+        Ok(Err(window_end.saturating_sub(now)))
+    } else {
+        Ok(Ok(()))
+    }
+}
+
+/// Middleware that applies the `RateLimiter` found in the request extensions,
+/// so the `RateLimiter` extension has to be layered outside of this one, as
+/// does the config, for telling clients behind trusted proxies apart.
+pub async fn limit(request: Request<Body>, next: Next<Body>) -> Response {
+    let limiter = request.extensions().get::<RateLimiter>().cloned();
+    let client = base_url::client_ip(&request).map(|ip| ip.to_string());
+
+    if let (Some(limiter), Some(client)) = (limiter, client) {
+        let group = RouteGroup::of(&request);
+        if let Err(retry_after) = limiter.check(group, &client).await {
+            return ChatError::too_many_requests(
+                "Too many requests, slow down".into(),
+                retry_after,
+            )
+            .into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(limit: &str) -> Result<Limit, String> {
+        Limit::try_from(limit.to_owned())
+    }
+
+    /// A limiter with 2 writes every 10 seconds, and its buckets.
+    fn limiter() -> (RateLimiter, Buckets) {
+        let limiter = RateLimiter {
+            auth: limit("1/60").unwrap(),
+            writes: limit("2/10").unwrap(),
+            reads: limit("300/60").unwrap(),
+            backend: Backend::Memory(Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            }))),
+        };
+        let buckets = Buckets {
+            buckets: HashMap::new(),
+            swept: Instant::now(),
+        };
+        (limiter, buckets)
+    }
+
+    #[test]
+    fn limits_are_requests_per_seconds() {
+        let ten_a_minute = limit("10/60").unwrap();
+        assert_eq!(ten_a_minute.requests, 10);
+        assert_eq!(ten_a_minute.per, Duration::from_secs(60));
+        assert_eq!(String::from(ten_a_minute), "10/60");
+        assert_eq!(String::from(limit(" 5 / 1 ").unwrap()), "5/1");
+
+        for invalid in [
+            "0/60", "10/0", "", "10", "ten/60", "-1/60", "10/60/1", "1.5/60",
+        ] {
+            assert!(limit(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn buckets_refill_and_say_when_to_retry() {
+        let (limiter, mut buckets) = limiter();
+        let start = Instant::now();
+        let check = |buckets: &mut Buckets, key, seconds: f64| {
+            let now = start + Duration::from_secs_f64(seconds);
+            limiter.check_memory(buckets, RouteGroup::Writes, key, now)
+        };
+
+        assert_eq!(check(&mut buckets, "a", 0.0), Ok(()));
+        assert_eq!(check(&mut buckets, "a", 0.0), Ok(()));
+        // A token comes back every 5 seconds.
+        assert_eq!(check(&mut buckets, "a", 0.0), Err(Duration::from_secs(5)));
+        assert_eq!(
+            check(&mut buckets, "a", 2.5),
+            Err(Duration::from_secs_f64(2.5))
+        );
+        assert_eq!(check(&mut buckets, "a", 5.0), Ok(()));
+        assert!(check(&mut buckets, "a", 5.0).is_err());
+
+        // Other clients and groups have budgets of their own.
+        assert_eq!(check(&mut buckets, "b", 5.0), Ok(()));
+        let now = start + Duration::from_secs(5);
+        assert_eq!(
+            limiter.check_memory(&mut buckets, RouteGroup::Reads, "a", now),
+            Ok(())
+        );
+
+        // Buckets don't fill up past the limit, however long it's been.
+        assert_eq!(check(&mut buckets, "a", 1000.0), Ok(()));
+        assert_eq!(check(&mut buckets, "a", 1000.0), Ok(()));
+        assert!(check(&mut buckets, "a", 1000.0).is_err());
+    }
+
+    #[test]
+    fn full_buckets_are_swept_once_there_are_too_many() {
+        let (limiter, mut buckets) = limiter();
+        let start = buckets.swept;
+        for client in 0..MAX_BUCKETS {
+            let key = client.to_string();
+            assert_eq!(
+                limiter.check_memory(&mut buckets, RouteGroup::Writes, &key, start),
+                Ok(())
+            );
+        }
+        assert_eq!(buckets.buckets.len(), MAX_BUCKETS);
+
+        // Not swept again until SWEEP_INTERVAL has passed.
+        let soon = start + SWEEP_INTERVAL / 2;
+        limiter
+            .check_memory(&mut buckets, RouteGroup::Writes, "new", soon)
+            .unwrap();
+        assert_eq!(buckets.buckets.len(), MAX_BUCKETS + 1);
+
+        // By then every bucket but the one in use has refilled.
+        let later = start + SWEEP_INTERVAL;
+        limiter
+            .check_memory(&mut buckets, RouteGroup::Writes, "new", later)
+            .unwrap();
+        assert_eq!(buckets.buckets.len(), 1);
+        assert!(buckets
+            .buckets
+            .contains_key(&(RouteGroup::Writes, "new".to_owned())));
+    }
+}
@@ -0,0 +1,80 @@
+use axum::{routing::MethodRouter, Router};
+use http::Method;
+use std::{collections::BTreeMap, sync::Arc};
+
+/// A route, with the name it's advertised under in the root document.
+///
+/// `methods` are the methods `method_router` handles. The router can't be
+/// asked, so they're listed for the OpenAPI drift check.
+pub struct Route {
+    name: &'static str,
+    path: &'static str,
+    methods: &'static [Method],
+    method_router: MethodRouter,
+}
+
+impl Route {
+    pub fn new(
+        name: &'static str,
+        path: &'static str,
+        methods: &'static [Method],
+        method_router: MethodRouter,
+    ) -> Self {
+        Self {
+            name,
+            path,
+            methods,
+            method_router,
+        }
+    }
+}
+
+/// The links in the root document: route names and RFC 6570 URI templates,
+/// relative to the base URL, along with the methods each route handles.
+#[derive(Clone)]
+pub struct Links(Arc<BTreeMap<&'static str, (String, &'static [Method])>>);
+
+impl Links {
+    /// Expands the templates against a base URL. Only the base URL is filled
+    /// in; route parameters are left for the client.
+    pub fn resolve(&self, base_url: &str) -> BTreeMap<String, String> {
+        self.0
+            .iter()
+            .filter(|(_, (template, _))| template.as_str() != "/")
+            .map(|(name, (template, _))| (name.to_string(), format!("{}{}", base_url, template)))
+            .collect()
+    }
+
+    /// Every route's template and methods, the root route (`/`) included.
+    pub fn templates(&self) -> impl Iterator<Item = (&str, &'static [Method])> {
+        self.0
+            .values()
+            .map(|(template, methods)| (template.as_str(), *methods))
+    }
+}
+
+/// Builds the router and the root document's links from the same table, so
+/// that the root document can't advertise a route that doesn't exist or miss
+/// one that does. The root route (`/`) is the document itself, so it isn't
+/// listed in it.
+pub fn build(routes: Vec<Route>) -> (Router, Links) {
+    let mut links = BTreeMap::new();
+    let mut router = Router::new();
+    for route in routes {
+        links.insert(route.name, (template(route.path), route.methods));
+        router = router.route(route.path, route.method_router);
+    }
+    (router, Links(Arc::new(links)))
+}
+
+/// Turns an axum path like `/rooms/:room_id/messages` into an RFC 6570 URI
+/// template like `/rooms/{room_id}/messages`.
+fn template(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(parameter) => format!("{{{}}}", parameter),
+            None => segment.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,1027 @@
+use crate::{
+    config::Config,
+    db::{self, Db},
+    rate_limit::BackendKind,
+};
+use aws_sdk_dynamodb::{
+    error::DescribeTableError,
+    model::{
+        AttributeDefinition, BillingMode as SdkBillingMode, CreateGlobalSecondaryIndexAction,
+        GlobalSecondaryIndex, GlobalSecondaryIndexDescription, GlobalSecondaryIndexUpdate,
+        IndexStatus, KeySchemaElement, KeyType, PointInTimeRecoverySpecification,
+        PointInTimeRecoveryStatus, ProjectionType, ProvisionedThroughput,
+        ProvisionedThroughputDescription, ScalarAttributeType, TableDescription, TableStatus,
+        TimeToLiveSpecification, TimeToLiveStatus, UpdateGlobalSecondaryIndexAction,
+    },
+    types::SdkError,
+};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// How long to wait for a table (and its indexes) to become active after
+/// creating it. DynamoDB Local is instant, the real thing takes seconds to
+/// minutes depending on the indexes.
+pub const WAIT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often to check on a table that isn't active yet.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How the tables are set up in DynamoDB, beyond their keys and indexes. It's
+/// applied on startup, to tables that already exist as well as new ones.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchemaConfig {
+    /// `provisioned`, or `pay_per_request` for on-demand. Env: `BILLING_MODE`.
+    pub billing_mode: BillingMode,
+    /// Capacity for every table and index, with provisioned billing. Env:
+    /// `READ_CAPACITY_UNITS`.
+    pub read_capacity_units: i64,
+    /// Env: `WRITE_CAPACITY_UNITS`.
+    pub write_capacity_units: i64,
+    /// Turns point-in-time recovery on or off. Left unset, it's left as it
+    /// is, which DynamoDB Local needs since it doesn't have it. Env:
+    /// `POINT_IN_TIME_RECOVERY`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub point_in_time_recovery: Option<bool>,
+    /// Turns on DynamoDB TTL for the tables whose items expire. Without it,
+    /// expired items are still ignored, but pile up until something else
+    /// deletes them. Env: `TABLE_TTL`.
+    pub ttl: bool,
+}
+
+impl Default for SchemaConfig {
+    fn default() -> Self {
+        Self {
+            billing_mode: BillingMode::Provisioned,
+            read_capacity_units: 5,
+            write_capacity_units: 5,
+            point_in_time_recovery: None,
+            ttl: true,
+        }
+    }
+}
+
+impl SchemaConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.billing_mode == BillingMode::Provisioned {
+            for (setting, value) in [
+                ("schema.read_capacity_units", self.read_capacity_units),
+                ("schema.write_capacity_units", self.write_capacity_units),
+            ] {
+                if value < 1 {
+                    errors.push(format!(
+                        "{}: has to be at least 1 with provisioned billing",
+                        setting
+                    ));
+                }
+            }
+        }
+        errors
+    }
+
+    fn throughput(&self) -> ProvisionedThroughput {
+        ProvisionedThroughput::builder()
+            .read_capacity_units(self.read_capacity_units)
+            .write_capacity_units(self.write_capacity_units)
+            .build()
+    }
+
+    /// Whether a table or index has the capacity it should.
+    fn has_throughput(&self, actual: Option<&ProvisionedThroughputDescription>) -> bool {
+        actual.is_some_and(|actual| {
+            actual.read_capacity_units() == Some(self.read_capacity_units)
+                && actual.write_capacity_units() == Some(self.write_capacity_units)
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BillingMode {
+    Provisioned,
+    PayPerRequest,
+}
+
+/// A table the API uses, as it should be in DynamoDB.
+///
+/// On startup, `apply` creates the tables that don't exist yet, waits for
+/// them to be active, and checks that the ones that do exist match. Indexes
+/// that are missing are added, which DynamoDB backfills on its own. Other
+/// differences aren't changed, since changing a key means moving every item,
+/// which is a migration for a person to plan; the API refuses to start
+/// instead, and says what's different.
+pub struct Table {
+    /// The setting the table's name comes from, like `tables.messages`.
+    pub setting: &'static str,
+    pub name: String,
+    pub key: Key,
+    /// Global secondary indexes. Indexes in DynamoDB that aren't listed here
+    /// are left alone.
+    pub indexes: Vec<Index>,
+    /// The attribute DynamoDB TTL deletes items by, if it's on.
+    pub ttl: Option<&'static str>,
+}
+
+pub struct Key {
+    pub hash: Attribute,
+    pub range: Option<Attribute>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Attribute {
+    pub name: &'static str,
+    pub kind: Kind,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    S,
+    N,
+}
+
+pub struct Index {
+    pub name: &'static str,
+    pub key: Key,
+    pub projection: Projection,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Projection {
+    KeysOnly,
+    All,
+}
+
+const fn attribute(name: &'static str, kind: Kind) -> Attribute {
+    Attribute { name, kind }
+}
+
+/// Every table the API uses with this config. The rate limits table is only
+/// used by the DynamoDB rate limit backend.
+pub fn tables(config: &Config) -> Vec<Table> {
+    let names = &config.tables.prefixed();
+    let mut tables = vec![
+        // Rooms, their messages, and the list of active rooms, told apart by
+        // `sort`.
+        Table {
+            setting: "tables.messages",
+            name: names.messages.clone(),
+            key: Key {
+                hash: attribute("room_id", Kind::N),
+                range: Some(attribute("sort", Kind::S)),
+            },
+            indexes: vec![Index {
+                name: "name-index",
+                key: Key {
+                    hash: attribute("name", Kind::S),
+                    range: None,
+                },
+                projection: Projection::KeysOnly,
+            }],
+            ttl: None,
+        },
+        Table {
+            setting: "tables.users",
+            name: names.users.clone(),
+            key: Key {
+                hash: attribute("user_id", Kind::N),
+                range: None,
+            },
+            indexes: vec![
+                Index {
+                    name: "name-index",
+                    key: Key {
+                        hash: attribute("name", Kind::S),
+                        range: None,
+                    },
+                    projection: Projection::KeysOnly,
+                },
+                Index {
+                    name: "name-key-index",
+                    key: Key {
+                        hash: attribute("name_key", Kind::S),
+                        range: None,
+                    },
+                    projection: Projection::All,
+                },
+            ],
+            ttl: None,
+        },
+        // One item per `name_key`, so that a name can only be taken once,
+        // see `db::create_user`.
+        Table {
+            setting: "tables.user_names",
+            name: names.user_names.clone(),
+            key: Key {
+                hash: attribute("name_key", Kind::S),
+                range: None,
+            },
+            indexes: Vec::new(),
+            ttl: None,
+        },
+        // Remembers the responses to requests with an `Idempotency-Key`, see
+        // `idempotency`. Keys are only remembered for a while.
+        Table {
+            setting: "tables.idempotency_keys",
+            name: names.idempotency_keys.clone(),
+            key: Key {
+                hash: attribute("key", Kind::S),
+                range: None,
+            },
+            indexes: Vec::new(),
+            ttl: Some("expires_at"),
+        },
+        // Which migrations have run, see `migrations`.
+        Table {
+            setting: "tables.migrations",
+            name: names.migrations.clone(),
+            key: Key {
+                hash: attribute("version", Kind::N),
+                range: None,
+            },
+            indexes: Vec::new(),
+            ttl: None,
+        },
+    ];
+    // Backs the shared rate limiter. Counters are only useful for one rate
+    // limit window.
+    if config.rate_limits.backend == BackendKind::DynamoDb {
+        tables.push(Table {
+            setting: "tables.rate_limits",
+            name: names.rate_limits.clone(),
+            key: Key {
+                hash: attribute("key", Kind::S),
+                range: None,
+            },
+            indexes: Vec::new(),
+            ttl: Some("expires_at"),
+        });
+    }
+    tables
+}
+
+/// Makes DynamoDB match `tables` and `config`, as far as it safely can:
+/// creates missing tables and indexes, waits for them to be active, and sets
+/// their billing, TTL and point-in-time recovery. Returns everything that's wrong
+/// with the tables that already exist, or couldn't be set up.
+///
+/// *Usually* this would be codified in terraform, but for the purposes of this
+/// exercise, the API stands up its own tables on start. Tables made some other
+/// way are checked all the same.
+pub async fn apply(
+    dynamodb: &Db,
+    tables: &[Table],
+    config: &SchemaConfig,
+) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+    for table in tables {
+        problems.extend(
+            apply_table(dynamodb, table, config)
+                .await
+                .into_iter()
+                .map(|problem| format!("{} ({}): {}", table.setting, table.name, problem)),
+        );
+    }
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems),
+    }
+}
+
+async fn apply_table(dynamodb: &Db, table: &Table, config: &SchemaConfig) -> Vec<String> {
+    let mut description = match describe(dynamodb, &table.name).await {
+        Ok(Some(description)) => description,
+        Ok(None) => {
+            if let Err(error) = create(dynamodb, table, config).await {
+                return vec![format!("could not create it: {}", error)];
+            }
+            tracing::info!(table = %table.name, "Created table");
+            match wait_until_active(dynamodb, table).await {
+                Ok(description) => description,
+                Err(problem) => return vec![problem],
+            }
+        }
+        Err(error) => return vec![format!("could not describe it: {}", error)],
+    };
+
+    // A table that's different is left for a person to sort out, along with
+    // its settings.
+    let problems = drift(table, &description);
+    if !problems.is_empty() {
+        return problems;
+    }
+    if !is_active(table, &description) {
+        description = match wait_until_active(dynamodb, table).await {
+            Ok(description) => description,
+            Err(problem) => return vec![problem],
+        };
+    }
+    for index in &table.indexes {
+        let indexes = description.global_secondary_indexes().unwrap_or_default();
+        if find_index(indexes, index.name).is_some() {
+            continue;
+        }
+        description = match add_index(dynamodb, table, index, &description).await {
+            Ok(description) => description,
+            Err(problem) => return vec![problem],
+        };
+    }
+
+    let mut problems = Vec::new();
+    if let Err(problem) = set_billing(dynamodb, table, config, &description).await {
+        problems.push(problem);
+    }
+    if let (Some(attribute), true) = (table.ttl, config.ttl) {
+        if let Err(problem) = turn_on_ttl(dynamodb, &table.name, attribute).await {
+            problems.push(problem);
+        }
+    }
+    if let Some(enabled) = config.point_in_time_recovery {
+        if let Err(problem) = set_point_in_time_recovery(dynamodb, &table.name, enabled).await {
+            problems.push(problem);
+        }
+    }
+    problems
+}
+
+/// The table as DynamoDB has it, or `None` if it doesn't exist.
+async fn describe(
+    dynamodb: &Db,
+    name: &str,
+) -> Result<Option<TableDescription>, SdkError<DescribeTableError>> {
+    match db::call(
+        "DescribeTable",
+        name,
+        dynamodb.client.describe_table().table_name(name).send(),
+    )
+    .await
+    {
+        Ok(output) => Ok(output.table),
+        Err(SdkError::ServiceError { err, .. }) if err.is_resource_not_found_exception() => {
+            Ok(None)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+async fn create(dynamodb: &Db, table: &Table, config: &SchemaConfig) -> Result<(), String> {
+    let provisioned = config.billing_mode == BillingMode::Provisioned;
+    let mut request = dynamodb
+        .client
+        .create_table()
+        .table_name(&table.name)
+        .set_key_schema(Some(key_schema(&table.key)))
+        .billing_mode(config.billing_mode.to_sdk())
+        .set_provisioned_throughput(provisioned.then(|| config.throughput()));
+
+    // Every attribute that's part of a key, the table's or an index's, has to
+    // be defined, once.
+    let mut attributes = key_attributes(&table.key);
+    for index in &table.indexes {
+        for attribute in key_attributes(&index.key) {
+            if !attributes.iter().any(|known| known.name == attribute.name) {
+                attributes.push(attribute);
+            }
+        }
+        request = request.global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name(index.name)
+                .set_key_schema(Some(key_schema(&index.key)))
+                .projection(
+                    aws_sdk_dynamodb::model::Projection::builder()
+                        .projection_type(index.projection.to_sdk())
+                        .build(),
+                )
+                .set_provisioned_throughput(provisioned.then(|| config.throughput()))
+                .build(),
+        );
+    }
+    for attribute in attributes {
+        request = request.attribute_definitions(
+            AttributeDefinition::builder()
+                .attribute_name(attribute.name)
+                .attribute_type(attribute.kind.to_sdk())
+                .build(),
+        );
+    }
+
+    db::call("CreateTable", &table.name, request.send())
+        .await
+        .map(|_| ())
+        .map_err(|error| error.to_string())
+}
+
+/// Adds an index that's missing from a table that already exists, and waits
+/// for DynamoDB to backfill it. Tables only take one new index at a time.
+async fn add_index(
+    dynamodb: &Db,
+    table: &Table,
+    index: &Index,
+    description: &TableDescription,
+) -> Result<TableDescription, String> {
+    let mut request = dynamodb.client.update_table().table_name(&table.name);
+    // Only new attributes need defining, but defining one again is fine.
+    for attribute in key_attributes(&index.key) {
+        request = request.attribute_definitions(
+            AttributeDefinition::builder()
+                .attribute_name(attribute.name)
+                .attribute_type(attribute.kind.to_sdk())
+                .build(),
+        );
+    }
+    // The index is billed like the table is now. `set_billing` changes both
+    // afterwards if that's not how they should be.
+    let throughput = description
+        .provisioned_throughput()
+        .filter(|_| !is_on_demand(description))
+        .map(|throughput| {
+            ProvisionedThroughput::builder()
+                .set_read_capacity_units(throughput.read_capacity_units())
+                .set_write_capacity_units(throughput.write_capacity_units())
+                .build()
+        });
+    request = request.global_secondary_index_updates(
+        GlobalSecondaryIndexUpdate::builder()
+            .create(
+                CreateGlobalSecondaryIndexAction::builder()
+                    .index_name(index.name)
+                    .set_key_schema(Some(key_schema(&index.key)))
+                    .projection(
+                        aws_sdk_dynamodb::model::Projection::builder()
+                            .projection_type(index.projection.to_sdk())
+                            .build(),
+                    )
+                    .set_provisioned_throughput(throughput)
+                    .build(),
+            )
+            .build(),
+    );
+
+    db::call("UpdateTable", &table.name, request.send())
+        .await
+        .map_err(|error| format!("could not add index {}: {}", index.name, error))?;
+    tracing::info!(table = %table.name, index = index.name, "Adding index");
+    wait_until_active(dynamodb, table).await
+}
+
+fn key_attributes(key: &Key) -> Vec<Attribute> {
+    std::iter::once(key.hash).chain(key.range).collect()
+}
+
+fn key_schema(key: &Key) -> Vec<KeySchemaElement> {
+    let element = |attribute: &Attribute, key_type| {
+        KeySchemaElement::builder()
+            .attribute_name(attribute.name)
+            .key_type(key_type)
+            .build()
+    };
+    std::iter::once(element(&key.hash, KeyType::Hash))
+        .chain(
+            key.range
+                .as_ref()
+                .map(|range| element(range, KeyType::Range)),
+        )
+        .collect()
+}
+
+/// Whether the table, and the indexes it should have that it does have, can
+/// be used. Missing indexes are left to `add_index`.
+fn is_active(table: &Table, description: &TableDescription) -> bool {
+    let indexes = description.global_secondary_indexes().unwrap_or_default();
+    description.table_status() == Some(&TableStatus::Active)
+        && table.indexes.iter().all(|index| {
+            find_index(indexes, index.name)
+                .is_none_or(|found| found.index_status() == Some(&IndexStatus::Active))
+        })
+}
+
+/// Whether the table is billed on demand. Tables that have always been
+/// provisioned don't say so.
+fn is_on_demand(description: &TableDescription) -> bool {
+    description
+        .billing_mode_summary()
+        .and_then(|summary| summary.billing_mode())
+        == Some(&SdkBillingMode::PayPerRequest)
+}
+
+async fn wait_until_active(dynamodb: &Db, table: &Table) -> Result<TableDescription, String> {
+    let start = Instant::now();
+    loop {
+        match describe(dynamodb, &table.name).await {
+            Ok(Some(description)) if is_active(table, &description) => return Ok(description),
+            Ok(_) => {}
+            Err(error) => return Err(format!("could not describe it: {}", error)),
+        }
+        if start.elapsed() > WAIT_TIMEOUT {
+            return Err(format!(
+                "still not active after {} seconds",
+                WAIT_TIMEOUT.as_secs()
+            ));
+        }
+        tracing::info!(table = %table.name, "Waiting for table to be active");
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+fn find_index<'a>(
+    indexes: &'a [GlobalSecondaryIndexDescription],
+    name: &str,
+) -> Option<&'a GlobalSecondaryIndexDescription> {
+    indexes
+        .iter()
+        .find(|index| index.index_name() == Some(name))
+}
+
+/// How the table in DynamoDB differs from how it should be, in ways that
+/// can't be fixed without moving its items. A missing index can be added, so
+/// it's only a problem if its key attributes are already in use with other
+/// types.
+pub fn drift(table: &Table, description: &TableDescription) -> Vec<String> {
+    let defined = description.attribute_definitions().unwrap_or_default();
+    let mut problems = key_drift("key", &table.key, description.key_schema(), defined);
+    let indexes = description.global_secondary_indexes().unwrap_or_default();
+    for index in &table.indexes {
+        let found = match find_index(indexes, index.name) {
+            Some(found) => found,
+            None => {
+                problems.extend(attribute_drift(
+                    &format!("index {} key", index.name),
+                    &index.key,
+                    defined,
+                ));
+                continue;
+            }
+        };
+        problems.extend(key_drift(
+            &format!("index {} key", index.name),
+            &index.key,
+            found.key_schema(),
+            defined,
+        ));
+        let projection = found
+            .projection()
+            .and_then(|projection| projection.projection_type());
+        if projection != Some(&index.projection.to_sdk()) {
+            problems.push(format!(
+                "index {} projects {}, should project {}",
+                index.name,
+                projection.map_or("nothing", |projection| projection.as_str()),
+                index.projection.to_sdk().as_str()
+            ));
+        }
+    }
+    problems
+}
+
+/// How a key differs from how it should be, in its attributes or their
+/// types.
+fn key_drift(
+    what: &str,
+    key: &Key,
+    actual: Option<&[KeySchemaElement]>,
+    defined: &[AttributeDefinition],
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let actual = actual.unwrap_or_default();
+    let expected = key_schema(key);
+    if !same_key(&expected, actual) {
+        problems.push(format!(
+            "{} is {}, should be {}",
+            what,
+            describe_key(actual),
+            describe_key(&expected)
+        ));
+    }
+    problems.extend(attribute_drift(what, key, defined));
+    problems
+}
+
+/// How the types of a key's attributes differ from how they're defined.
+fn attribute_drift(what: &str, key: &Key, defined: &[AttributeDefinition]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for attribute in key_attributes(key) {
+        let kind = defined
+            .iter()
+            .find(|definition| definition.attribute_name() == Some(attribute.name))
+            .and_then(|definition| definition.attribute_type());
+        if let Some(kind) = kind.filter(|kind| **kind != attribute.kind.to_sdk()) {
+            problems.push(format!(
+                "{} attribute {} is {}, should be {}",
+                what,
+                attribute.name,
+                kind.as_str(),
+                attribute.kind.to_sdk().as_str()
+            ));
+        }
+    }
+    problems
+}
+
+fn same_key(expected: &[KeySchemaElement], actual: &[KeySchemaElement]) -> bool {
+    expected.len() == actual.len()
+        && expected.iter().all(|element| {
+            actual.iter().any(|other| {
+                other.attribute_name() == element.attribute_name()
+                    && other.key_type() == element.key_type()
+            })
+        })
+}
+
+fn describe_key(key: &[KeySchemaElement]) -> String {
+    if key.is_empty() {
+        return "missing".into();
+    }
+    key.iter()
+        .map(|element| {
+            format!(
+                "{} ({})",
+                element.attribute_name().unwrap_or_default(),
+                element.key_type().map_or("?", |key_type| key_type.as_str())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Switches the table between on-demand and provisioned billing, or changes
+/// its capacity, if it isn't as configured. DynamoDB only allows switching
+/// billing modes once a day, so a table that was switched recently is
+/// reported rather than fixed.
+async fn set_billing(
+    dynamodb: &Db,
+    table: &Table,
+    config: &SchemaConfig,
+    description: &TableDescription,
+) -> Result<(), String> {
+    let change = match billing_change(config, description) {
+        Some(change) => change,
+        None => return Ok(()),
+    };
+    let mut request = dynamodb
+        .client
+        .update_table()
+        .table_name(&table.name)
+        .billing_mode(config.billing_mode.to_sdk());
+    if change.table {
+        request = request.provisioned_throughput(config.throughput());
+    }
+    for index in change.indexes {
+        request = request.global_secondary_index_updates(
+            GlobalSecondaryIndexUpdate::builder()
+                .update(
+                    UpdateGlobalSecondaryIndexAction::builder()
+                        .index_name(index)
+                        .provisioned_throughput(config.throughput())
+                        .build(),
+                )
+                .build(),
+        );
+    }
+
+    db::call("UpdateTable", &table.name, request.send())
+        .await
+        .map_err(|error| format!("could not change its billing: {}", error))?;
+    tracing::info!(
+        table = %table.name,
+        billing_mode = ?config.billing_mode,
+        "Changed table billing"
+    );
+    wait_until_active(dynamodb, table).await.map(|_| ())
+}
+
+/// What has to change for a table to be billed as configured, besides its
+/// billing mode: with provisioned billing, whether the table needs capacity
+/// and which indexes do.
+#[derive(Debug, PartialEq, Eq)]
+struct BillingChange<'a> {
+    table: bool,
+    indexes: Vec<&'a str>,
+}
+
+fn billing_change<'a>(
+    config: &SchemaConfig,
+    description: &'a TableDescription,
+) -> Option<BillingChange<'a>> {
+    let on_demand = is_on_demand(description);
+    let indexes = description.global_secondary_indexes().unwrap_or_default();
+    match config.billing_mode {
+        BillingMode::PayPerRequest if on_demand => None,
+        BillingMode::PayPerRequest => Some(BillingChange {
+            table: false,
+            indexes: Vec::new(),
+        }),
+        BillingMode::Provisioned => {
+            // Capacity can only be given when it changes, but every index
+            // needs it when switching to provisioned.
+            let table = on_demand || !config.has_throughput(description.provisioned_throughput());
+            let indexes: Vec<_> = indexes
+                .iter()
+                .filter(|index| on_demand || !config.has_throughput(index.provisioned_throughput()))
+                .filter_map(|index| index.index_name())
+                .collect();
+            match table || !indexes.is_empty() {
+                true => Some(BillingChange { table, indexes }),
+                false => None,
+            }
+        }
+    }
+}
+
+/// Turns point-in-time recovery on or off, unless it already is.
+async fn set_point_in_time_recovery(
+    dynamodb: &Db,
+    table: &str,
+    enabled: bool,
+) -> Result<(), String> {
+    let output = db::call(
+        "DescribeContinuousBackups",
+        table,
+        dynamodb
+            .client
+            .describe_continuous_backups()
+            .table_name(table)
+            .send(),
+    )
+    .await
+    .map_err(|error| format!("could not check point-in-time recovery: {}", error))?;
+    let status = output
+        .continuous_backups_description()
+        .and_then(|description| description.point_in_time_recovery_description())
+        .and_then(|description| description.point_in_time_recovery_status());
+    if (status == Some(&PointInTimeRecoveryStatus::Enabled)) == enabled {
+        return Ok(());
+    }
+
+    db::call(
+        "UpdateContinuousBackups",
+        table,
+        dynamodb
+            .client
+            .update_continuous_backups()
+            .table_name(table)
+            .point_in_time_recovery_specification(
+                PointInTimeRecoverySpecification::builder()
+                    .point_in_time_recovery_enabled(enabled)
+                    .build(),
+            )
+            .send(),
+    )
+    .await
+    .map_err(|error| format!("could not change point-in-time recovery: {}", error))?;
+    tracing::info!(table, enabled, "Changed point-in-time recovery");
+    Ok(())
+}
+
+/// Turns TTL on, unless it already is. TTL can only be on for one attribute,
+/// so TTL on a different one is a problem.
+async fn turn_on_ttl(dynamodb: &Db, table: &str, attribute: &str) -> Result<(), String> {
+    let output = db::call(
+        "DescribeTimeToLive",
+        table,
+        dynamodb
+            .client
+            .describe_time_to_live()
+            .table_name(table)
+            .send(),
+    )
+    .await
+    .map_err(|error| format!("could not check TTL: {}", error))?;
+    let description = output.time_to_live_description();
+    let status = description.and_then(|description| description.time_to_live_status());
+    if matches!(
+        status,
+        Some(TimeToLiveStatus::Enabled) | Some(TimeToLiveStatus::Enabling)
+    ) {
+        let actual = description.and_then(|description| description.attribute_name());
+        return match actual {
+            Some(actual) if actual != attribute => Err(format!(
+                "TTL is on for {}, should be on for {}",
+                actual, attribute
+            )),
+            _ => Ok(()),
+        };
+    }
+
+    db::call(
+        "UpdateTimeToLive",
+        table,
+        dynamodb
+            .client
+            .update_time_to_live()
+            .table_name(table)
+            .time_to_live_specification(
+                TimeToLiveSpecification::builder()
+                    .attribute_name(attribute)
+                    .enabled(true)
+                    .build(),
+            )
+            .send(),
+    )
+    .await
+    .map_err(|error| format!("could not turn TTL on: {}", error))?;
+    tracing::info!(table, attribute, "Turned on TTL");
+    Ok(())
+}
+
+impl Kind {
+    fn to_sdk(self) -> ScalarAttributeType {
+        match self {
+            Kind::S => ScalarAttributeType::S,
+            Kind::N => ScalarAttributeType::N,
+        }
+    }
+}
+
+impl BillingMode {
+    fn to_sdk(self) -> SdkBillingMode {
+        match self {
+            BillingMode::Provisioned => SdkBillingMode::Provisioned,
+            BillingMode::PayPerRequest => SdkBillingMode::PayPerRequest,
+        }
+    }
+}
+
+impl Projection {
+    fn to_sdk(self) -> ProjectionType {
+        match self {
+            Projection::KeysOnly => ProjectionType::KeysOnly,
+            Projection::All => ProjectionType::All,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::model::{BillingModeSummary, Projection as SdkProjection};
+
+    /// Rooms and their messages, with an index by name.
+    fn table() -> Table {
+        Table {
+            setting: "tables.messages",
+            name: "messages".into(),
+            key: Key {
+                hash: attribute("room_id", Kind::N),
+                range: Some(attribute("sort", Kind::S)),
+            },
+            indexes: vec![Index {
+                name: "name-index",
+                key: Key {
+                    hash: attribute("name", Kind::S),
+                    range: None,
+                },
+                projection: Projection::KeysOnly,
+            }],
+            ttl: None,
+        }
+    }
+
+    fn definition(name: &str, kind: ScalarAttributeType) -> AttributeDefinition {
+        AttributeDefinition::builder()
+            .attribute_name(name)
+            .attribute_type(kind)
+            .build()
+    }
+
+    fn throughput(units: i64) -> ProvisionedThroughputDescription {
+        ProvisionedThroughputDescription::builder()
+            .read_capacity_units(units)
+            .write_capacity_units(units)
+            .build()
+    }
+
+    fn name_index(projection: ProjectionType) -> GlobalSecondaryIndexDescription {
+        GlobalSecondaryIndexDescription::builder()
+            .index_name("name-index")
+            .set_key_schema(Some(key_schema(&table().indexes[0].key)))
+            .projection(SdkProjection::builder().projection_type(projection).build())
+            .index_status(IndexStatus::Active)
+            .provisioned_throughput(throughput(5))
+            .build()
+    }
+
+    /// The table as `create` would make it with the default config, with
+    /// `room_id` defined as `room_id_kind`, and the given indexes.
+    fn description(
+        room_id_kind: ScalarAttributeType,
+        indexes: Vec<GlobalSecondaryIndexDescription>,
+    ) -> TableDescription {
+        TableDescription::builder()
+            .table_name("messages")
+            .table_status(TableStatus::Active)
+            .set_key_schema(Some(key_schema(&table().key)))
+            .attribute_definitions(definition("room_id", room_id_kind))
+            .attribute_definitions(definition("sort", ScalarAttributeType::S))
+            .attribute_definitions(definition("name", ScalarAttributeType::S))
+            .set_global_secondary_indexes(Some(indexes))
+            .provisioned_throughput(throughput(5))
+            .build()
+    }
+
+    #[test]
+    fn matching_tables_have_no_drift() {
+        let description = description(
+            ScalarAttributeType::N,
+            vec![name_index(ProjectionType::KeysOnly)],
+        );
+        assert!(drift(&table(), &description).is_empty());
+        assert!(is_active(&table(), &description));
+        assert_eq!(billing_change(&SchemaConfig::default(), &description), None);
+    }
+
+    #[test]
+    fn keys_and_projections_that_differ_are_drift() {
+        let description = description(
+            ScalarAttributeType::S,
+            vec![name_index(ProjectionType::All)],
+        );
+        assert_eq!(
+            drift(&table(), &description),
+            [
+                "key attribute room_id is S, should be N",
+                "index name-index projects ALL, should project KEYS_ONLY",
+            ]
+        );
+
+        let mut wrong_key = table();
+        wrong_key.key.range = None;
+        assert_eq!(
+            drift(&wrong_key, &description),
+            [
+                "key is room_id (HASH), sort (RANGE), should be room_id (HASH)",
+                "key attribute room_id is S, should be N",
+                "index name-index projects ALL, should project KEYS_ONLY",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_indexes_are_added_rather_than_drift() {
+        let missing = description(ScalarAttributeType::N, Vec::new());
+        assert!(drift(&table(), &missing).is_empty());
+        assert!(is_active(&table(), &missing));
+
+        // Unless the index's key attribute is already in use with another
+        // type.
+        let mut other_type = table();
+        other_type.indexes[0].key.hash = attribute("name", Kind::N);
+        assert_eq!(
+            drift(&other_type, &missing),
+            ["index name-index key attribute name is S, should be N"]
+        );
+    }
+
+    #[test]
+    fn billing_modes_that_differ_are_changed_rather_than_drift() {
+        let provisioned = description(
+            ScalarAttributeType::N,
+            vec![name_index(ProjectionType::KeysOnly)],
+        );
+        let on_demand_config = SchemaConfig {
+            billing_mode: BillingMode::PayPerRequest,
+            ..SchemaConfig::default()
+        };
+        assert!(drift(&table(), &provisioned).is_empty());
+        assert_eq!(
+            billing_change(&on_demand_config, &provisioned),
+            Some(BillingChange {
+                table: false,
+                indexes: Vec::new(),
+            })
+        );
+
+        // Switching to provisioned gives the table and every index capacity.
+        let mut on_demand = provisioned.clone();
+        on_demand.billing_mode_summary = Some(
+            BillingModeSummary::builder()
+                .billing_mode(SdkBillingMode::PayPerRequest)
+                .build(),
+        );
+        assert!(drift(&table(), &on_demand).is_empty());
+        assert_eq!(billing_change(&on_demand_config, &on_demand), None);
+        assert_eq!(
+            billing_change(&SchemaConfig::default(), &on_demand),
+            Some(BillingChange {
+                table: true,
+                indexes: vec!["name-index"],
+            })
+        );
+
+        // Changing capacity only gives it to what needs it.
+        let more = SchemaConfig {
+            read_capacity_units: 10,
+            write_capacity_units: 10,
+            ..SchemaConfig::default()
+        };
+        let mut index = name_index(ProjectionType::KeysOnly);
+        index.provisioned_throughput = Some(throughput(10));
+        let index_only = description(ScalarAttributeType::N, vec![index]);
+        assert_eq!(
+            billing_change(&more, &index_only),
+            Some(BillingChange {
+                table: true,
+                indexes: Vec::new(),
+            })
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use std::{fmt::Display, future::Future, time::Duration};
+use tokio::sync::watch;
+
+/// Waits for SIGTERM (what orchestrators send to stop a container) or Ctrl-C,
+/// and then tells `drain` that shutting down has started. Meant for
+/// `with_graceful_shutdown`, which stops accepting connections when it
+/// returns.
+pub async fn signal(draining: watch::Sender<bool>) {
+    let ctrl_c = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            tracing::error!(%error, "Could not listen for Ctrl-C");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(error) => {
+                tracing::error!(%error, "Could not listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!(
+        in_flight = crate::metrics::requests_in_flight(),
+        "Shutting down, finishing requests in flight"
+    );
+    let _ = draining.send(true);
+}
+
+/// Runs the server until it has shut down. Once `signal` has gone off, the
+/// requests in flight get `timeout` to finish. Whatever is still going after
+/// that is dropped, so that a stuck request can't hold up a deploy.
+pub async fn drain<E: Display>(
+    server: impl Future<Output = Result<(), E>>,
+    mut draining: watch::Receiver<bool>,
+    timeout: Duration,
+) -> Result<(), E> {
+    tokio::pin!(server);
+    let result = tokio::select! {
+        result = &mut server => Some(result),
+        _ = draining.changed() => tokio::time::timeout(timeout, &mut server).await.ok(),
+    };
+    match result {
+        Some(Ok(())) => {
+            tracing::info!("Every request finished");
+            Ok(())
+        }
+        Some(Err(error)) => {
+            tracing::error!(%error, "Server failed");
+            Err(error)
+        }
+        None => {
+            tracing::warn!(
+                in_flight = crate::metrics::requests_in_flight(),
+                "Gave up on requests that didn't finish in time"
+            );
+            Ok(())
+        }
+    }
+}
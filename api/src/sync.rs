@@ -0,0 +1,121 @@
+use chrono::{DateTime, TimeZone, Utc};
+use std::time::Duration;
+
+/// The most messages `GET /sync` sends at once. Clients that are further
+/// behind get `has_more` and page through the rest.
+pub const PAGE_SIZE: usize = 200;
+
+/// How far back a sync token can be and still be caught up from. Clients that
+/// have been away longer are told to start over, since walking every room for
+/// a week of messages is slower than loading the rooms they're looking at.
+pub const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A point in time that a client has seen every change up to, handed out by
+/// `GET /sync` and sent back as `since`. Clients should treat it as opaque.
+/// It's the time in microseconds since the epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Token(u64);
+
+impl Token {
+    pub fn now() -> Self {
+        Self::from_date_time(&Utc::now())
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        value.parse().ok().map(Token)
+    }
+
+    /// Reads a token off a message's sort key, `message.` and an RFC 3339
+    /// date.
+    pub fn from_sort_key(sort: &str) -> Option<Self> {
+        sort.strip_prefix("message.")
+            .and_then(|date_time| DateTime::parse_from_rfc3339(date_time).ok())
+            .map(|date_time| Self::from_date_time(&date_time.with_timezone(&Utc)))
+    }
+
+    fn from_date_time(date_time: &DateTime<Utc>) -> Self {
+        Self(
+            date_time.timestamp().max(0) as u64 * 1_000_000
+                + date_time.timestamp_subsec_micros() as u64,
+        )
+    }
+
+    /// The sort key a message posted at this time would have, which messages
+    /// posted later sort after. Message dates are more precise than a token,
+    /// so the message a token was read off of sorts after it too, which means
+    /// clients may see it twice, but never miss one.
+    pub fn sort_key(&self) -> String {
+        let date_time = Utc
+            .timestamp_opt(
+                (self.0 / 1_000_000) as i64,
+                (self.0 % 1_000_000) as u32 * 1000,
+            )
+            .single()
+            .unwrap_or_else(Utc::now);
+        format!("message.{}", date_time.to_rfc3339())
+    }
+
+    pub fn saturating_sub(&self, duration: Duration) -> Self {
+        Self(self.0.saturating_sub(duration.as_micros() as u64))
+    }
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    /// The sort key of a message posted at `date_time`, the way messages are
+    /// written.
+    fn message_sort_key(date_time: DateTime<Utc>) -> String {
+        format!("message.{}", date_time.to_rfc3339())
+    }
+
+    #[test]
+    fn tokens_sort_between_messages() {
+        let second = Utc.ymd(2025, 10, 18).and_hms(12, 0, 0);
+        // Whole seconds, then milli, micro and nanosecond precision, which
+        // `to_rfc3339` writes with 0, 3, 6 and 9 digits.
+        let mut times: Vec<_> = [0, 1, 999, 1_000, 1_001, 100_000, 100_000_001, 999_999_999]
+            .into_iter()
+            .map(|nanoseconds| second + Duration::nanoseconds(nanoseconds))
+            .collect();
+        times.push(second + Duration::seconds(1));
+        times.sort();
+
+        for (i, before) in times.iter().enumerate() {
+            for after in &times[i + 1..] {
+                assert!(message_sort_key(*before) < message_sort_key(*after));
+
+                // A token sorts before every message posted from its
+                // microsecond on, and after every one before.
+                let token = Token::from_date_time(after);
+                assert!(token.sort_key() <= message_sort_key(*after));
+                if Token::from_date_time(before) < token {
+                    assert!(message_sort_key(*before) < token.sort_key());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tokens_read_off_messages_sort_before_them() {
+        let sent = Utc.ymd(2025, 10, 18).and_hms(12, 0, 0) + Duration::nanoseconds(123_456_789);
+        let sort = message_sort_key(sent);
+        let token = Token::from_sort_key(&sort).unwrap();
+        assert_eq!(token, Token::from_date_time(&sent));
+        assert_eq!(token.to_string(), "1760788800123456");
+        assert!(token.sort_key() < sort);
+        assert_eq!(Token::parse(&token.to_string()), Some(token));
+
+        assert_eq!(Token::from_sort_key("room"), None);
+        assert_eq!(Token::from_sort_key("message.yesterday"), None);
+        assert_eq!(Token::parse("-1"), None);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::versioning::RequestedVersion;
+use axum::{body::Body, middleware::Next, response::Response};
+use http::{HeaderValue, Request};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tower_http::request_id::{MakeRequestId, RequestId};
+use tracing::{field, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Logging settings.
+///
+/// - `format`: `json` (default), one JSON object per line for a log pipeline
+///   to pick up, or `text` for reading in a terminal.
+/// - `filter`: which logs to keep, as `tracing_subscriber::EnvFilter`
+///   directives, like `info` or `info,api=debug`. Defaults to `info`. DynamoDB
+///   calls are logged at `debug`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    pub filter: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Json,
+            filter: "info".into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+impl LogConfig {
+    /// Returns a message if the filter can't be parsed.
+    pub fn validate(&self) -> Vec<String> {
+        match EnvFilter::try_new(&self.filter) {
+            Ok(_) => Vec::new(),
+            Err(error) => vec![format!("log.filter: {:?} {}", self.filter, error)],
+        }
+    }
+}
+
+/// OpenTelemetry settings. Spans for requests and DynamoDB calls are exported
+/// over OTLP (gRPC) to a collector, if the API was built with the `otel`
+/// feature and there's an endpoint.
+///
+/// - `endpoint`: the collector, like `http://localhost:4317`. Leave it out to
+///   not export anything.
+/// - `service_name`: what the spans say they came from. Defaults to
+///   `chat-api`.
+/// - `sample_ratio`: the share of traces to keep, from 0 to 1. Defaults to
+///   keeping all of them. Requests that come with a `traceparent` header
+///   follow the caller's decision instead.
+///
+/// Spans are only exported if `log.filter` lets them through: requests are
+/// at `info`, DynamoDB calls at `info` too.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtelConfig {
+    pub endpoint: Option<String>,
+    pub service_name: String,
+    pub sample_ratio: f64,
+}
+
+impl Default for OtelConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: "chat-api".into(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+impl OtelConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.endpoint.is_some() && !cfg!(feature = "otel") {
+            errors.push(
+                "otel.endpoint: the API was built without the `otel` feature, so it can't export spans"
+                    .into(),
+            );
+        }
+        if !(0.0..=1.0).contains(&self.sample_ratio) {
+            errors.push(format!(
+                "otel.sample_ratio: {} is not between 0 and 1",
+                self.sample_ratio
+            ));
+        }
+        errors
+    }
+}
+
+/// Sets up logging, and span export if it's turned on. The config has to have
+/// been validated.
+pub fn init(config: &LogConfig, otel: &OtelConfig) {
+    let json = config.format == LogFormat::Json;
+    let registry = tracing_subscriber::registry()
+        .with(EnvFilter::new(&config.filter))
+        .with(json.then(|| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(true)
+        }))
+        .with((!json).then(tracing_subscriber::fmt::layer));
+
+    #[cfg(feature = "otel")]
+    let registry = registry.with(crate::otel::layer(otel));
+    #[cfg(not(feature = "otel"))]
+    let _ = otel;
+
+    registry.init();
+}
+
+/// Sends off whatever telemetry hasn't been yet, before the process exits.
+/// Logs are written as they happen, so that's only exported spans.
+pub async fn shutdown() {
+    #[cfg(feature = "otel")]
+    crate::otel::shutdown().await;
+}
+
+/// Makes up an ID for requests that didn't come with an `X-Request-Id`.
+#[derive(Clone, Copy)]
+pub struct MakeId;
+
+impl MakeRequestId for MakeId {
+    fn make_request_id<B>(&mut self, _: &Request<B>) -> Option<RequestId> {
+        let id = format!("{:032x}", fastrand::u128(..));
+        HeaderValue::from_str(&id).ok().map(RequestId::new)
+    }
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request being handled, if there is one.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Middleware that makes the request ID that `SetRequestIdLayer` found or made
+/// up available to `request_id` while the request is handled, so that errors
+/// can tell the client which request to quote.
+pub async fn scope_request_id(request: Request<Body>, next: Next<Body>) -> Response {
+    let id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    REQUEST_ID.scope(id, next.run(request)).await
+}
+
+/// The span every request is handled in. Everything logged while handling
+/// it has its ID, method and path.
+pub fn make_span(request: &Request<Body>) -> Span {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        otel.kind = "server",
+        request_id,
+        method = %request.method(),
+        path = request.uri().path(),
+        api_version = field::Empty,
+    );
+    #[cfg(feature = "otel")]
+    crate::otel::set_parent(&span, request.headers());
+    span
+}
+
+/// Logs each response, with how long it took.
+pub fn on_response<B>(response: &Response<B>, latency: Duration, span: &Span) {
+    if let Some(requested) = response.extensions().get::<RequestedVersion>() {
+        span.record("api_version", requested.version.as_str());
+    }
+    let status = response.status().as_u16();
+    let latency_ms = latency.as_secs_f64() * 1000.0;
+    if response.status().is_server_error() {
+        tracing::error!(status, latency_ms, "Response");
+    } else {
+        tracing::info!(status, latency_ms, "Response");
+    }
+}
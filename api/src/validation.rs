@@ -0,0 +1,234 @@
+use crate::errors::FieldError;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::confusable_detection::skeleton;
+
+/// Longest user or room name, in characters (not bytes).
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// Longest message, in characters (not bytes).
+pub const MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// Validates a user or room name, and returns the normalized version of it,
+/// which is what should be stored.
+///
+/// Names are NFC normalized, so that "é" typed as one code point and "é" typed
+/// as "e" plus a combining accent are the same name, and trimmed. Names can't
+/// contain control characters (including newlines) or bidi overrides, and have
+/// to contain at least one visible character.
+pub fn name(field: &'static str, value: &str) -> Result<String, FieldError> {
+    let value: String = value.nfc().collect();
+    let value = value.trim();
+
+    if value.is_empty() {
+        return Err(FieldError::new(field, "Name is empty"));
+    }
+    if value.chars().count() > MAX_NAME_LENGTH {
+        return Err(FieldError::new(
+            field,
+            &format!("Name can be at most {} characters", MAX_NAME_LENGTH),
+        ));
+    }
+    if value.chars().any(|c| c.is_control() || is_bidi_control(c)) {
+        return Err(FieldError::new(field, "Name contains control characters"));
+    }
+    if value.chars().all(is_invisible) {
+        return Err(FieldError::new(field, "Name has no visible characters"));
+    }
+
+    Ok(value.to_owned())
+}
+
+/// Validates a message body, and returns the normalized version of it.
+///
+/// Messages get the same treatment as names, except they can be a lot longer,
+/// can contain newlines and tabs, and leading whitespace is kept in case
+/// somebody is pasting code.
+pub fn message(field: &'static str, value: &str) -> Result<String, FieldError> {
+    let value: String = value.nfc().collect();
+    let value = value.trim_end();
+
+    if value.is_empty() {
+        return Err(FieldError::new(field, "Message is empty"));
+    }
+    if value.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(FieldError::new(
+            field,
+            &format!("Message can be at most {} characters", MAX_MESSAGE_LENGTH),
+        ));
+    }
+    if value
+        .chars()
+        .any(|c| (c.is_control() && c != '\n' && c != '\t') || is_bidi_control(c))
+    {
+        return Err(FieldError::new(
+            field,
+            "Message contains control characters",
+        ));
+    }
+    if value.chars().all(is_invisible) {
+        return Err(FieldError::new(field, "Message has no visible characters"));
+    }
+
+    Ok(value.to_owned())
+}
+
+/// Takes the ID off the end of a resource URI, e.g. `123456` from
+/// `http://localhost:5050/users/123456`. A bare ID is also accepted.
+pub fn id_from_uri<'a>(field: &'static str, uri: &'a str) -> Result<&'a str, FieldError> {
+    let id = uri.rsplit('/').next().unwrap_or_default();
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(FieldError::new(field, &format!("Invalid {}", field)));
+    }
+    Ok(id)
+}
+
+/// The key used to check that user names are unique.
+///
+/// Two names are the same if they only differ by case, or by characters that
+/// look alike (the UTS #39 "skeleton"), so "ryan", "RYAN" and "ryаn" (with a
+/// Cyrillic "а") all get the same key. Only `name` is validated, this should
+/// only be called with its output.
+///
+/// Names are lowercased before the skeleton is taken, so that case never
+/// matters. The flip side is that "Iris" and "lris" are different names:
+/// "I" looks like "l", but it's lowercased to "i" first, which doesn't. The
+/// skeleton can map to capitals, like "0" to "O", so what it gives is
+/// lowercased, and its skeleton taken, again.
+pub fn name_key(name: &str) -> String {
+    let once: String = skeleton(&name.to_lowercase()).collect();
+    skeleton(&once.to_lowercase()).collect()
+}
+
+/// Bidi overrides and isolates can make a name render as something else
+/// entirely, and have no business being in a name or message.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Characters that take up no space, or only whitespace, when rendered.
+fn is_invisible(c: char) -> bool {
+    c.is_whitespace()
+        || matches!(
+            c,
+            '\u{00AD}'
+                | '\u{034F}'
+                | '\u{061C}'
+                | '\u{115F}'
+                | '\u{1160}'
+                | '\u{17B4}'
+                | '\u{17B5}'
+                | '\u{180B}'..='\u{180F}'
+                | '\u{200B}'..='\u{200F}'
+                | '\u{2060}'..='\u{2064}'
+                | '\u{206A}'..='\u{206F}'
+                | '\u{3164}'
+                | '\u{FE00}'..='\u{FE0F}'
+                | '\u{FEFF}'
+                | '\u{FFA0}'
+                | '\u{1D173}'..='\u{1D17A}'
+                | '\u{E0000}'..='\u{E0FFF}'
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_normalized_and_trimmed() {
+        assert_eq!(name("name", "  Ryan ").ok().unwrap(), "Ryan");
+        // "é" as "e" and a combining accent becomes the one code point.
+        assert_eq!(name("name", "Jose\u{301}").ok().unwrap(), "Jos\u{E9}");
+        assert_eq!(name_key("Jose\u{301}"), name_key("Jos\u{E9}"));
+    }
+
+    #[test]
+    fn names_cant_hide_what_they_are() {
+        for bad in [
+            "",
+            "   ",
+            "Ry\nan",
+            "Ry\u{7}an",
+            "\u{202E}nayR",
+            "Ry\u{2066}an",
+            "\u{200B}\u{200B}",
+            "\u{3164}",
+            "\u{FEFF} \u{FE0F}",
+        ] {
+            assert!(name("name", bad).is_err(), "{:?}", bad);
+        }
+        // Invisible characters are fine next to visible ones.
+        assert!(name("name", "Ry\u{200D}an").is_ok());
+    }
+
+    #[test]
+    fn lengths_are_in_characters() {
+        assert!(name("name", &"\u{E9}".repeat(MAX_NAME_LENGTH)).is_ok());
+        assert!(name("name", &"\u{E9}".repeat(MAX_NAME_LENGTH + 1)).is_err());
+        assert!(name("name", &"\u{1F642}".repeat(MAX_NAME_LENGTH)).is_ok());
+        // Counted after normalizing, so combining accents don't count twice.
+        assert!(name("name", &"e\u{301}".repeat(MAX_NAME_LENGTH)).is_ok());
+
+        assert!(message("message", &"\u{E9}".repeat(MAX_MESSAGE_LENGTH)).is_ok());
+        assert!(message("message", &"\u{E9}".repeat(MAX_MESSAGE_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn messages_keep_their_layout() {
+        assert_eq!(
+            message("message", "    let x = 1;\n\tx\n\n").ok().unwrap(),
+            "    let x = 1;\n\tx"
+        );
+        for bad in [
+            "",
+            " \n\t",
+            "a\rb",
+            "a\u{1B}[31mb",
+            "\u{202E}olleh",
+            "\u{200B}",
+        ] {
+            assert!(message("message", bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn ids_come_off_the_end_of_uris() {
+        let id = |uri| id_from_uri("user", uri).ok();
+        assert_eq!(id("http://localhost:5050/users/123456"), Some("123456"));
+        assert_eq!(id("/v2/rooms/42"), Some("42"));
+        assert_eq!(id("123456"), Some("123456"));
+        for bad in [
+            "",
+            "http://localhost:5050/users/",
+            "/users/12a",
+            "-1",
+            "/users/1 ",
+        ] {
+            assert_eq!(id(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn names_that_look_alike_have_the_same_key() {
+        for (a, b) in [
+            ("ryan", "RYAN"),
+            ("ryan", "rYaN"),
+            // A Cyrillic "а".
+            ("ryan", "ry\u{430}n"),
+            ("ryan", "RY\u{410}N"),
+            ("Iris", "iris"),
+            ("lris", "1ris"),
+            ("olive", "OLIVE"),
+            // "0" looks like a capital "O", so it has to be lowercased after.
+            ("olive", "0live"),
+            ("olive", "0LIVE"),
+            ("m", "rn"),
+        ] {
+            assert_eq!(name_key(a), name_key(b), "{:?} and {:?}", a, b);
+        }
+        // Case goes first, so "I" is an "i", which doesn't look like "l".
+        for (a, b) in [("ryan", "rayn"), ("bob", "rob"), ("Iris", "lris")] {
+            assert_ne!(name_key(a), name_key(b), "{:?} and {:?}", a, b);
+        }
+    }
+}
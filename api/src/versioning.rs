@@ -0,0 +1,194 @@
+use axum::{body::Body, middleware::Next, response::Response};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use http::{HeaderValue, Request};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// A version of the API's response shapes.
+///
+/// - `v1`: the original shapes. Plain JSON unless JSON-LD is asked for.
+/// - `v2`: JSON-LD unless plain JSON is asked for.
+///
+/// Every route is served under `/v1` and `/v2`, and also without a prefix,
+/// which is the same as `/v1` so that existing clients keep working.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::V1 => "v1",
+            Version::V2 => "v2",
+        }
+    }
+}
+
+/// Which route tree a request came in through. It's put in the request's
+/// extensions by the route tree, and in the response's extensions on the way
+/// out, so that logging and metrics further out can see it too.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestedVersion {
+    pub version: Version,
+    /// `/v1`, `/v2`, or nothing for the unversioned aliases. URIs in responses
+    /// keep the prefix the client used.
+    pub prefix: &'static str,
+}
+
+impl RequestedVersion {
+    pub const TREES: [RequestedVersion; 3] = [
+        RequestedVersion {
+            version: Version::V1,
+            prefix: "/v1",
+        },
+        RequestedVersion {
+            version: Version::V2,
+            prefix: "/v2",
+        },
+        RequestedVersion {
+            version: Version::V1,
+            prefix: "",
+        },
+    ];
+
+    /// The name the route tree goes by in `Config::deprecations`.
+    pub fn name(&self) -> &'static str {
+        match self.prefix {
+            "" => "unversioned",
+            prefix => &prefix[1..],
+        }
+    }
+}
+
+/// Strips the version prefix off a path, if it has one.
+pub fn unversioned_path(path: &str) -> &str {
+    RequestedVersion::TREES
+        .iter()
+        .filter(|tree| !tree.prefix.is_empty())
+        .find_map(|tree| {
+            path.strip_prefix(tree.prefix)
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .unwrap_or(path)
+}
+
+/// Marks a route tree as deprecated. Its responses get a `Deprecation` header,
+/// with the date from `since` if there is one, and a `Sunset` header if
+/// there's a `sunset` date. Dates are like `2024-06-30` or
+/// `2024-06-30T00:00:00Z`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Deprecation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sunset: Option<String>,
+}
+
+/// Checks `Config::deprecations`, keyed by `unversioned`, `v1` or `v2`.
+pub fn validate(deprecations: &BTreeMap<String, Deprecation>) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (name, deprecation) in deprecations {
+        if !RequestedVersion::TREES
+            .iter()
+            .any(|tree| tree.name() == name)
+        {
+            errors.push(format!(
+                "deprecations.{}: should be \"unversioned\", \"v1\" or \"v2\"",
+                name
+            ));
+        }
+        for (field, date) in [
+            ("since", &deprecation.since),
+            ("sunset", &deprecation.sunset),
+        ] {
+            if date
+                .as_deref()
+                .is_some_and(|date| parse_date(date).is_none())
+            {
+                errors.push(format!(
+                    "deprecations.{}.{}: {:?} is not a date like \"2024-06-30\"",
+                    name,
+                    field,
+                    date.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+    }
+    errors
+}
+
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|date| Utc.from_utc_datetime(&date))
+        })
+}
+
+/// The headers a route tree's responses get, worked out once on startup.
+#[derive(Clone)]
+pub struct VersionHeaders {
+    requested: RequestedVersion,
+    deprecation: Option<HeaderValue>,
+    sunset: Option<HeaderValue>,
+}
+
+impl VersionHeaders {
+    /// The config has to have been validated.
+    pub fn new(
+        requested: RequestedVersion,
+        deprecations: &BTreeMap<String, Deprecation>,
+    ) -> Arc<Self> {
+        let deprecation = deprecations.get(requested.name());
+        let date = |date: &Option<String>| date.as_deref().and_then(parse_date);
+        Arc::new(Self {
+            requested,
+            // RFC 9745: `@` and a Unix timestamp, or just `true`.
+            deprecation: deprecation.map(|deprecation| match date(&deprecation.since) {
+                Some(since) => HeaderValue::from_str(&format!("@{}", since.timestamp())).unwrap(),
+                None => HeaderValue::from_static("true"),
+            }),
+            // RFC 8594: an HTTP date.
+            sunset: deprecation
+                .and_then(|deprecation| date(&deprecation.sunset))
+                .map(|sunset| {
+                    HeaderValue::from_str(&sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+                        .unwrap()
+                }),
+        })
+    }
+}
+
+/// Middleware for a route tree. Tells handlers which version was asked for,
+/// and tells the client which version it got, and whether that version is on
+/// its way out.
+pub async fn tag(mut request: Request<Body>, next: Next<Body>) -> Response {
+    let headers = request.extensions().get::<Arc<VersionHeaders>>().cloned();
+    let headers = match headers {
+        Some(headers) => headers,
+        None => return next.run(request).await,
+    };
+    request.extensions_mut().insert(headers.requested);
+
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(headers.requested);
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        "api-version",
+        HeaderValue::from_static(headers.requested.version.as_str()),
+    );
+    if let Some(deprecation) = &headers.deprecation {
+        response_headers.insert("deprecation", deprecation.clone());
+    }
+    if let Some(sunset) = &headers.sunset {
+        response_headers.insert("sunset", sunset.clone());
+    }
+    response
+}
@@ -1,6 +1,7 @@
 /**
  * This hook exposes an rxjs observer that can be subscribed to that
- * continually checks the API server's status check endpoint.
+ * continually checks the API server's readiness endpoint, which only says
+ * the API is healthy when its database is reachable too.
  * In a production app I would have this thing adjust it's interval
  * timer and increase the frequency when interesting things are
  * happening, and decrease it when nothing is happening.
@@ -44,9 +45,10 @@ export function useApiHealthChecker() {
 
 async function apiCall(): Promise<boolean> {
   try {
-    const res = await fetch(`${API_URI}/status`);
-    const text = await res.text();
-    return text === "OK";
+    // A degraded API answers with a 503, and says what's down in the body.
+    const res = await fetch(`${API_URI}/health/ready`);
+    const health = await res.json();
+    return res.ok && health.status === "ok";
   } catch (_e) {
     return false;
   }
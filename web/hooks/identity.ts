@@ -17,6 +17,10 @@ const SIGN_UP_URI = `${API_URI}/sign-up`;
 const SIGN_IN_URI = `${API_URI}/sign-in`;
 
 type Identity = [string | null, string | null];
+interface FieldError {
+  field: string;
+  message: string;
+}
 type HookResponse = [
   string | null,
   string | null,
@@ -66,11 +70,12 @@ export function useIdentity(): HookResponse {
           setWorkingError("User already exists. Sign in instead.")
           return
         }
-        if (text === "Name is empty") {
-          setWorkingError("Please fill in your name.")
+        let data = JSON.parse(text);
+        // Validation errors come back as a list of problems per field
+        if (data.fields) {
+          setWorkingError(data.fields.map((f: FieldError) => f.message).join(" "))
           return
         }
-        let data = JSON.parse(text);
         localStorage.setItem("com.ryanknu.chat-app__id", data.id);
         localStorage.setItem(
           "com.ryanknu.chat-app__name",